@@ -1,10 +1,22 @@
+use log::{error, info, warn};
 use serde::Serialize;
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, RwLock};
+use std::collections::HashMap;
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex, RwLock};
 
-use crate::config::ConfigFromFile;
-use crate::config_store::{ConfigStore, ConfigStoreFunc, Monitor};
-use crate::file_store::{FileStore, FileStoreFunc};
+use crate::blob_store::{self, BlobStoreFunc};
+use crate::compression::{self, Encoding, StoredContent};
+use crate::config::{BlobStoreConfig, ConfigFromFile};
+use crate::config_store::{ConfigStore, ConfigStoreFunc, ContentHash, ContentHasher, Monitor};
+use crate::encrypted_blob_store::EncryptedBlobStore;
+use crate::eviction::{self, EvictionPolicyFunc};
+use crate::file_store::{
+    CorruptedReplica, FileAccess, FileEntry, FileSource, FileStore, FileStoreFunc,
+};
+use crate::multi_dir_blob_store::MultiDirBlobStore;
+use crate::sqlite_file_store::SqliteFileStore;
 use crate::stat_store::{StatStore, StatStoreFunc, Stats};
+use crate::token_store::TokenStore;
+use crate::upload_store::{Upload, UploadStore};
 
 /*  AppState
  *  Acts as the single source of truth. All stores are accessible over the AppState.
@@ -19,18 +31,38 @@ pub struct Ping {
     pub port: u16,
     pub weight: f32,
     pub files: Vec<String>,
+    pub legacy_hashes: HashMap<String, String>, // SHA-256 hash -> SHA-1 hash, during the transition to SHA-256
     pub rejected_hashes: Vec<String>,
     pub capacity_left: u64,
+    pub capacity: u64, // Total space for files, the sum of all healthy data dirs if there are several
     pub uploaded_hashes: Vec<String>,
+    pub corrupted_hashes: Vec<String>,
+    pub lost_hashes: Vec<String>, // Files whose content vanished from the BlobStore
+    pub refused_deletions: Vec<String>, // Files the monitor wanted to delete, which are pinned or retained
+    pub evicted_hashes: Vec<String>, // Recovered files removed to make room for other recoveries
+    pub corrupted_replicas: Vec<CorruptedReplica>,
     pub ipv6: Option<String>,
 }
 
 pub struct AppState {
-    pub file_store: RwLock<FileStore>,
+    pub file_store: RwLock<Box<dyn FileStoreFunc + Send + Sync>>,
     pub config_store: RwLock<ConfigStore>,
     pub stat_store: RwLock<StatStore>,
     pub stop_services: Arc<AtomicBool>,
     pub force_ping: Arc<AtomicBool>,
+    pub eviction_policy: Option<Box<dyn EvictionPolicyFunc>>,
+    pub encryption: Option<Arc<EncryptedBlobStore>>, // Encrypts the contents in the BlobStore, if enabled
+    pub data_dirs: Option<Arc<MultiDirBlobStore>>,   // Spreads the contents over several data dirs, if configured
+    pub uploads: RwLock<UploadStore>,                // Resumable uploads which are not completed or not expired
+    pub tokens: RwLock<TokenStore>,                  // API tokens which authorize uploads, deletions and admin requests
+    accesses: Mutex<HashMap<String, FileAccess>>,    // Downloads and lookups not written to the FileStore yet
+}
+
+// BlobStore configured in config.json, with the layers which services need to reach
+pub struct OpenedBlobStore {
+    pub blob_store: Arc<dyn BlobStoreFunc>,          // Outermost layer, which stores the contents
+    pub encryption: Option<Arc<EncryptedBlobStore>>, // Set if encryption is enabled
+    pub data_dirs: Option<Arc<MultiDirBlobStore>>,   // Set if data_dirs are configured
 }
 
 impl AppState {
@@ -42,91 +74,796 @@ impl AppState {
         stop_services: Arc<AtomicBool>,
         force_ping: Arc<AtomicBool>,
         path: &str,
-    ) -> AppState {
-        let file_store = RwLock::new(FileStore::new(stats.capacity.value, &path));
-        let config_store = RwLock::new(ConfigStore::new(
-            &config.manager_addr,
-            own_monitor.clone(),
-            monitors,
-            config.port,
-            &config.fingerprint,
-            config.ipv6,
+    ) -> std::io::Result<AppState> {
+        let opened = AppState::open_blob_store(&config, path);
+        let file_store = RwLock::new(AppState::open_file_store(
+            &config,
+            stats.capacity.value,
+            path,
+            opened.blob_store,
         ));
+        let config_store = RwLock::new(ConfigStore::new(&config, own_monitor.clone(), monitors));
         let stat_store = RwLock::new(StatStore::new(stats, String::from(path)));
+        let eviction_policy = eviction::from_config(config.eviction_policy.as_deref());
+
+        let uploads = UploadStore::new(path);
 
-        AppState {
+        // Without the tokens the node could not tell who may upload, so it refuses to start
+        let tokens = TokenStore::open(path).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("Could not read the API tokens: {}", err),
+            )
+        })?;
+        if !tokens.is_enabled() {
+            warn!("No API tokens created, uploads are accepted from everyone until the first one is created with node-app create-token");
+        }
+
+        Ok(AppState {
             file_store,
             config_store,
             stat_store,
             stop_services,
             force_ping,
+            eviction_policy,
+            encryption: opened.encryption,
+            data_dirs: opened.data_dirs,
+            uploads: RwLock::new(uploads),
+            tokens: RwLock::new(tokens),
+            accesses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /* Opens the BlobStore configured in config.json. If encryption is enabled, it is wrapped
+     * in an EncryptedBlobStore. The layers which services need to reach are returned as well.
+     */
+    pub fn open_blob_store(config: &ConfigFromFile, path: &str) -> OpenedBlobStore {
+        let min_free_space = config
+            .min_free_space
+            .unwrap_or(blob_store::DEFAULT_MIN_FREE_SPACE);
+        let data_dirs = match &config.blob_store {
+            Some(BlobStoreConfig::Local(local_config)) if local_config.data_dirs.is_some() => Some(
+                Arc::new(MultiDirBlobStore::new(local_config, min_free_space)),
+            ),
+            _ => None,
+        };
+        let blob_store: Arc<dyn BlobStoreFunc> = match &data_dirs {
+            Some(data_dirs) => data_dirs.clone(),
+            None => blob_store::from_config(config.blob_store.as_ref(), path, min_free_space),
+        };
+
+        match &config.encryption {
+            Some(encryption_config) => {
+                let encrypted = Arc::new(EncryptedBlobStore::new(
+                    blob_store,
+                    encryption_config,
+                    path,
+                ));
+                OpenedBlobStore {
+                    blob_store: encrypted.clone(),
+                    encryption: Some(encrypted),
+                    data_dirs,
+                }
+            }
+            None => OpenedBlobStore {
+                blob_store,
+                encryption: None,
+                data_dirs,
+            },
         }
     }
 
-    pub fn generate_ping(&self) -> Ping {
+    // Opens the FileStore configured in config.json on top of the given BlobStore
+    pub fn open_file_store(
+        config: &ConfigFromFile,
+        capacity: u64,
+        path: &str,
+        blob_store: Arc<dyn BlobStoreFunc>,
+    ) -> Box<dyn FileStoreFunc + Send + Sync> {
+        match config.metadata_store.as_deref() {
+            None | Some("json") => Box::new(FileStore::new(capacity, path, blob_store)),
+            Some("sqlite") => Box::new(SqliteFileStore::new(capacity, path, blob_store)),
+            Some(other) => panic!("Unknown metadata_store {}", other),
+        }
+    }
+
+    // Fails if the stored files can not be listed, an empty list would tell the monitor they are gone
+    pub fn generate_ping(&self) -> std::io::Result<Ping> {
         let config = self.config_store.read().unwrap();
         let capacity_left = self.file_store.read().unwrap().capacity_left();
+        let capacity = self.file_store.read().unwrap().capacity();
+
+        let mut files = self.file_store.read().unwrap().hashes()?;
+        let mut uploaded_hashes = self.file_store.read().unwrap().uploaded_hashes()?;
+        let mut legacy_hashes = HashMap::new();
+
+        // Announce SHA-1 hashes as well, so monitors do not lose track of files
+        if config.advertise_legacy_hashes() {
+            legacy_hashes = self.file_store.read().unwrap().legacy_hashes()?;
+            files.extend(legacy_hashes.values().cloned());
+            let uploaded_legacy = uploaded_hashes
+                .iter()
+                .filter_map(|hash| legacy_hashes.get(hash).cloned())
+                .collect::<Vec<String>>();
+            uploaded_hashes.extend(uploaded_legacy);
+        }
 
         let ping = Ping {
             fingerprint: config.fingerprint(),
             port: config.port(),
             weight: self.calculate_weight(),
-            files: self.file_store.read().unwrap().hashes(),
+            files,
+            legacy_hashes,
             capacity_left,
-            rejected_hashes: self.file_store.read().unwrap().rejected_hashes(),
-            uploaded_hashes: self.file_store.read().unwrap().uploaded_hashes(),
+            capacity,
+            rejected_hashes: self.file_store.read().unwrap().rejected_hashes()?,
+            uploaded_hashes,
+            corrupted_hashes: self.file_store.read().unwrap().corrupted_hashes()?,
+            lost_hashes: self.file_store.read().unwrap().lost_hashes()?,
+            refused_deletions: self.file_store.read().unwrap().refused_deletions()?,
+            evicted_hashes: self.file_store.read().unwrap().evicted_hashes()?,
+            corrupted_replicas: self.file_store.read().unwrap().corrupted_replicas()?,
             ipv6: self.config_store.read().unwrap().ipv6.clone(),
         };
 
-        self.file_store.write().unwrap().clear_uploaded_hashes();
-        self.file_store.write().unwrap().clear_rejected_hashes();
+        return Ok(ping);
+    }
 
-        return ping;
+    /* Removes the entries reported with a ping once the monitor received it. Entries added while
+     * the ping was sent stay for the next one, and nothing is lost if the monitor is unreachable.
+     */
+    pub fn clear_reported(&self, ping: &Ping) -> std::io::Result<()> {
+        let mut file_store = self.file_store.write().unwrap();
+        file_store.clear_uploaded_hashes(&ping.uploaded_hashes)?;
+        file_store.clear_rejected_hashes(&ping.rejected_hashes)?;
+        file_store.clear_corrupted_hashes(&ping.corrupted_hashes)?;
+        file_store.clear_lost_hashes(&ping.lost_hashes)?;
+        file_store.clear_refused_deletions(&ping.refused_deletions)?;
+        file_store.clear_evicted_hashes(&ping.evicted_hashes)?;
+        file_store.clear_corrupted_replicas(&ping.corrupted_replicas)
     }
 
-    pub fn add_new_file(
+    /* Moves an already hashed temporary file into the BlobStore, the hash must be verified by
+     * the caller. The file is compressed, if enabled and worthwhile for its type. It is written to
+     * the BlobStore without holding the lock of the file_store, so it blocks and should be called
+     * with spawn_blocking.
+     */
+    pub fn add_new_file_from_path(
         &self,
-        content: &[u8],
+        hash: &ContentHash,
+        temp_path: &str,
         content_type: &str,
         file_name: &str,
+        source: FileSource,
         distribute: bool,
-    ) -> String {
-        let hash = self.config_store.write().unwrap().hash_content(content);
+    ) -> std::io::Result<()> {
+        let size = std::fs::metadata(temp_path)?.len();
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        let encoding = self.encoding_for(content_type);
+        let compressed_path = self.file_store.read().unwrap().temp_file_path()?;
+        let stored = match compression::compress_file(temp_path, &compressed_path, encoding)? {
+            Some(stored_size) => {
+                blob_store.put_file(&hash.hash, &compressed_path)?;
+                let _ = std::fs::remove_file(temp_path);
+                StoredContent {
+                    size,
+                    stored_size,
+                    encoding,
+                }
+            }
+            None => {
+                blob_store.put_file(&hash.hash, temp_path)?;
+                StoredContent::identity(size)
+            }
+        };
 
-        // Add file to file_store and disk
         self.file_store
             .write()
             .unwrap()
-            .save_file(&hash, content, content_type, file_name);
+            .insert_file(hash, stored, content_type, file_name, source)?;
+
+        self.announce_new_file(&hash.hash, distribute)
+    }
+
+    /* Stores a resumable upload, whose data file is complete, as new file. The data file is
+     * hashed and moved into the BlobStore. The caller holds the UploadLock, which has to reserve
+     * the space of the whole file beforehand. It blocks and should be called with spawn_blocking.
+     */
+    pub fn complete_upload(&self, id: &str) -> std::io::Result<ContentHash> {
+        let (upload, data_path) = {
+            let uploads = self.uploads.read().unwrap();
+            let upload = uploads.get(id).ok_or_else(|| blob_store::not_found(id))?;
+            (upload, uploads.data_path(id))
+        };
+
+        let hash = ContentHasher::hash_file(&data_path)?;
+        self.add_new_file_from_path(
+            &hash,
+            &data_path,
+            &upload.content_type,
+            &upload.file_name,
+            FileSource::Upload,
+            true,
+        )?;
+        self.uploads.write().unwrap().complete(id, &hash.hash);
+
+        Ok(hash)
+    }
+
+    // Marks a resumable upload as busy, returns None if another request appends to it already
+    pub fn lock_upload(&self, id: &str) -> Option<UploadLock<'_>> {
+        if !self.uploads.write().unwrap().lock(id) {
+            return None;
+        }
+
+        Some(UploadLock {
+            app_state: self,
+            id: String::from(id),
+            reserved: 0,
+            written: 0,
+        })
+    }
+
+    // Removes a resumable upload, returns None if it does not exist or a request appends to it
+    pub fn remove_upload(&self, id: &str) -> Option<Upload> {
+        self.uploads.write().unwrap().remove(id)
+    }
+
+    // Removes all resumable uploads which made no progress until their expiry
+    pub fn expire_uploads(&self) -> Vec<Upload> {
+        let now = chrono::Utc::now().timestamp();
+        self.uploads.write().unwrap().remove_expired(now)
+    }
+
+    // Removes the file from the BlobStore and the file_store. If the content can not be removed, the file is kept.
+    pub fn remove_file(&self, hash: &str) {
+        let file_entry = match self.file_store.read().unwrap().get_file(hash) {
+            Ok(Some(file_entry)) => file_entry,
+            _ => return,
+        };
+
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        match blob_store.remove(&file_entry.hash) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => error!("{}", err),
+            _ => match self.file_store.write().unwrap().remove_file(hash) {
+                Ok(_) => info!("Removed file {}", hash),
+                Err(err) => error!("Could not remove {}: {}", hash, err),
+            },
+        }
+    }
+
+    /* Moves a file the monitor asked to delete to the trash, unless a pin or retention hold
+     * prevents it. The TrashService purges it later. Without a trash retention the file is removed.
+     */
+    pub fn remove_file_on_request(&self, hash: &str) {
+        let held = match self.file_store.read().unwrap().get_file(hash) {
+            Ok(Some(file_entry)) => file_entry.is_held(),
+            _ => return,
+        };
+
+        if held {
+            // Tell the monitor, so it does not count on the file being gone
+            info!("Refused deletion of held file {}", hash);
+            if let Err(err) = self.file_store.write().unwrap().refuse_deletion(hash) {
+                error!("Could not refuse the deletion of {}: {}", hash, err);
+            }
+            return;
+        }
+
+        if self.config_store.read().unwrap().trash_retention() == 0 {
+            self.remove_file(hash);
+            return;
+        }
+
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        match blob_store.trash(hash) {
+            Ok(_) => match self.file_store.write().unwrap().trash_file(hash) {
+                Ok(_) => info!("Moved file {} to the trash", hash),
+                Err(err) => error!("Could not move {} to the trash: {}", hash, err),
+            },
+            Err(err) => error!("Could not move {} to the trash: {}", hash, err),
+        }
+    }
+
+    /* Moves a file back from the trash and announces it to the monitor again.
+     * Returns None if the file is not in the trash, fails with StorageFull if there is not
+     * enough space left for it.
+     */
+    pub fn restore_file(&self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        let trash_entry = self
+            .file_store
+            .read()
+            .unwrap()
+            .trashed_files()?
+            .into_iter()
+            .find(|entry| {
+                entry.file_entry.hash == hash || entry.file_entry.legacy_hash.as_deref() == Some(hash)
+            });
+        let file_entry = match trash_entry {
+            Some(trash_entry) => trash_entry.file_entry,
+            None => return Ok(None),
+        };
+
+        // The file was stored again since its deletion, the trashed copy is not needed anymore
+        if let Some(stored) = self.file_store.read().unwrap().get_file(&file_entry.hash)? {
+            self.purge_file(&file_entry.hash);
+            return Ok(Some(stored));
+        }
+
+        if !self
+            .file_store
+            .write()
+            .unwrap()
+            .reserve_space(file_entry.disk_size())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!("not enough space left to restore {}", file_entry.hash),
+            ));
+        }
+
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        let restored = blob_store.restore(&file_entry.hash);
+        let mut file_store = self.file_store.write().unwrap();
+        file_store.release_space(file_entry.disk_size());
+        restored?;
+        file_store.restore_file(&file_entry.hash)?;
+        drop(file_store);
+
+        info!("Restored file {} from the trash", file_entry.hash);
+        self.announce_new_file(&file_entry.hash, false)?;
+        Ok(Some(file_entry))
+    }
+
+    // Removes a file from the trash for good
+    pub fn purge_file(&self, hash: &str) {
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        match blob_store.purge(hash) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => error!("{}", err),
+            _ => match self.file_store.write().unwrap().purge_file(hash) {
+                Ok(_) => info!("Purged file {}", hash),
+                Err(err) => error!("Could not purge {}: {}", hash, err),
+            },
+        }
+    }
+
+    // Returns true if files may be evicted to make room for recoveries
+    pub fn can_evict(&self) -> bool {
+        self.eviction_policy.is_some()
+    }
+
+    /* Evicts recovered files in the order of the eviction policy, until size bytes are left.
+     * Nothing is evicted if that is not enough. Returns true if there is enough space left.
+     * Removes contents from the BlobStore, so it should be called with spawn_blocking.
+     */
+    pub fn make_room(&self, size: u64) -> bool {
+        let capacity_left = self.file_store.read().unwrap().capacity_left();
+        if capacity_left >= size {
+            return true;
+        }
+        let policy = match &self.eviction_policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+
+        // The order of some policies depends on the latest accesses
+        self.flush_accesses();
+        let files = match self.file_store.read().unwrap().files() {
+            Ok(files) => files,
+            Err(err) => {
+                error!("Could not list the files to evict: {}", err);
+                return false;
+            }
+        };
+        let mut candidates = files
+            .into_iter()
+            .filter(eviction::is_evictable)
+            .collect::<Vec<FileEntry>>();
+        policy.order(&mut candidates);
+
+        // Only evict as much as needed
+        let missing = size - capacity_left;
+        let mut freed = 0;
+        let victims = candidates
+            .into_iter()
+            .take_while(|file_entry| {
+                let take = freed < missing;
+                freed += file_entry.disk_size();
+                take
+            })
+            .collect::<Vec<FileEntry>>();
+        if freed < missing {
+            info!("Evicting files would not free {} bytes", missing);
+            return false;
+        }
+
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        for file_entry in victims {
+            match blob_store.remove(&file_entry.hash) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => error!("{}", err),
+                _ => match self.file_store.write().unwrap().evict_file(&file_entry.hash) {
+                    Ok(_) => info!("Evicted file {} ({})", file_entry.hash, policy.name()),
+                    Err(err) => error!("Could not evict {}: {}", file_entry.hash, err),
+                },
+            }
+        }
+        self.force_ping.swap(true, Ordering::Relaxed);
+
+        self.file_store.read().unwrap().capacity_left() >= size
+    }
+
+    // Removes a corrupted file from the file_store and moves its content aside
+    pub fn quarantine_file(&self, hash: &str) -> std::io::Result<()> {
+        let file_entry = match self.file_store.write().unwrap().quarantine_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(()),
+        };
+
+        let blob_store = self.file_store.read().unwrap().blob_store();
+        blob_store.quarantine(&file_entry.hash)
+    }
+
+    // Returns the encoding to store a file of the given content type with
+    fn encoding_for(&self, content_type: &str) -> Encoding {
+        if compression::should_compress(content_type) {
+            self.config_store.read().unwrap().compression()
+        } else {
+            Encoding::Identity
+        }
+    }
+
+    /* Counts a download or lookup of a file. Downloads must not wait for the lock of the
+     * file_store, so the accesses are kept aside and written in a batch by flush_accesses.
+     */
+    pub fn record_access(&self, hash: &str) {
+        let mut accesses = self.accesses.lock().unwrap();
+        let access = accesses.entry(String::from(hash)).or_default();
+        access.last_accessed = chrono::Utc::now().timestamp();
+        access.count += 1;
+    }
 
+    // Writes the accesses counted since the last call to the FileStore, they are kept on failure
+    pub fn flush_accesses(&self) {
+        let accesses = std::mem::take(&mut *self.accesses.lock().unwrap());
+        if accesses.is_empty() {
+            return;
+        }
+        if let Err(err) = self.file_store.write().unwrap().record_accesses(&accesses) {
+            error!("Could not record the accesses of {} files: {}", accesses.len(), err);
+            let mut pending = self.accesses.lock().unwrap();
+            for (hash, access) in accesses {
+                let entry = pending.entry(hash).or_default();
+                entry.last_accessed = entry.last_accessed.max(access.last_accessed);
+                entry.count += access.count;
+            }
+        }
+    }
+
+    // Write file_store and stat_store to disk
+    pub fn serialize_state(&self) {
+        self.flush_accesses();
+        self.file_store.read().unwrap().serialize_state();
+        self.stat_store.read().unwrap().serialize_state();
+    }
+
+    fn announce_new_file(&self, hash: &str, distribute: bool) -> std::io::Result<()> {
         // Update uploaded_hashes
         self.file_store
             .write()
             .unwrap()
-            .add_hash_to_uploaded_hashes(&hash);
+            .add_hash_to_uploaded_hashes(hash)?;
 
         // If needed, enqueue hash to distribution
         if distribute {
             self.file_store
                 .write()
                 .unwrap()
-                .insert_file_to_distribute(&hash);
+                .insert_file_to_distribute(hash)?;
         }
 
+        // Written right away, the queues must survive a crash before the next ping
+        self.file_store.read().unwrap().serialize_state();
+
         // Force ping service to send a new ping to monitor
         self.force_ping.swap(true, Ordering::Relaxed);
+        Ok(())
+    }
 
-        return hash;
+    fn calculate_weight(&self) -> f32 {
+        let capacity_left = self.file_store.read().unwrap().capacity_left();
+        let capacity = self.file_store.read().unwrap().capacity();
+        self.stat_store
+            .read()
+            .unwrap()
+            .total_rating(capacity_left, capacity)
     }
+}
 
-    // Write file_store and stat_store to disk
-    pub fn serialize_state(&self) {
-        self.file_store.read().unwrap().serialize_state();
-        self.stat_store.read().unwrap().serialize_state();
+/*
+ * UploadLock
+ * Held by the request which appends to a resumable upload. Partial uploads do not count in
+ * capacity_left, the request only reserves space for the bytes it writes. When the lock is
+ * dropped, the upload is released for the next request and the space is released as well,
+ * also if the request is cancelled because the client went away.
+ */
+pub struct UploadLock<'a> {
+    app_state: &'a AppState,
+    id: String,    // Id of the locked upload
+    reserved: u64, // Space reserved by the request
+    written: u64,  // Part of the reserved space written by the request
+}
+
+impl UploadLock<'_> {
+    // Reserves more space for the request, fails if not enough space is left
+    pub fn reserve(&mut self, size: u64) -> bool {
+        if !self.app_state.file_store.write().unwrap().reserve_space(size) {
+            return false;
+        }
+        self.reserved += size;
+        true
     }
 
-    fn calculate_weight(&self) -> f32 {
-        let usage = self.file_store.read().unwrap().capacity_left();
-        self.stat_store.read().unwrap().total_rating(usage)
+    // Marks reserved space as written, it stays marked until the lock is dropped
+    pub fn written(&mut self, size: u64) {
+        self.app_state.file_store.read().unwrap().mark_written(size);
+        self.written += size;
+    }
+
+    pub fn reserved(&self) -> u64 {
+        self.reserved
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        let app_state = self.app_state;
+        let mut file_store = app_state
+            .file_store
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        file_store.unmark_written(self.written);
+        file_store.release_space(self.reserved);
+        drop(file_store);
+        app_state
+            .uploads
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .unlock(&self.id);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /* Opens a node with its files in memory and the state in the given dir
+     *
+     * options: Entries of config.json besides the required ones
+     */
+    pub fn open_app_state(
+        dir: &tempfile::TempDir,
+        options: serde_json::Value,
+    ) -> std::io::Result<AppState> {
+        let mut config = serde_json::json!({
+            "fingerprint": "test",
+            "port": 0,
+            "manager_addr": "http://127.0.0.1:1",
+            "blob_store": { "type": "memory" },
+        });
+        for (key, value) in options.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+        let config: ConfigFromFile = serde_json::from_value(config).unwrap();
+        let stats: Stats =
+            serde_json::from_str(include_str!("../state_example/stat_state.json")).unwrap();
+        let monitor = Monitor {
+            addr: String::from("http://127.0.0.1:1"),
+            bound: Vec::new(),
+        };
+        AppState::new(
+            config,
+            stats,
+            monitor.clone(),
+            vec![monitor],
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+            dir.path().to_str().unwrap(),
+        )
+    }
+
+    // Stores a file as if it was uploaded, returns its hash
+    pub fn upload(app_state: &AppState, content: &[u8]) -> ContentHash {
+        let temp_path = app_state
+            .file_store
+            .read()
+            .unwrap()
+            .temp_file_path()
+            .unwrap();
+        std::fs::write(&temp_path, content).unwrap();
+        let hash = ContentHasher::hash_file(&temp_path).unwrap();
+        app_state
+            .add_new_file_from_path(
+                &hash,
+                &temp_path,
+                "text/plain",
+                "file.txt",
+                FileSource::Upload,
+                true,
+            )
+            .unwrap();
+        hash
+    }
+
+    #[test]
+    fn new_files_are_written_before_they_are_confirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = open_app_state(&dir, serde_json::json!({})).unwrap();
+        let hash = upload(&app_state, b"uploaded");
+
+        // Read back like after a crash, without the state being written by a ping
+        let state = std::fs::read_to_string(dir.path().join("file_state.json")).unwrap();
+        let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        assert!(state["files"].get(&hash.hash).is_some());
+        assert_eq!(state["uploaded_hashes"], serde_json::json!([hash.hash]));
+        assert_eq!(state["files_to_distribute"], serde_json::json!([hash.hash]));
+    }
+
+    #[test]
+    fn reported_entries_are_cleared_after_the_ping() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = open_app_state(&dir, serde_json::json!({})).unwrap();
+        let hash = upload(&app_state, b"uploaded");
+        app_state
+            .file_store
+            .write()
+            .unwrap()
+            .report_lost_hash("lost")
+            .unwrap();
+
+        // The ping is generated again as long as the monitor did not receive it
+        let ping = app_state.generate_ping().unwrap();
+        assert_eq!(
+            app_state.generate_ping().unwrap().lost_hashes,
+            ping.lost_hashes
+        );
+
+        // Entries added while the ping was sent are kept for the next one
+        app_state
+            .file_store
+            .write()
+            .unwrap()
+            .report_lost_hash("lost later")
+            .unwrap();
+        app_state.clear_reported(&ping).unwrap();
+        let next_ping = app_state.generate_ping().unwrap();
+        assert!(ping.uploaded_hashes.contains(&hash.hash));
+        assert!(next_ping.uploaded_hashes.is_empty());
+        assert_eq!(next_ping.lost_hashes, vec![String::from("lost later")]);
+    }
+
+    #[test]
+    fn accesses_are_written_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = open_app_state(&dir, serde_json::json!({})).unwrap();
+        let hash = upload(&app_state, b"downloaded");
+        let access_count = |app_state: &AppState| {
+            let file_store = app_state.file_store.read().unwrap();
+            file_store
+                .get_file(&hash.hash)
+                .unwrap()
+                .unwrap()
+                .access_count
+        };
+
+        app_state.record_access(&hash.hash);
+        app_state.record_access(&hash.legacy_hash);
+        assert_eq!(access_count(&app_state), 0);
+
+        app_state.serialize_state();
+        assert_eq!(access_count(&app_state), 2);
+        let state = std::fs::read_to_string(dir.path().join("file_state.json")).unwrap();
+        let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        assert_eq!(state["files"][&hash.hash]["access_count"], 2);
+    }
+
+    #[test]
+    fn trashed_files_are_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = open_app_state(&dir, serde_json::json!({})).unwrap();
+        let hash = upload(&app_state, b"restored");
+        app_state.remove_file_on_request(&hash.hash);
+        let ping = app_state.generate_ping().unwrap();
+        app_state.clear_reported(&ping).unwrap();
+        assert!(app_state
+            .file_store
+            .read()
+            .unwrap()
+            .get_file(&hash.hash)
+            .unwrap()
+            .is_none());
+        assert!(app_state.restore_file("unknown").unwrap().is_none());
+
+        // Not enough space left, the file stays in the trash
+        let capacity_left = app_state.file_store.read().unwrap().capacity_left();
+        assert!(app_state
+            .file_store
+            .write()
+            .unwrap()
+            .reserve_space(capacity_left));
+        let err = app_state.restore_file(&hash.hash).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        app_state
+            .file_store
+            .write()
+            .unwrap()
+            .release_space(capacity_left);
+        assert_eq!(
+            app_state
+                .file_store
+                .read()
+                .unwrap()
+                .trashed_files()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Restored by its legacy hash, the monitor learns about it again
+        let file_entry = app_state.restore_file(&hash.legacy_hash).unwrap().unwrap();
+        assert_eq!(file_entry.hash, hash.hash);
+        let file_store = app_state.file_store.read().unwrap();
+        assert!(file_store.trashed_files().unwrap().is_empty());
+        assert!(file_store.get_file(&hash.hash).unwrap().is_some());
+        assert_eq!(
+            file_store.blob_store().get(&hash.hash).unwrap(),
+            b"restored"
+        );
+        assert_eq!(file_store.uploaded_hashes().unwrap(), vec![hash.hash]);
+    }
+
+    #[test]
+    fn files_are_removed_right_away_without_trash_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "trash_retention": 0 });
+        let app_state = open_app_state(&dir, options).unwrap();
+        let hash = upload(&app_state, b"removed");
+        app_state.remove_file_on_request(&hash.hash);
+
+        assert!(app_state.restore_file(&hash.hash).unwrap().is_none());
+        let file_store = app_state.file_store.read().unwrap();
+        assert!(file_store.get_file(&hash.hash).unwrap().is_none());
+        assert!(file_store.blob_store().get(&hash.hash).is_err());
+    }
+
+    #[test]
+    fn dropped_upload_locks_release_their_reservation() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = open_app_state(&dir, serde_json::json!({})).unwrap();
+        let upload = app_state
+            .uploads
+            .write()
+            .unwrap()
+            .create(100, "file.txt", "text/plain", i64::MAX)
+            .unwrap();
+        let capacity_left = app_state.file_store.read().unwrap().capacity_left();
+
+        let mut lock = app_state.lock_upload(&upload.id).unwrap();
+        assert!(app_state.lock_upload(&upload.id).is_none());
+        assert!(lock.reserve(60));
+        assert!(lock.reserve(40));
+        lock.written(70);
+        assert_eq!(lock.reserved(), 100);
+        assert_eq!(
+            app_state.file_store.read().unwrap().capacity_left(),
+            capacity_left - 100
+        );
+
+        // Dropped like after a failed request, the next request may lock the upload again
+        drop(lock);
+        assert_eq!(
+            app_state.file_store.read().unwrap().capacity_left(),
+            capacity_left
+        );
+        assert!(app_state.lock_upload(&upload.id).is_some());
     }
 }
@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, RwLock};
+
+use crate::config::BlobStoreConfig;
+use crate::s3_blob_store::S3BlobStore;
+
+/*
+ * BlobStore
+ * Stores the content of files, addressed by a key (the SHA-256 hash of the content).
+ * The FileStore keeps the metadata of all files and uses a BlobStore beneath it, so the
+ * content can live on the local disk, in memory or in an S3 compatible object store.
+ * All functions block, callers in async code should use spawn_blocking.
+ */
+
+pub trait BlobStoreFunc: Send + Sync {
+    fn put(&self, key: &str, content: &[u8]) -> std::io::Result<()>;             // Stores the content under the given key
+    fn put_file(&self, key: &str, temp_path: &str) -> std::io::Result<()>;       // Takes over a finished temporary file, which is removed afterwards
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;                         // Returns the whole content
+    fn get_range(&self, key: &str, start: u64, len: u64) -> std::io::Result<Vec<u8>>; // Returns len bytes of the content, beginning at start
+    fn size(&self, key: &str) -> std::io::Result<u64>;                            // Returns the size of the content, NotFound if it does not exist
+    fn remove(&self, key: &str) -> std::io::Result<()>;                           // Removes the content
+    fn quarantine(&self, key: &str) -> std::io::Result<()>;                       // Moves corrupted content aside, to keep it for inspection
+    fn trash(&self, key: &str) -> std::io::Result<()>;                            // Moves deleted content to the trash, so it can be restored
+    fn restore(&self, key: &str) -> std::io::Result<()>;                          // Moves content back from the trash
+    fn purge(&self, key: &str) -> std::io::Result<()>;                            // Removes content from the trash
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;                // Stores the content under a new key
+    fn relocate(&self, key: &str) -> std::io::Result<bool>;                       // Moves the content to its place in the configured layout, false if it was there already
+    fn keys(&self) -> std::io::Result<Vec<String>>;                               // Returns the keys of all stored contents
+    fn capacity(&self) -> Option<u64>;                                            // Space of all storage locations, None if only the capacity in stat_state.json limits it
+    fn free_space(&self) -> Option<u64>;                                          // Space left on the disk minus the safety margin, None if it is not limited by a local disk
+    fn chunk_size(&self) -> u64;                                                  // Preferred amount of bytes to read at once
+}
+
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024; // Space kept free on local disks, 1 GiB
+
+/* Creates the BlobStore configured in config.json, files are stored in the state dir by default
+ *
+ * min_free_space: Bytes which files must leave free on a local disk
+ */
+pub fn from_config(
+    config: Option<&BlobStoreConfig>,
+    path: &str,
+    min_free_space: u64,
+) -> Arc<dyn BlobStoreFunc> {
+    match config {
+        None => Arc::new(LocalBlobStore::new(path, 0, min_free_space)),
+        Some(BlobStoreConfig::Local(local_config)) => Arc::new(LocalBlobStore::new(
+            path,
+            local_config.shard_levels.unwrap_or(0),
+            min_free_space,
+        )),
+        Some(BlobStoreConfig::Memory) => Arc::new(MemoryBlobStore::new()),
+        Some(BlobStoreConfig::S3(s3_config)) => Arc::new(S3BlobStore::new(s3_config.clone())),
+    }
+}
+
+/*
+ * LocalBlobStore
+ * Stores every content as a file in {path}/files, corrupted files are moved to
+ * {path}/quarantine/{key} and deleted files to {path}/trash/{key}.
+ *
+ * With shard_levels set, files are spread over nested directories named after the first
+ * characters of their key, like files/ab/cd/{key} for two levels, so no directory holds too
+ * many files. Contents stored in another layout stay readable, relocate moves them to their
+ * place in the configured layout while the node is running.
+ */
+
+pub const MAX_SHARD_LEVELS: usize = 3; // Deepest supported layout
+const SHARD_WIDTH: usize = 2; // Characters of the key per directory level, 256 directories per level
+
+pub struct LocalBlobStore {
+    path: String,        // Path of the state dir
+    shard_levels: usize, // Number of directory levels below files, 0 stores all files flat
+    min_free_space: u64, // Bytes which files must leave free on the disk
+}
+
+impl LocalBlobStore {
+    pub fn new(path: &str, shard_levels: usize, min_free_space: u64) -> LocalBlobStore {
+        if shard_levels > MAX_SHARD_LEVELS {
+            panic!(
+                "shard_levels must not be larger than {}, got {}",
+                MAX_SHARD_LEVELS, shard_levels
+            );
+        }
+
+        LocalBlobStore {
+            path: String::from(path),
+            shard_levels,
+            min_free_space,
+        }
+    }
+
+    // Path of the content in the configured layout
+    fn file_path(&self, key: &str) -> String {
+        self.layout_path(key, self.shard_levels)
+    }
+
+    // Path of the content with the given number of directory levels, keys too short are stored flat
+    fn layout_path(&self, key: &str, levels: usize) -> String {
+        let mut path = format!("{}/files", self.path);
+        if key.len() > levels * SHARD_WIDTH {
+            for level in 0..levels {
+                match key.get(level * SHARD_WIDTH..(level + 1) * SHARD_WIDTH) {
+                    Some(shard) => {
+                        path.push('/');
+                        path.push_str(shard);
+                    }
+                    None => return format!("{}/files/{}", self.path, key),
+                }
+            }
+        }
+        format!("{}/{}", path, key)
+    }
+
+    // Paths of the content in all layouts except the configured one
+    fn other_paths(&self, key: &str) -> Vec<String> {
+        let file_path = self.file_path(key);
+        (0..=MAX_SHARD_LEVELS)
+            .map(|levels| self.layout_path(key, levels))
+            .filter(|path| *path != file_path)
+            .collect()
+    }
+
+    // Returns the path of the stored content, which may still be in another layout
+    fn find_path(&self, key: &str) -> String {
+        let file_path = self.file_path(key);
+        if std::path::Path::new(&file_path).exists() {
+            return file_path;
+        }
+
+        self.other_paths(key)
+            .into_iter()
+            .find(|path| std::path::Path::new(path).exists())
+            .unwrap_or(file_path)
+    }
+
+    /* Runs op with the path of the stored content. If the content was moved to the configured
+     * layout in the meantime, op runs again with its new path.
+     */
+    fn with_path<T>(
+        &self,
+        key: &str,
+        op: impl Fn(&str) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let path = self.find_path(key);
+        match op(&path) {
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound && path != self.file_path(key) =>
+            {
+                op(&self.file_path(key))
+            }
+            result => result,
+        }
+    }
+
+    fn trash_path(&self, key: &str) -> String {
+        format!("{}/trash/{}", self.path, key)
+    }
+
+    fn create_dir(&self, name: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(std::path::Path::new(&self.path).join(name))
+    }
+
+    // Creates the shard directories of a path in the files dir
+    fn create_parent(&self, path: &str) -> std::io::Result<()> {
+        match std::path::Path::new(path).parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    // Returns a hidden path next to the given one, in the same shard, which keys() skips
+    fn partial_path(&self, path: &str) -> String {
+        let path = std::path::Path::new(path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".partial-{}", name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Copies the file next to its destination first, so the content appears atomically
+    fn move_across_devices(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let partial_path = self.partial_path(to);
+        let copied = std::fs::copy(from, &partial_path)
+            .and_then(|_| File::open(&partial_path)?.sync_all())
+            .and_then(|_| std::fs::rename(&partial_path, to));
+        if let Err(err) = copied {
+            let _ = std::fs::remove_file(&partial_path);
+            return Err(err);
+        }
+        std::fs::remove_file(from)
+    }
+
+    // Removes the shard directories of a moved file, as long as they are empty
+    fn remove_empty_parents(&self, path: &str) {
+        let files_dir = std::path::Path::new(&self.path).join("files");
+        let mut dir = std::path::Path::new(path).parent();
+        while let Some(parent) = dir {
+            if parent == files_dir || std::fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+}
+
+impl BlobStoreFunc for LocalBlobStore {
+    fn put(&self, key: &str, content: &[u8]) -> std::io::Result<()> {
+        let file_path = self.file_path(key);
+        self.create_parent(&file_path)?;
+        // Written next to its destination first, so a crash never leaves truncated content
+        let partial_path = self.partial_path(&file_path);
+        let written = File::create(&partial_path)
+            .and_then(|mut file| {
+                file.write_all(content)?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&partial_path, &file_path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&partial_path);
+        }
+        written
+    }
+
+    fn put_file(&self, key: &str, temp_path: &str) -> std::io::Result<()> {
+        let file_path = self.file_path(key);
+        self.create_parent(&file_path)?;
+        // The temporary file lives inside the state dir, so the rename is atomic, unless the
+        // files are kept on another disk
+        match std::fs::rename(temp_path, &file_path) {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                self.move_across_devices(temp_path, &file_path)
+            }
+            result => result,
+        }
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        self.with_path(key, |path| std::fs::read(path))
+    }
+
+    fn get_range(&self, key: &str, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let mut file = self.with_path(key, |path| File::open(path))?;
+        let mut buf = vec![0; len as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn size(&self, key: &str) -> std::io::Result<u64> {
+        self.with_path(key, |path| {
+            std::fs::metadata(path).map(|metadata| metadata.len())
+        })
+    }
+
+    fn remove(&self, key: &str) -> std::io::Result<()> {
+        self.with_path(key, |path| std::fs::remove_file(path))
+    }
+
+    fn quarantine(&self, key: &str) -> std::io::Result<()> {
+        self.create_dir("quarantine")?;
+        let quarantine_path = format!("{}/quarantine/{}", self.path, key);
+        match self.with_path(key, |path| std::fs::rename(path, &quarantine_path)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn trash(&self, key: &str) -> std::io::Result<()> {
+        self.create_dir("trash")?;
+        let trash_path = self.trash_path(key);
+        match self.with_path(key, |path| std::fs::rename(path, &trash_path)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn restore(&self, key: &str) -> std::io::Result<()> {
+        let file_path = self.file_path(key);
+        self.create_parent(&file_path)?;
+        std::fs::rename(self.trash_path(key), file_path)
+    }
+
+    fn purge(&self, key: &str) -> std::io::Result<()> {
+        std::fs::remove_file(self.trash_path(key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let to_path = self.file_path(to);
+        self.create_parent(&to_path)?;
+        self.with_path(from, |path| std::fs::rename(path, &to_path))
+    }
+
+    fn relocate(&self, key: &str) -> std::io::Result<bool> {
+        let file_path = self.file_path(key);
+        let mut moved = false;
+
+        for path in self.other_paths(key) {
+            if !std::path::Path::new(&path).is_file() {
+                continue;
+            }
+
+            // Contents are addressed by their hash, a copy in the configured layout is the same
+            let result = if std::path::Path::new(&file_path).exists() {
+                std::fs::remove_file(&path)
+            } else {
+                self.create_parent(&file_path)?;
+                std::fs::rename(&path, &file_path)
+            };
+
+            match result {
+                // Removed or moved by another call in the meantime
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+                Ok(()) => {}
+            }
+            self.remove_empty_parents(&path);
+            moved = true;
+        }
+
+        Ok(moved)
+    }
+
+    fn keys(&self) -> std::io::Result<Vec<String>> {
+        let mut keys = vec![];
+        collect_keys(
+            &std::path::Path::new(&self.path).join("files"),
+            MAX_SHARD_LEVELS,
+            &mut keys,
+        )?;
+
+        // During a migration a content may briefly exist in two layouts
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
+
+    // The files dir may be a mount point of its own
+    fn free_space(&self) -> Option<u64> {
+        let files_dir = format!("{}/files", self.path);
+        let available = available_space(&files_dir)
+            .or_else(|_| available_space(&self.path))
+            .ok()?;
+        Some(available.saturating_sub(self.min_free_space))
+    }
+
+    fn chunk_size(&self) -> u64 {
+        64 * 1024
+    }
+}
+
+// Returns the space on the filesystem of path which is available to unprivileged users
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn available_space(path: &str) -> std::io::Result<u64> {
+    let c_path = std::ffi::CString::new(path)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &str) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space is only known on unix",
+    ))
+}
+
+/* Collects the names of all files in dir and in its shard directories
+ *
+ * depth: Number of directory levels below dir which may hold files
+ */
+fn collect_keys(
+    dir: &std::path::Path,
+    depth: usize,
+    keys: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let dir_entries = match std::fs::read_dir(dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        let path = dir_entry.path();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        if path.is_file() {
+            keys.push(name);
+        } else if depth > 0 && path.is_dir() && is_shard(&name) {
+            collect_keys(&path, depth - 1, keys)?;
+        }
+    }
+    Ok(())
+}
+
+// Shard directories are named after SHARD_WIDTH hex characters of the keys they hold
+fn is_shard(name: &str) -> bool {
+    name.len() == SHARD_WIDTH && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/*
+ * MemoryBlobStore
+ * Keeps all contents in memory, they are lost when the node stops. Meant for tests.
+ */
+
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: RwLock<HashMap<String, Vec<u8>>>,       // Content of all files
+    quarantined: RwLock<HashMap<String, Vec<u8>>>, // Content of all corrupted files
+    trashed: RwLock<HashMap<String, Vec<u8>>>,     // Content of all deleted files
+    free_space: RwLock<Option<u64>>,               // Free space to report, as if on a local disk
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> MemoryBlobStore {
+        MemoryBlobStore::default()
+    }
+
+    // Reports the given free space from now on, None if it is not limited
+    #[cfg(test)]
+    pub fn set_free_space(&self, free_space: Option<u64>) {
+        *self.free_space.write().unwrap() = free_space;
+    }
+}
+
+pub fn not_found(key: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} does not exist", key),
+    )
+}
+
+impl BlobStoreFunc for MemoryBlobStore {
+    fn put(&self, key: &str, content: &[u8]) -> std::io::Result<()> {
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(String::from(key), content.to_vec());
+        Ok(())
+    }
+
+    fn put_file(&self, key: &str, temp_path: &str) -> std::io::Result<()> {
+        let content = std::fs::read(temp_path)?;
+        self.put(key, &content)?;
+        std::fs::remove_file(temp_path)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn get_range(&self, key: &str, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let blobs = self.blobs.read().unwrap();
+        let content = blobs.get(key).ok_or_else(|| not_found(key))?;
+        content
+            .get(start as usize..(start + len) as usize)
+            .map(|range| range.to_vec())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("range exceeds {}", key),
+                )
+            })
+    }
+
+    fn size(&self, key: &str) -> std::io::Result<u64> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|content| content.len() as u64)
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn remove(&self, key: &str) -> std::io::Result<()> {
+        self.blobs
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn quarantine(&self, key: &str) -> std::io::Result<()> {
+        if let Some(content) = self.blobs.write().unwrap().remove(key) {
+            self.quarantined
+                .write()
+                .unwrap()
+                .insert(String::from(key), content);
+        }
+        Ok(())
+    }
+
+    fn trash(&self, key: &str) -> std::io::Result<()> {
+        if let Some(content) = self.blobs.write().unwrap().remove(key) {
+            self.trashed
+                .write()
+                .unwrap()
+                .insert(String::from(key), content);
+        }
+        Ok(())
+    }
+
+    fn restore(&self, key: &str) -> std::io::Result<()> {
+        let content = self
+            .trashed
+            .write()
+            .unwrap()
+            .remove(key)
+            .ok_or_else(|| not_found(key))?;
+        self.put(key, &content)
+    }
+
+    fn purge(&self, key: &str) -> std::io::Result<()> {
+        self.trashed
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let mut blobs = self.blobs.write().unwrap();
+        let content = blobs.remove(from).ok_or_else(|| not_found(from))?;
+        blobs.insert(String::from(to), content);
+        Ok(())
+    }
+
+    fn relocate(&self, _key: &str) -> std::io::Result<bool> {
+        Ok(false)
+    }
+
+    fn keys(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.blobs.read().unwrap().keys().cloned().collect())
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        *self.free_space.read().unwrap()
+    }
+
+    fn chunk_size(&self) -> u64 {
+        64 * 1024
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    fn assert_not_found<T: std::fmt::Debug>(result: std::io::Result<T>) {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    /* Checks the behaviour all BlobStores share, with keys starting with the given prefix
+     *
+     * temp_dir: Dir for the temporary files taken over by put_file
+     */
+    pub fn check_blob_store(store: &dyn BlobStoreFunc, prefix: &str, temp_dir: &std::path::Path) {
+        let key = |name: &str| format!("{}{}", prefix, name);
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+        store.put(&key("a"), &content).unwrap();
+        assert_eq!(store.get(&key("a")).unwrap(), content);
+        assert_eq!(store.size(&key("a")).unwrap(), content.len() as u64);
+        assert_eq!(
+            store.get_range(&key("a"), 1000, 10).unwrap(),
+            content[1000..1010]
+        );
+        assert!(store.get_range(&key("a"), 99_995, 10).is_err());
+        assert_not_found(store.get(&key("missing")));
+        assert_not_found(store.size(&key("missing")));
+
+        let temp_path = temp_dir.join("upload");
+        std::fs::write(&temp_path, b"from a file").unwrap();
+        store
+            .put_file(&key("b"), temp_path.to_str().unwrap())
+            .unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(store.get(&key("b")).unwrap(), b"from a file");
+
+        let keys = store.keys().unwrap();
+        assert!(keys.contains(&key("a")) && keys.contains(&key("b")));
+
+        store.rename(&key("b"), &key("c")).unwrap();
+        assert_not_found(store.get(&key("b")));
+        assert_eq!(store.get(&key("c")).unwrap(), b"from a file");
+
+        store.trash(&key("a")).unwrap();
+        assert_not_found(store.get(&key("a")));
+        store.restore(&key("a")).unwrap();
+        assert_eq!(store.get(&key("a")).unwrap(), content);
+        store.trash(&key("a")).unwrap();
+        store.purge(&key("a")).unwrap();
+        assert!(store.restore(&key("a")).is_err());
+
+        store.quarantine(&key("c")).unwrap();
+        assert_not_found(store.get(&key("c")));
+        store.quarantine(&key("missing")).unwrap();
+
+        store.put(&key("d"), b"").unwrap();
+        assert_eq!(store.size(&key("d")).unwrap(), 0);
+        store.remove(&key("d")).unwrap();
+        assert_not_found(store.get(&key("d")));
+        assert!(!store
+            .keys()
+            .unwrap()
+            .iter()
+            .any(|name| name.starts_with(prefix)));
+    }
+
+    #[test]
+    fn memory_blob_store() {
+        let dir = tempfile::tempdir().unwrap();
+        check_blob_store(&MemoryBlobStore::new(), "", dir.path());
+    }
+
+    #[test]
+    fn local_blob_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        for shard_levels in 0..=MAX_SHARD_LEVELS {
+            let store = LocalBlobStore::new(path, shard_levels, 0);
+            check_blob_store(&store, "abcdef", dir.path());
+        }
+    }
+
+    #[test]
+    fn local_blob_store_relocates_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        LocalBlobStore::new(path, 0, 0)
+            .put("abcdef", b"content")
+            .unwrap();
+
+        let store = LocalBlobStore::new(path, 2, 0);
+        assert_eq!(store.get("abcdef").unwrap(), b"content");
+        assert!(store.relocate("abcdef").unwrap());
+        assert!(!store.relocate("abcdef").unwrap());
+        assert!(dir.path().join("files/ab/cd/abcdef").is_file());
+        assert_eq!(store.keys().unwrap(), vec![String::from("abcdef")]);
+    }
+
+    #[test]
+    fn local_blob_store_replaces_contents_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(dir.path().to_str().unwrap(), 2, 0);
+        store.put("abcdef", b"old").unwrap();
+        store.put("abcdef", b"new content").unwrap();
+
+        assert_eq!(store.get("abcdef").unwrap(), b"new content");
+        let shard = std::fs::read_dir(dir.path().join("files/ab/cd"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(shard, vec![std::ffi::OsString::from("abcdef")]);
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::Utc;
+use log::info;
+use std::io::{Error, ErrorKind};
+
+use crate::config;
+use crate::http_requests::{rotate_key_on_node, set_pin_on_node, set_retention_on_node};
+use crate::server::HoldResponse;
+use crate::token_store::{ApiToken, Scope, TokenStore};
+
+/*
+ * Cli
+ * Subcommands to pin files, set retention holds, rotate the encryption key and manage the API
+ * tokens. Holds and keys are changed through the HTTP endpoints of the node running on this
+ * machine, whose port is read from the config in the state dir. They need an admin token in
+ * NODE_API_TOKEN, unless the node trusts local requests. Held files are kept even if the monitor
+ * asks to delete them. The tokens are managed in the state dir directly, so the first admin
+ * token can be created before the node is started.
+ *
+ * node-app pin <state dir> <hash>
+ * node-app unpin <state dir> <hash>
+ * node-app retain <state dir> <hash> <days>   (0 days clears the hold)
+ * node-app rotate-key <state dir>
+ * node-app create-token <state dir> <name> <scope>...   (upload, delete or admin)
+ * node-app list-tokens <state dir>
+ * node-app revoke-token <state dir> <id>
+ */
+
+const TOKEN_VAR: &str = "NODE_API_TOKEN"; // Environment variable which holds an admin token
+
+// Returns true if the subcommand is handled by run
+pub fn is_command(command: &str) -> bool {
+    matches!(
+        command,
+        "pin" | "unpin" | "retain" | "rotate-key" | "create-token" | "list-tokens" | "revoke-token"
+    )
+}
+
+/* Entry point of all subcommands
+ *
+ * command: Name of the subcommand
+ * args: Arguments after the subcommand
+ */
+pub async fn run(command: &str, args: &[String]) -> std::io::Result<()> {
+    let path = args.first().ok_or_else(usage)?;
+    match command {
+        "create-token" => return create_token(path, &args[1..]),
+        "list-tokens" => return list_tokens(path),
+        "revoke-token" => return revoke_token(path, args.get(1).ok_or_else(usage)?),
+        _ => {}
+    }
+
+    let config = config::parse_config(path);
+    let node_addr = format!("http://127.0.0.1:{}", config.port);
+    let api_token = std::env::var(TOKEN_VAR).ok();
+    let api_token = api_token.as_deref();
+
+    if command == "rotate-key" {
+        return rotate_key(&node_addr, api_token).await;
+    }
+    let hash = args.get(1).ok_or_else(usage)?;
+
+    let response = match command {
+        "pin" => set_pin_on_node(&node_addr, api_token, hash, true).await,
+        "unpin" => set_pin_on_node(&node_addr, api_token, hash, false).await,
+        _ => {
+            let days = args
+                .get(2)
+                .and_then(|days| days.parse::<i64>().ok())
+                .ok_or_else(usage)?;
+            let retain_until = match days {
+                0 => None,
+                days => Some(Utc::now().timestamp() + days * 24 * 60 * 60),
+            };
+            set_retention_on_node(&node_addr, api_token, hash, retain_until).await
+        }
+    };
+
+    match response {
+        Ok(response) => {
+            log_hold(&response);
+            Ok(())
+        }
+        Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no file with hash {}", hash),
+        )),
+        Err(err) => Err(request_error(err)),
+    }
+}
+
+// Existing files are re-encrypted by the node in the background
+async fn rotate_key(node_addr: &str, api_token: Option<&str>) -> std::io::Result<()> {
+    match rotate_key_on_node(node_addr, api_token).await {
+        Ok(response) => {
+            info!("Files are encrypted with key {} from now on", response.key_id);
+            info!("Existing files are re-encrypted in the background");
+            Ok(())
+        }
+        Err(err) if err.status() == Some(reqwest::StatusCode::CONFLICT) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "encryption is disabled in config.json",
+        )),
+        Err(err) => Err(request_error(err)),
+    }
+}
+
+// The token is only shown once, the state dir keeps just its hash
+fn create_token(path: &str, args: &[String]) -> std::io::Result<()> {
+    let name = args.first().ok_or_else(usage)?;
+    let scopes = args[1..]
+        .iter()
+        .flat_map(|scopes| scopes.split(','))
+        .map(|scope| {
+            Scope::from_name(scope.trim()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown scope {}, use upload, delete or admin", scope),
+                )
+            })
+        })
+        .collect::<std::io::Result<Vec<Scope>>>()?;
+    if scopes.is_empty() {
+        return Err(usage());
+    }
+
+    let (api_token, token) = TokenStore::open(path)?.create(name, &scopes)?;
+    log_token(&api_token);
+    info!("Token: {}", token);
+    info!("Store it now, it can not be shown again");
+    Ok(())
+}
+
+fn list_tokens(path: &str) -> std::io::Result<()> {
+    let tokens = TokenStore::open(path)?;
+    if !tokens.is_enabled() {
+        info!("No API tokens, uploads are accepted from everyone, deletions and admin requests are rejected");
+    }
+    tokens.tokens().iter().for_each(log_token);
+    Ok(())
+}
+
+fn revoke_token(path: &str, id: &str) -> std::io::Result<()> {
+    match TokenStore::open(path)?.revoke(id)? {
+        Some(api_token) => {
+            info!("Revoked token {} ({})", api_token.id, api_token.name);
+            Ok(())
+        }
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no token with id {}", id),
+        )),
+    }
+}
+
+fn log_token(api_token: &ApiToken) {
+    let scopes: Vec<&str> = api_token.scopes.iter().map(|scope| scope.name()).collect();
+    let created_at = chrono::DateTime::from_timestamp(api_token.created_at, 0)
+        .map(|created_at| created_at.to_string())
+        .unwrap_or_default();
+    info!(
+        "Token {} ({}), scopes: {}, created: {}",
+        api_token.id,
+        api_token.name,
+        scopes.join(", "),
+        created_at
+    );
+}
+
+fn log_hold(response: &HoldResponse) {
+    info!("File {}", response.hash);
+    info!("Pinned: {}", response.pinned);
+    match response
+        .retain_until
+        .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+    {
+        Some(retain_until) => info!("Retained until: {}", retain_until),
+        None => info!("Retained until: -"),
+    }
+}
+
+// Tells how to authorize the command if the node rejected it
+fn request_error(err: reqwest::Error) -> Error {
+    match err.status() {
+        Some(reqwest::StatusCode::UNAUTHORIZED) | Some(reqwest::StatusCode::FORBIDDEN) => {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("the node needs an admin token in {}", TOKEN_VAR),
+            )
+        }
+        _ => Error::other(err),
+    }
+}
+
+fn usage() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "usage: node-app pin|unpin <state dir> <hash>, node-app retain <state dir> <hash> <days>, node-app rotate-key <state dir>, node-app create-token <state dir> <name> <scope>..., node-app list-tokens <state dir> or node-app revoke-token <state dir> <id>",
+    )
+}
@@ -0,0 +1,366 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, Read};
+use std::sync::Arc;
+
+use crate::blob_store::BlobStoreFunc;
+use crate::config_store::{ContentHash, ContentHasher};
+
+/*
+ * Compression
+ * Contents can be stored compressed with zstd. The FileEntry records the encoding of the stored
+ * content, its hash and size always refer to the uncompressed content. Contents of types which
+ * are compressed already are stored as they are, as are contents which would not get smaller.
+ * Readers returned by open decompress on the fly, so callers never see the stored encoding.
+ */
+
+const ZSTD_LEVEL: i32 = 3; // Default level of zstd, fast with a good ratio for text
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd]; // First bytes of every zstd frame
+
+// Content types which are compressed already and would not get smaller
+const COMPRESSED_TYPES: [&str; 14] = [
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/zstd",
+    "application/pdf",
+    "application/epub+zip",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+];
+
+// Encoding of a content in the BlobStore
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Identity, // Stored as it is
+    Zstd,     // Compressed with zstd
+}
+
+impl Encoding {
+    // Name of the encoding, as used in the Content-Encoding header and the SQLite FileStore
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Encoding {
+        match name {
+            "zstd" => Encoding::Zstd,
+            _ => Encoding::Identity,
+        }
+    }
+}
+
+// Size and encoding of a content written to the BlobStore
+#[derive(Clone, Copy, Debug)]
+pub struct StoredContent {
+    pub size: u64,          // Size of the uncompressed content
+    pub stored_size: u64,   // Size of the content in the BlobStore
+    pub encoding: Encoding, // Encoding of the content in the BlobStore
+}
+
+impl StoredContent {
+    // Content stored as it is
+    pub fn identity(size: u64) -> StoredContent {
+        StoredContent {
+            size,
+            stored_size: size,
+            encoding: Encoding::Identity,
+        }
+    }
+}
+
+// Returns the encoding for new files configured in config.json, files are not compressed by default
+pub fn from_config(compression: Option<&str>) -> Encoding {
+    match compression {
+        None | Some("none") => Encoding::Identity,
+        Some("zstd") => Encoding::Zstd,
+        Some(other) => panic!("Unknown compression {}", other),
+    }
+}
+
+// Returns false for content types which are compressed already, like images, videos and archives
+pub fn should_compress(content_type: &str) -> bool {
+    let content_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    if content_type == "image/svg+xml" || content_type == "image/bmp" {
+        return true;
+    }
+
+    !(content_type.starts_with("image/")
+        || content_type.starts_with("video/")
+        || content_type.starts_with("audio/")
+        || COMPRESSED_TYPES.contains(&content_type.as_str()))
+}
+
+/* Compresses the file at path with the given encoding into a new file at compressed_path.
+ * Returns the size of the compressed file, or None if the encoding is Identity or the file
+ * does not get smaller.
+ */
+pub fn compress_file(
+    path: &str,
+    compressed_path: &str,
+    encoding: Encoding,
+) -> std::io::Result<Option<u64>> {
+    if encoding == Encoding::Identity {
+        return Ok(None);
+    }
+
+    let size = std::fs::metadata(path)?.len();
+    let input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(compressed_path)?;
+    let compressed_size = zstd::stream::copy_encode(input, output, ZSTD_LEVEL)
+        .and_then(|_| std::fs::metadata(compressed_path))
+        .map(|metadata| metadata.len());
+
+    match compressed_size {
+        Ok(compressed_size) if compressed_size < size => Ok(Some(compressed_size)),
+        result => {
+            let _ = std::fs::remove_file(compressed_path);
+            result.map(|_| None)
+        }
+    }
+}
+
+// Returns a reader over the uncompressed content stored under the given key
+pub fn open(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+    encoding: Encoding,
+) -> std::io::Result<Box<dyn Read + Send>> {
+    let chunk_size = blob_store.chunk_size() as usize;
+    let reader = BlobReader::new(blob_store, key)?;
+    match encoding {
+        Encoding::Identity => Ok(Box::new(reader)),
+        Encoding::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(
+            BufReader::with_capacity(chunk_size, reader),
+        )?)),
+    }
+}
+
+// Returns the whole uncompressed content stored under the given key
+pub fn read(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+    encoding: Encoding,
+) -> std::io::Result<Vec<u8>> {
+    if encoding == Encoding::Identity {
+        return blob_store.get(key);
+    }
+
+    let mut content = vec![];
+    open(blob_store, key, encoding)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/* Hash a content whose encoding is unknown, like a content without FileEntry. A content which
+ * does not match its key is decompressed and hashed again, if it looks like zstd.
+ * Returns the hash with the size and encoding which matched, or those of the stored bytes otherwise.
+ */
+pub fn identify_blob(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+) -> std::io::Result<(ContentHash, StoredContent)> {
+    let stored_size = blob_store.size(key)?;
+    let content_hash = ContentHasher::hash_blob(blob_store.as_ref(), key)?;
+    let identity = (content_hash, StoredContent::identity(stored_size));
+    if identity.0.matches(key) || stored_size < ZSTD_MAGIC.len() as u64 {
+        return Ok(identity);
+    }
+
+    if blob_store.get_range(key, 0, ZSTD_MAGIC.len() as u64)? != ZSTD_MAGIC {
+        return Ok(identity);
+    }
+
+    match hash_content(blob_store, key, Encoding::Zstd) {
+        Ok((content_hash, size)) if content_hash.matches(key) => Ok((
+            content_hash,
+            StoredContent {
+                size,
+                stored_size,
+                encoding: Encoding::Zstd,
+            },
+        )),
+        _ => Ok(identity),
+    }
+}
+
+// Returns the hash and the size of the uncompressed content
+fn hash_content(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+    encoding: Encoding,
+) -> std::io::Result<(ContentHash, u64)> {
+    let chunk_size = blob_store.chunk_size() as usize;
+    let mut reader = open(blob_store, key, encoding)?;
+    let mut hasher = ContentHasher::new();
+    let mut buf = vec![0; chunk_size];
+    let mut size = 0;
+
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok((hasher.result(), size));
+        }
+        hasher.input(&buf[..len]);
+        size += len as u64;
+    }
+}
+
+// Reads the content stored under a key in chunks of the BlobStore, without loading it as a whole
+struct BlobReader {
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: String,
+    offset: u64, // Position of the next byte to read
+    size: u64,   // Size of the stored content
+}
+
+impl BlobReader {
+    fn new(blob_store: Arc<dyn BlobStoreFunc>, key: &str) -> std::io::Result<BlobReader> {
+        let size = blob_store.size(key)?;
+        Ok(BlobReader {
+            blob_store,
+            key: String::from(key),
+            offset: 0,
+            size,
+        })
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (buf.len() as u64)
+            .min(self.blob_store.chunk_size())
+            .min(self.size - self.offset);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let chunk = self.blob_store.get_range(&self.key, self.offset, len)?;
+        buf[..chunk.len()].copy_from_slice(&chunk);
+        self.offset += chunk.len() as u64;
+        Ok(chunk.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::tests::{open_app_state, upload};
+    use crate::blob_store::MemoryBlobStore;
+
+    fn text() -> Vec<u8> {
+        "All work and no play makes Jack a dull boy.\n"
+            .repeat(2000)
+            .into_bytes()
+    }
+
+    fn random_bytes() -> Vec<u8> {
+        (0..100_000).map(|_| rand::random::<u8>()).collect()
+    }
+
+    #[test]
+    fn compressed_contents_read_back_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let compressed_path = dir.path().join("file.zst");
+        let (path, compressed_path) = (path.to_str().unwrap(), compressed_path.to_str().unwrap());
+        let content = text();
+        std::fs::write(path, &content).unwrap();
+
+        let compressed_size = compress_file(path, compressed_path, Encoding::Zstd)
+            .unwrap()
+            .unwrap();
+        assert!(compressed_size < content.len() as u64);
+
+        // Read back whole and as stream, the hash of the blob refers to the uncompressed content
+        let hash = ContentHasher::hash_file(path).unwrap();
+        let blob_store: Arc<dyn BlobStoreFunc> = Arc::new(MemoryBlobStore::new());
+        blob_store
+            .put(&hash.hash, &std::fs::read(compressed_path).unwrap())
+            .unwrap();
+        assert_eq!(
+            read(blob_store.clone(), &hash.hash, Encoding::Zstd).unwrap(),
+            content
+        );
+        let (identified, stored) = identify_blob(blob_store, &hash.hash).unwrap();
+        assert_eq!(identified.hash, hash.hash);
+        assert_eq!(stored.encoding, Encoding::Zstd);
+        assert_eq!(stored.size, content.len() as u64);
+        assert_eq!(stored.stored_size, compressed_size);
+    }
+
+    #[test]
+    fn incompressible_contents_are_stored_as_they_are() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let compressed_path = dir.path().join("file.zst");
+        std::fs::write(&path, random_bytes()).unwrap();
+
+        let compressed = compress_file(
+            path.to_str().unwrap(),
+            compressed_path.to_str().unwrap(),
+            Encoding::Zstd,
+        )
+        .unwrap();
+        assert_eq!(compressed, None);
+        assert!(!compressed_path.exists());
+
+        let compressed = compress_file(
+            path.to_str().unwrap(),
+            compressed_path.to_str().unwrap(),
+            Encoding::Identity,
+        )
+        .unwrap();
+        assert_eq!(compressed, None);
+    }
+
+    #[test]
+    fn uploads_are_compressed_if_worthwhile() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "compression": "zstd" });
+        let app_state = open_app_state(&dir, options).unwrap();
+
+        let compressible = upload(&app_state, &text());
+        let incompressible = upload(&app_state, &random_bytes());
+
+        let file_store = app_state.file_store.read().unwrap();
+        let file_entry = file_store.get_file(&compressible.hash).unwrap().unwrap();
+        assert_eq!(file_entry.encoding, Encoding::Zstd);
+        assert!(file_entry.disk_size() < file_entry.size);
+        let content = read(
+            file_store.blob_store(),
+            &file_entry.hash,
+            file_entry.encoding,
+        );
+        assert_eq!(content.unwrap(), text());
+
+        let file_entry = file_store.get_file(&incompressible.hash).unwrap().unwrap();
+        assert_eq!(file_entry.encoding, Encoding::Identity);
+        assert_eq!(file_entry.disk_size(), file_entry.size);
+    }
+
+    #[test]
+    fn compressed_types_are_skipped() {
+        assert!(should_compress("text/plain; charset=utf-8"));
+        assert!(should_compress("application/json"));
+        assert!(should_compress("image/svg+xml"));
+        assert!(!should_compress("image/png"));
+        assert!(!should_compress("Video/MP4"));
+        assert!(!should_compress("application/zip"));
+    }
+}
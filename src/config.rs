@@ -6,6 +6,57 @@ pub struct ConfigFromFile {
     pub port: u16,
     pub manager_addr: String,
     pub ipv6: Option<String>,
+    pub max_upload_size: Option<u64>, // Maximum size of an upload in bytes, defaults to 10 GiB
+    pub advertise_legacy_hashes: Option<bool>, // Announce SHA-1 hashes next to SHA-256, defaults to true
+    pub scrub_interval: Option<u64>, // Seconds between two integrity checks of all files, defaults to one day
+    pub scrub_rate: Option<u64>,     // Bytes per second read while checking files, defaults to 10 MiB/s
+    pub metadata_store: Option<String>, // Where file metadata is kept, "json" (default) or "sqlite"
+    pub blob_store: Option<BlobStoreConfig>, // Where file contents are kept, defaults to the state dir
+    pub trash_retention: Option<u64>, // Seconds deleted files are kept in the trash, defaults to 7 days, 0 deletes immediately
+    pub eviction_policy: Option<String>, // Evicts recovered files to make room, "lru", "lfu" or "oldest_recovered", disabled by default
+    pub compression: Option<String>, // Compresses new files, "zstd" or "none" (default)
+    pub encryption: Option<EncryptionConfig>, // Encrypts the stored files, disabled by default
+    pub min_free_space: Option<u64>, // Bytes always left free on the disks which store the files, defaults to 1 GiB
+    pub upload_expiry: Option<u64>, // Seconds a resumable upload is kept without progress, defaults to one day
+    pub trust_local_requests: Option<bool>, // Requests from the same machine need no API token, defaults to false
+}
+
+// Backend which stores the content of the files
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BlobStoreConfig {
+    Local(LocalConfig), // Files in the state dir
+    Memory,             // In memory, lost on shutdown
+    S3(S3Config),       // S3 compatible object store
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LocalConfig {
+    pub shard_levels: Option<usize>, // Directory levels below state/files, like files/ab/cd/{hash} for 2, defaults to 0 (flat)
+    pub data_dirs: Option<Vec<DataDirConfig>>, // Directories on separate disks which store the files instead of the state dir
+    pub placement: Option<String>,   // Data dir of new files, "most_free" (default) or "round_robin"
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DataDirConfig {
+    pub path: String,  // Path of the directory, files are stored in {path}/files
+    pub capacity: u64, // Space in bytes the files in the directory may use
+}
+
+#[derive(Deserialize, Clone)]
+pub struct S3Config {
+    pub endpoint: String,       // Url of the object store, for example http://localhost:9000
+    pub bucket: String,         // Bucket which stores the files, has to exist
+    pub region: Option<String>, // Region of the bucket, defaults to us-east-1
+    pub access_key: String,
+    pub secret_key: String,
+    pub prefix: Option<String>, // Prepended to all keys, to share a bucket between nodes
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EncryptionConfig {
+    pub cipher: Option<String>,     // Cipher of new files, "aes-256-gcm" (default) or "chacha20-poly1305"
+    pub key_source: Option<String>, // "file" (default) stores the keys in the state dir, "passphrase" derives them from NODE_PASSPHRASE
 }
 
 // Read the config from a file for the given path
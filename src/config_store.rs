@@ -1,4 +1,7 @@
-use crypto::{digest::Digest, sha1::Sha1};
+use crate::blob_store::BlobStoreFunc;
+use crate::compression::{self, Encoding};
+use crate::config::ConfigFromFile;
+use crypto::{digest::Digest, sha1::Sha1, sha2::Sha256};
 use serde::{Deserialize, Serialize};
 
 /*
@@ -12,6 +15,99 @@ pub struct ConfigStore {
     port: u16,
     fingerprint: String,
     pub ipv6: Option<String>,
+    max_upload_size: u64,
+    advertise_legacy_hashes: bool,
+    trash_retention: u64,
+    upload_expiry: u64,
+    trust_local_requests: bool,
+    compression: Encoding,
+}
+
+/*
+ * Files are addressed by the SHA-256 hash of their content. During the transition from
+ * SHA-1 the legacy hash is computed alongside, so files stay resolvable by their old hash.
+ */
+#[derive(Clone, Debug)]
+pub struct ContentHash {
+    pub hash: String,        // SHA-256, used as primary key
+    pub legacy_hash: String, // SHA-1, used before the switch to SHA-256
+}
+
+impl ContentHash {
+    // Returns true if the given hash is either the SHA-256 or the SHA-1 hash
+    pub fn matches(&self, hash: &str) -> bool {
+        self.hash == hash || self.legacy_hash == hash
+    }
+}
+
+/*
+ * Computes the hash of a file incrementally, so uploads can be hashed chunk by chunk
+ * without holding the whole content in memory.
+ */
+pub struct ContentHasher {
+    sha256: Sha256,
+    sha1: Sha1,
+}
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        ContentHasher::new()
+    }
+}
+
+impl ContentHasher {
+    pub fn new() -> ContentHasher {
+        ContentHasher {
+            sha256: Sha256::new(),
+            sha1: Sha1::new(),
+        }
+    }
+
+    // Feed the next chunk of the content into the hasher
+    pub fn input(&mut self, chunk: &[u8]) {
+        self.sha256.input(chunk);
+        self.sha1.input(chunk);
+    }
+
+    // Returns the hashes of all chunks read so far
+    pub fn result(&mut self) -> ContentHash {
+        ContentHash {
+            hash: self.sha256.result_str(),
+            legacy_hash: self.sha1.result_str(),
+        }
+    }
+
+    // Hash the content stored under the given key, reading it chunk by chunk
+    pub fn hash_blob(blob_store: &dyn BlobStoreFunc, key: &str) -> std::io::Result<ContentHash> {
+        let size = blob_store.size(key)?;
+        let mut hasher = ContentHasher::new();
+        let mut start = 0;
+
+        while start < size {
+            let len = blob_store.chunk_size().min(size - start);
+            hasher.input(&blob_store.get_range(key, start, len)?);
+            start += len;
+        }
+
+        Ok(hasher.result())
+    }
+
+    // Hash a local file, reading it chunk by chunk
+    pub fn hash_file(path: &str) -> std::io::Result<ContentHash> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = ContentHasher::new();
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            let len = std::io::Read::read(&mut file, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.input(&buf[..len]);
+        }
+
+        Ok(hasher.result())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -28,37 +124,39 @@ impl PartialEq for Monitor {
 
 pub trait ConfigStoreFunc {
     fn new(
-        manager_addr: &str,         // Address of the manager
+        config: &ConfigFromFile,    // Manager address, port, fingerprint and options from config.json
         own_monitor: Monitor,       // Assigned monitor
         monitors: Vec<Monitor>,     // All monitors in network
-        port: u16,                  // Port the backend server should use
-        fingerprint: &str,          // Identifier for this node
-        ipv6: Option<String>,       // Optional, must be set when IPv6 is used
     ) -> ConfigStore;
     fn monitor(&self) -> Monitor;           // Return the assigned monitor
     fn monitors(&self) -> Vec<Monitor>;     // Returns all monitors
     fn manager(&self) -> String;            // Returns manager address
     fn port(&self) -> u16;                  // Returns port
     fn fingerprint(&self) -> String;        // Returns own fingerprint
-    fn hash_content(&mut self, content: &[u8]) -> String; // Returns the hash of a file content
+    fn max_upload_size(&self) -> u64;       // Returns maximum size of an upload
+    fn advertise_legacy_hashes(&self) -> bool; // Returns if SHA-1 hashes are send in the ping
+    fn trash_retention(&self) -> u64;       // Returns seconds a deleted file is kept in the trash
+    fn upload_expiry(&self) -> u64;         // Returns seconds a resumable upload is kept without progress
+    fn trust_local_requests(&self) -> bool; // Returns if requests from the same machine need no API token
+    fn compression(&self) -> Encoding;      // Returns the encoding new files are stored with
+    fn content_hasher(&self) -> ContentHasher; // Returns a hasher to hash a file content in chunks
 }
 
 impl ConfigStoreFunc for ConfigStore {
-    fn new(
-        manager_addr: &str,
-        own_monitor: Monitor,
-        monitors: Vec<Monitor>,
-        port: u16,
-        fingerprint: &str,
-        ipv6: Option<String>,
-    ) -> ConfigStore {
+    fn new(config: &ConfigFromFile, own_monitor: Monitor, monitors: Vec<Monitor>) -> ConfigStore {
         ConfigStore {
-            manager_addr: String::from(manager_addr),
+            manager_addr: String::from(&config.manager_addr),
             own_monitor,
             monitors,
-            port,
-            fingerprint: String::from(fingerprint),
-            ipv6,
+            port: config.port,
+            fingerprint: String::from(&config.fingerprint),
+            ipv6: config.ipv6.clone(),
+            max_upload_size: config.max_upload_size.unwrap_or(10 * 1024 * 1024 * 1024),
+            advertise_legacy_hashes: config.advertise_legacy_hashes.unwrap_or(true),
+            trash_retention: config.trash_retention.unwrap_or(7 * 24 * 60 * 60),
+            upload_expiry: config.upload_expiry.unwrap_or(24 * 60 * 60),
+            trust_local_requests: config.trust_local_requests.unwrap_or(false),
+            compression: compression::from_config(config.compression.as_deref()),
         }
     }
 
@@ -82,10 +180,31 @@ impl ConfigStoreFunc for ConfigStore {
         self.fingerprint.clone()
     }
 
-    fn hash_content(&mut self, content: &[u8]) -> String {
-        // TODO: Use Sha256 instead of Sha1
-        let mut hasher = Sha1::new();
-        hasher.input(content);
-        hasher.result_str()
+    fn max_upload_size(&self) -> u64 {
+        self.max_upload_size
+    }
+
+    fn advertise_legacy_hashes(&self) -> bool {
+        self.advertise_legacy_hashes
+    }
+
+    fn trash_retention(&self) -> u64 {
+        self.trash_retention
+    }
+
+    fn upload_expiry(&self) -> u64 {
+        self.upload_expiry
+    }
+
+    fn trust_local_requests(&self) -> bool {
+        self.trust_local_requests
+    }
+
+    fn compression(&self) -> Encoding {
+        self.compression
+    }
+
+    fn content_hasher(&self) -> ContentHasher {
+        ContentHasher::new()
     }
 }
@@ -6,7 +6,6 @@ use std::time::Duration;
 
 use crate::app_state::AppState;
 use crate::config_store::{ConfigStoreFunc, Monitor};
-use crate::file_store::FileStoreFunc;
 use crate::http_requests::{distribute_to_monitor, DistributionRequest};
 
 /*
@@ -37,8 +36,8 @@ impl DistributionService {
                 .unwrap()
                 .monitors()
                 .iter()
-                .cloned()
                 .filter(|m| m.addr != own_monitor.addr)
+                .cloned()
                 .collect();
 
             info!("Distribution Service started");
@@ -47,12 +46,19 @@ impl DistributionService {
 
             loop {
                 // Get next entry in queue
-                let hash_opt = self
+                let hash_opt = match self
                     .app_state
                     .file_store
                     .write()
                     .unwrap()
-                    .next_file_to_distribute();
+                    .next_file_to_distribute()
+                {
+                    Ok(hash_opt) => hash_opt,
+                    Err(err) => {
+                        error!("Could not read the distribution queue: {}", err);
+                        None
+                    }
+                };
 
                 // If a hash is found, distribute it, otherwise send t hread to sleep
                 if let Some(hash) = hash_opt {
@@ -64,7 +70,7 @@ impl DistributionService {
                     )
                     .await;
                 } else {
-                    std::thread::sleep(Duration::from_secs(self.timeout));
+                    tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
                 }
 
                 // If the flag is set, break out of the loop and exit thread
@@ -105,7 +111,7 @@ impl DistributionService {
 
         info!("Distributing {} to own monitor {}", hash, own_monitor.addr);
         if let Err(err) =
-            distribute_to_monitor(&hash, &own_monitor.addr, &own_distribution_request).await
+            distribute_to_monitor(hash, &own_monitor.addr, &own_distribution_request).await
         {
             error!("{}", err);
         }
@@ -120,7 +126,7 @@ impl DistributionService {
             };
 
             if let Err(err) =
-                distribute_to_monitor(&hash, &monitor.addr, &distribution_request).await
+                distribute_to_monitor(hash, &monitor.addr, &distribution_request).await
             {
                 error!("{}", err);
             }
@@ -146,7 +152,7 @@ impl DistributionService {
 
         info!("Distributing {} to own monitor {}", hash, own_monitor.addr);
         if let Err(err) =
-            distribute_to_monitor(&hash, &own_monitor.addr, &own_distribution_request).await
+            distribute_to_monitor(hash, &own_monitor.addr, &own_distribution_request).await
         {
             error!("{}", err);
         }
@@ -161,7 +167,7 @@ impl DistributionService {
             };
 
             if let Err(err) =
-                distribute_to_monitor(&hash, &monitor.addr, &distribution_request).await
+                distribute_to_monitor(hash, &monitor.addr, &distribution_request).await
             {
                 error!("{}", err);
             }
@@ -212,7 +218,7 @@ impl DistributionService {
                 };
 
                 if let Err(err) =
-                    distribute_to_monitor(&hash, &monitor.addr, &distribution_request).await
+                    distribute_to_monitor(hash, &monitor.addr, &distribution_request).await
                 {
                     error!("{}", err);
                 }
@@ -285,7 +291,7 @@ impl DistributionService {
             };
 
             if let Err(err) =
-                distribute_to_monitor(&hash, &monitor.addr, &distribution_request).await
+                distribute_to_monitor(hash, &monitor.addr, &distribution_request).await
             {
                 error!("{}", err);
             }
@@ -300,13 +306,13 @@ impl DistributionService {
     ) -> HashMap<String, Vec<Monitor>> {
         let mut monitor_map: HashMap<String, Vec<Monitor>> = HashMap::new();
         monitor_map
-            .entry(String::from(own_monitor.bound.get(0).unwrap()))
+            .entry(String::from(own_monitor.bound.first().unwrap()))
             .or_insert(vec![])
             .push(own_monitor.clone());
 
         // Group monitors by bounds
         for monitor in monitors {
-            let key = String::from(monitor.bound.get(0).unwrap());
+            let key = String::from(monitor.bound.first().unwrap());
             monitor_map
                 .entry(key)
                 .or_insert(vec![])
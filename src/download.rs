@@ -0,0 +1,872 @@
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use futures::stream::Stream;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::Arc;
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+
+use crate::blob_store::BlobStoreFunc;
+use crate::compression::{self, Encoding};
+
+/*
+ * Download
+ * Streams a stored file from the BlobStore as the body of a http response, so a file never has
+ * to be loaded into memory as a whole. Supports single and multiple byte ranges
+ * requested with the Range header (RFC 7233). Compressed files are decompressed on the fly,
+ * ranges always refer to the uncompressed content.
+ */
+
+const MAX_RANGES: usize = 32; // Requests with more ranges get the whole file
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64, // First byte of the range
+    pub end: u64,   // Last byte of the range (inclusive)
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Full,                    // No or an invalid Range header, send the whole file
+    Partial(Vec<ByteRange>), // Send only the given ranges
+    Unsatisfiable,           // None of the ranges lies inside the file
+}
+
+// Parts of a response body, either fixed bytes or a range of the file
+enum Segment {
+    Bytes(Vec<u8>),
+    File(ByteRange),
+}
+
+/* Parse the value of a Range header for a file with the given size.
+ * Headers that can not be parsed are ignored, as allowed by RFC 7233. Overlapping and adjacent
+ * ranges are merged and sorted, so no byte is sent twice.
+ *
+ * header: Value of the Range header, if the request contains one
+ * total: Size of the requested file
+ */
+pub fn parse_range(header: Option<&str>, total: u64) -> RangeRequest {
+    let specs = match header.and_then(|value| value.trim().strip_prefix("bytes=")) {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = vec![];
+    for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let (first, last) = match spec.find('-') {
+            Some(index) => (spec[..index].trim(), spec[index + 1..].trim()),
+            None => return RangeRequest::Full,
+        };
+
+        let range = if first.is_empty() {
+            // Suffix range: the last n bytes of the file
+            let suffix = match last.parse::<u64>() {
+                Ok(suffix) => suffix,
+                Err(_) => return RangeRequest::Full,
+            };
+            if suffix == 0 || total == 0 {
+                continue;
+            }
+            ByteRange {
+                start: total.saturating_sub(suffix),
+                end: total - 1,
+            }
+        } else {
+            let start = match first.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= total {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(total - 1),
+            }
+        };
+
+        ranges.push(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Partial(coalesce(ranges))
+}
+
+// Sorts the ranges by their start and merges those which overlap or touch
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+/* Returns true if the Accept-Encoding header of a request allows the given encoding.
+ * Wildcards are ignored, so only clients asking for the encoding explicitly get it.
+ */
+pub fn accepts_encoding(header: Option<&str>, encoding: &str) -> bool {
+    header.unwrap_or_default().split(',').any(|item| {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|quality| quality.trim().parse::<f32>().ok())
+                == Some(0.0)
+        });
+        name.eq_ignore_ascii_case(encoding) && !refused
+    })
+}
+
+/*
+ * Headers
+ * The content of a hash never changes, so downloads carry a strong ETag derived from the hash and
+ * may be cached forever. Conditional requests are evaluated as described in RFC 7232.
+ */
+
+pub const CACHE_CONTROL: &str = "public, max-age=31536000, immutable"; // One year, the maximum allowed
+
+// Conditional headers of a request
+#[derive(Default, Debug)]
+pub struct Preconditions {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub if_range: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    Passed,      // Answer the request as usual
+    NotModified, // The client has the file already, answer with 304
+    Failed,      // The client expects another file, answer with 412
+}
+
+impl Preconditions {
+    /* Evaluates If-Match, If-None-Match and If-Modified-Since in the order of RFC 7232
+     *
+     * etag: ETag of the response
+     * last_modified: Unix timestamp when the file was stored, if known
+     */
+    pub fn evaluate(&self, etag: &str, last_modified: Option<i64>) -> Precondition {
+        if let Some(if_match) = &self.if_match {
+            if !etag_matches(if_match, etag, false) {
+                return Precondition::Failed;
+            }
+        }
+
+        match (&self.if_none_match, &self.if_modified_since, last_modified) {
+            (Some(if_none_match), _, _) if etag_matches(if_none_match, etag, true) => {
+                Precondition::NotModified
+            }
+            (None, Some(since), Some(modified))
+                if parse_http_date(since).is_some_and(|since| modified <= since) =>
+            {
+                Precondition::NotModified
+            }
+            _ => Precondition::Passed,
+        }
+    }
+
+    // Returns false if If-Range names another version of the file, the Range header is ignored then
+    pub fn range_applies(&self, etag: &str, last_modified: Option<i64>) -> bool {
+        match self.if_range.as_deref().map(str::trim) {
+            None => true,
+            Some(if_range) if if_range.starts_with('"') => if_range == etag,
+            Some(if_range) => parse_http_date(if_range)
+                .zip(last_modified)
+                .is_some_and(|(date, modified)| modified <= date),
+        }
+    }
+}
+
+// Returns the ETag of a file, files sent compressed are another representation of the content
+pub fn etag(hash: &str, encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Identity => format!("\"{}\"", hash),
+        encoding => format!("\"{}-{}\"", hash, encoding.name()),
+    }
+}
+
+/* Returns true if the value of If-Match or If-None-Match names the ETag
+ *
+ * weak: Ignores the W/ prefix of weak ETags, as If-None-Match does
+ */
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag == etag || (weak && tag.strip_prefix("W/").is_some_and(|tag| tag == etag))
+    })
+}
+
+// Formats a unix timestamp as HTTP date, like Wed, 25 Jun 2014 16:00:00 GMT
+pub fn http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// Parses a HTTP date into a unix timestamp
+fn parse_http_date(date: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(date.trim(), "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|date| date.and_utc().timestamp())
+}
+
+/* Returns the Content-Disposition header of a download. File names which are not plain ASCII
+ * are sent encoded as filename* (RFC 5987, RFC 6266), with an ASCII fallback for old clients.
+ */
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' => c,
+            _ => '_',
+        })
+        .collect();
+    let quoted = fallback.replace('\\', "\\\\").replace('"', "\\\"");
+
+    if fallback == file_name {
+        format!("attachment; filename=\"{}\"", quoted)
+    } else {
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            quoted,
+            percent_encode(file_name)
+        )
+    }
+}
+
+/* Returns the file name of a Content-Disposition header like attachment; filename="data.csv".
+ * The extended form filename*=UTF-8''... of RFC 5987 is preferred, if both are given.
+ */
+pub fn disposition_file_name(header: &str) -> Option<String> {
+    let mut file_name = None;
+    for (name, value) in header_params(header) {
+        if name == "filename*" {
+            let decoded = value
+                .split_once('\'')
+                .and_then(|(charset, rest)| Some((charset, rest.split_once('\'')?.1)))
+                .filter(|(charset, _)| charset.eq_ignore_ascii_case("utf-8"))
+                .and_then(|(_, encoded)| percent_decode(encoded));
+            if decoded.is_some() {
+                return decoded;
+            }
+        } else if name == "filename" {
+            file_name = Some(value);
+        }
+    }
+    file_name.filter(|name| !name.is_empty())
+}
+
+// Splits the parameters of a header like attachment; filename="a;b.txt" into lowercase names and unquoted values
+fn header_params(header: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = header.chars().skip_while(|c| *c != ';').skip(1).peekable();
+
+    while chars.peek().is_some() {
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        let name = name.rsplit(';').next().unwrap_or_default(); // Skips parameters without value
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            // Quoted string, a backslash escapes the next character
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            chars.by_ref().take_while(|c| *c != ';').for_each(drop);
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+
+        params.push((name.trim().to_lowercase(), String::from(value.trim())));
+    }
+
+    params
+}
+
+// Encodes all bytes except the attr-chars of RFC 5987 as %XX
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (byte as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Decodes %XX escapes, returns None for invalid escapes or UTF-8
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Builds a response without body, like 304 Not Modified, which carries the given headers
+pub fn status_response(status: StatusCode, headers: Vec<(&str, String)>) -> Response<Body> {
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+/* Build a response which streams the file stored under the given key
+ *
+ * blob_store: BlobStore which holds the content of the file
+ * key: Key of the file in the BlobStore
+ * encoding: Encoding of the stored content, it is decompressed while streaming
+ * total: Size of the file, after decompression
+ * range_request: Parsed Range header of the request
+ * headers: Additional headers, like Content-Type and Content-Disposition
+ */
+pub fn file_response(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+    encoding: Encoding,
+    content_type: &str,
+    total: u64,
+    range_request: RangeRequest,
+    headers: Vec<(&str, String)>,
+) -> Response<Body> {
+    let mut builder = Response::builder().header("Accept-Ranges", "bytes");
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+
+    let (status, content_length, segments) = match range_request {
+        RangeRequest::Full => {
+            builder = builder.header("Content-Type", content_type);
+            let segments = if total > 0 {
+                vec![Segment::File(ByteRange {
+                    start: 0,
+                    end: total - 1,
+                })]
+            } else {
+                vec![]
+            };
+            (StatusCode::OK, total, segments)
+        }
+        RangeRequest::Unsatisfiable => {
+            builder = builder.header("Content-Range", format!("bytes */{}", total));
+            (StatusCode::RANGE_NOT_SATISFIABLE, 0, vec![])
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            builder = builder.header("Content-Type", content_type).header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", range.start, range.end, total),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                range.len(),
+                vec![Segment::File(range)],
+            )
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = format!("{:016x}", rand::random::<u64>());
+            builder = builder.header(
+                "Content-Type",
+                format!("multipart/byteranges; boundary={}", boundary),
+            );
+
+            // Every range is preceded by its own part header
+            let mut segments = vec![];
+            let mut length = 0;
+            for range in ranges {
+                let part_header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, range.start, range.end, total
+                );
+                length += part_header.len() as u64 + range.len();
+                segments.push(Segment::Bytes(part_header.into_bytes()));
+                segments.push(Segment::File(range));
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            length += closing.len() as u64;
+            segments.push(Segment::Bytes(closing.into_bytes()));
+
+            (StatusCode::PARTIAL_CONTENT, length, segments)
+        }
+    };
+
+    builder
+        .status(status)
+        .header("Content-Length", content_length)
+        .body(Body::wrap_stream(body_stream(
+            blob_store, key, encoding, segments,
+        )))
+        .unwrap()
+}
+
+// Stream of the response body, which reads the file chunk by chunk
+fn body_stream(
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: &str,
+    encoding: Encoding,
+    segments: Vec<Segment>,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> + Send + 'static {
+    let state = BodyState {
+        blob_store,
+        key: String::from(key),
+        encoding,
+        decoder: None,
+        segments: segments.into_iter().collect(),
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        match state.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), state)),
+            Ok(None) => None,
+            Err(err) => {
+                // End the stream after reporting the error
+                state.segments.clear();
+                Some((Err(err), state))
+            }
+        }
+    })
+}
+
+struct BodyState {
+    blob_store: Arc<dyn BlobStoreFunc>,
+    key: String,
+    encoding: Encoding,
+    decoder: Option<Decoder>, // Decompresses the content, kept between chunks of compressed files
+    segments: VecDeque<Segment>,
+}
+
+// Reader over the uncompressed content
+struct Decoder {
+    reader: Box<dyn Read + Send>,
+    offset: u64, // Position of the next uncompressed byte
+}
+
+impl BodyState {
+    async fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let range = match self.segments.pop_front() {
+            Some(Segment::Bytes(bytes)) => return Ok(Some(bytes)),
+            Some(Segment::File(range)) => range,
+            None => return Ok(None),
+        };
+
+        // Reading from the BlobStore blocks, so it must not run on the runtime itself
+        let len = range.len().min(self.blob_store.chunk_size());
+        let buf = match self.encoding {
+            Encoding::Identity => {
+                let blob_store = self.blob_store.clone();
+                let key = self.key.clone();
+                tokio::task::spawn_blocking(move || blob_store.get_range(&key, range.start, len))
+                    .await
+                    .map_err(std::io::Error::other)??
+            }
+            _ => self.read_decoded(range.start, len).await?,
+        };
+
+        // Put the rest of the range back in front of the queue
+        if len < range.len() {
+            self.segments.push_front(Segment::File(ByteRange {
+                start: range.start + len,
+                end: range.end,
+            }));
+        }
+
+        Ok(Some(buf))
+    }
+
+    /* Read len bytes of the uncompressed content, beginning at start. Compressed contents can
+     * only be read from the beginning, so the bytes before start are decompressed and skipped.
+     * The decoder is reopened for ranges before its position.
+     */
+    async fn read_decoded(&mut self, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let decoder = self.decoder.take().filter(|decoder| decoder.offset <= start);
+        let blob_store = self.blob_store.clone();
+        let key = self.key.clone();
+        let encoding = self.encoding;
+
+        let (decoder, buf) = tokio::task::spawn_blocking(move || {
+            let mut decoder = match decoder {
+                Some(decoder) => decoder,
+                None => Decoder {
+                    reader: compression::open(blob_store, &key, encoding)?,
+                    offset: 0,
+                },
+            };
+
+            let skip = start - decoder.offset;
+            std::io::copy(
+                &mut decoder.reader.by_ref().take(skip),
+                &mut std::io::sink(),
+            )?;
+            let mut buf = vec![];
+            decoder.reader.by_ref().take(len).read_to_end(&mut buf)?;
+            if (buf.len() as u64) < len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("content of {} is shorter than its size", key),
+                ));
+            }
+
+            decoder.offset = start + len;
+            Ok((decoder, buf))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        self.decoder = Some(decoder);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-99"), 1000), partial(&[(0, 99)]));
+        assert_eq!(
+            parse_range(Some("bytes=900-"), 1000),
+            partial(&[(900, 999)])
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-100"), 1000),
+            partial(&[(900, 999)])
+        );
+        assert_eq!(
+            parse_range(Some(" bytes= 10 - 20 "), 1000),
+            partial(&[(10, 20)])
+        );
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(
+            parse_range(Some("bytes=990-2000"), 1000),
+            partial(&[(990, 999)])
+        );
+        assert_eq!(parse_range(Some("bytes=-5000"), 1000), partial(&[(0, 999)]));
+    }
+
+    #[test]
+    fn rejects_ranges_outside_the_file() {
+        assert_eq!(
+            parse_range(Some("bytes=1000-"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=2000-3000"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-0"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=0-"), 0),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-10"), 0),
+            RangeRequest::Unsatisfiable
+        );
+
+        // Ranges outside are skipped as long as one lies inside
+        assert_eq!(
+            parse_range(Some("bytes=2000-3000,0-9"), 1000),
+            partial(&[(0, 9)])
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_headers() {
+        assert_eq!(parse_range(None, 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("items=0-9"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=9-0"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=a-9"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=5"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=0-9,x"), 1000), RangeRequest::Full);
+
+        let too_many = (0..=MAX_RANGES as u64)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect::<Vec<String>>()
+            .join(",");
+        assert_eq!(
+            parse_range(Some(&format!("bytes={}", too_many)), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn parses_multiple_ranges_sorted() {
+        assert_eq!(
+            parse_range(Some("bytes=500-599, 0-99, -100"), 1000),
+            partial(&[(0, 99), (500, 599), (900, 999)])
+        );
+    }
+
+    #[test]
+    fn coalesces_overlapping_ranges() {
+        let repeated = vec!["0-"; MAX_RANGES].join(",");
+        assert_eq!(
+            parse_range(Some(&format!("bytes={}", repeated)), 1000),
+            partial(&[(0, 999)])
+        );
+        assert_eq!(
+            parse_range(Some("bytes=0-99,50-149,150-199,300-399,-750"), 1000),
+            partial(&[(0, 199), (250, 999)])
+        );
+        assert_eq!(
+            parse_range(Some("bytes=10-19,0-9"), 1000),
+            partial(&[(0, 19)])
+        );
+    }
+
+    #[test]
+    fn file_names_round_trip() {
+        let names = [
+            "data.csv",
+            "say \"hi\".txt",
+            "a;b; filename=c.txt",
+            "back\\slash.txt",
+            "Grüße aus Köln.txt",
+            "\"ü\";x=1.txt",
+            "it's 100%.txt",
+            "日本語 ファイル.pdf",
+            "🎉.png",
+        ];
+        for name in &names {
+            let header = content_disposition(name);
+            assert!(header.is_ascii(), "{}", header);
+            assert_eq!(
+                disposition_file_name(&header).as_deref(),
+                Some(*name),
+                "{}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_only_non_ascii_names() {
+        assert_eq!(
+            content_disposition("data.csv"),
+            "attachment; filename=\"data.csv\""
+        );
+        assert_eq!(
+            content_disposition("ü \"x\".txt"),
+            "attachment; filename=\"_ \\\"x\\\".txt\"; filename*=UTF-8''%C3%BC%20%22x%22.txt"
+        );
+    }
+
+    #[test]
+    fn reads_file_names_of_other_servers() {
+        let cases = [
+            ("attachment; filename=data.csv", Some("data.csv")),
+            ("attachment; FileName=\"data.csv\"", Some("data.csv")),
+            ("inline; size=10; filename=\"a;b.csv\"", Some("a;b.csv")),
+            (
+                "attachment; filename*=utf-8'en'%E2%82%AC%20rates.csv; filename=\"EUR rates.csv\"",
+                Some("€ rates.csv"),
+            ),
+            (
+                "attachment; filename=\"EUR rates.csv\"; filename*=UTF-8''%E2%82%AC%20rates.csv",
+                Some("€ rates.csv"),
+            ),
+            // Unknown charsets and broken escapes fall back to the plain file name
+            (
+                "attachment; filename*=ISO-8859-1''%A3%20rates.csv; filename=\"rates.csv\"",
+                Some("rates.csv"),
+            ),
+            (
+                "attachment; filename*=UTF-8''%E2%82; filename=\"rates.csv\"",
+                Some("rates.csv"),
+            ),
+            ("attachment; filename=\"\"", None),
+            ("attachment", None),
+            ("", None),
+        ];
+        for (header, file_name) in &cases {
+            assert_eq!(
+                disposition_file_name(header).as_deref(),
+                *file_name,
+                "{}",
+                header
+            );
+        }
+    }
+
+    const ETAG: &str = "\"abc\"";
+    const MODIFIED: i64 = 1_403_712_000; // Wed, 25 Jun 2014 16:00:00 GMT
+
+    fn evaluate(
+        if_match: Option<&str>,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> Precondition {
+        let preconditions = Preconditions {
+            if_match: if_match.map(String::from),
+            if_none_match: if_none_match.map(String::from),
+            if_modified_since: if_modified_since.map(String::from),
+            if_range: None,
+        };
+        preconditions.evaluate(ETAG, Some(MODIFIED))
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(http_date(MODIFIED), "Wed, 25 Jun 2014 16:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(MODIFIED)), Some(MODIFIED));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        assert_eq!(evaluate(None, None, None), Precondition::Passed);
+        assert_eq!(evaluate(Some("\"abc\""), None, None), Precondition::Passed);
+        assert_eq!(
+            evaluate(Some("\"x\", \"abc\""), None, None),
+            Precondition::Passed
+        );
+        assert_eq!(evaluate(Some("*"), None, None), Precondition::Passed);
+        assert_eq!(evaluate(Some("\"x\""), None, None), Precondition::Failed);
+        assert_eq!(
+            evaluate(Some("W/\"abc\""), None, None),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert_eq!(
+            evaluate(None, Some("\"abc\""), None),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate(None, Some("W/\"abc\""), None),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate(None, Some("\"x\", \"abc\""), None),
+            Precondition::NotModified
+        );
+        assert_eq!(evaluate(None, Some("*"), None), Precondition::NotModified);
+        assert_eq!(evaluate(None, Some("\"x\""), None), Precondition::Passed);
+    }
+
+    #[test]
+    fn if_modified_since_compares_dates() {
+        let at = http_date(MODIFIED);
+        let before = http_date(MODIFIED - 1);
+        assert_eq!(evaluate(None, None, Some(&at)), Precondition::NotModified);
+        assert_eq!(evaluate(None, None, Some(&before)), Precondition::Passed);
+        assert_eq!(
+            evaluate(None, None, Some("yesterday")),
+            Precondition::Passed
+        );
+
+        let preconditions = Preconditions {
+            if_modified_since: Some(at),
+            ..Preconditions::default()
+        };
+        assert_eq!(preconditions.evaluate(ETAG, None), Precondition::Passed);
+    }
+
+    // RFC 7232 section 6: If-Match comes first, If-None-Match makes If-Modified-Since ignored
+    #[test]
+    fn preconditions_are_evaluated_in_order() {
+        let at = http_date(MODIFIED);
+        let before = http_date(MODIFIED - 1);
+        assert_eq!(
+            evaluate(Some("\"x\""), Some("\"abc\""), Some(&at)),
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluate(Some("\"abc\""), Some("\"abc\""), None),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluate(None, Some("\"x\""), Some(&at)),
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluate(None, Some("\"abc\""), Some(&before)),
+            Precondition::NotModified
+        );
+    }
+
+    #[test]
+    fn if_range_needs_the_same_version() {
+        let if_range = |value: Option<&str>| Preconditions {
+            if_range: value.map(String::from),
+            ..Preconditions::default()
+        };
+        let at = http_date(MODIFIED);
+        let before = http_date(MODIFIED - 1);
+
+        assert!(if_range(None).range_applies(ETAG, Some(MODIFIED)));
+        assert!(if_range(Some("\"abc\"")).range_applies(ETAG, Some(MODIFIED)));
+        assert!(!if_range(Some("\"x\"")).range_applies(ETAG, Some(MODIFIED)));
+        assert!(!if_range(Some("W/\"abc\"")).range_applies(ETAG, Some(MODIFIED)));
+        assert!(if_range(Some(&at)).range_applies(ETAG, Some(MODIFIED)));
+        assert!(!if_range(Some(&before)).range_applies(ETAG, Some(MODIFIED)));
+        assert!(!if_range(Some(&at)).range_applies(ETAG, None));
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::blob_store::BlobStoreFunc;
+use crate::config::EncryptionConfig;
+use crate::encryption::{self, Cipher, Key, Keyring, SALT_LEN, TAG_LEN};
+use crate::file_store;
+
+/*
+ * EncryptedBlobStore
+ * Wraps another BlobStore and encrypts all contents before they are written to it, so
+ * everything above only sees plain contents. Every content is split into segments, which are
+ * encrypted separately, so ranges can be read without decrypting the whole content.
+ *
+ * Layout of an encrypted content:
+ *   header: magic "PDENC1", cipher id, 0, key id (u32), salt (16 bytes), segment size (u32)
+ *   segments: up to 64 KiB of ciphertext followed by a 16 byte tag, at least one segment
+ *
+ * Every segment authenticates the header and a flag marking the last segment, so segments can
+ * neither be swapped between contents nor cut off. Contents stored before encryption was
+ * enabled are read as they are, until the KeyRotationService encrypts them.
+ */
+
+const MAGIC: &[u8; 6] = b"PDENC1"; // First bytes of every encrypted content
+const HEADER_LEN: u64 = 32; // Length of the header
+const SEGMENT_SIZE: u64 = 64 * 1024; // Plain bytes per segment
+const SEALED_SEGMENT_SIZE: u64 = SEGMENT_SIZE + TAG_LEN as u64; // Stored bytes per full segment
+
+pub struct EncryptedBlobStore {
+    inner: Arc<dyn BlobStoreFunc>, // Stores the encrypted contents
+    keyring: RwLock<Keyring>,      // Keys of all encrypted contents
+    cipher: Cipher,                // Cipher of newly written contents
+    path: String,                  // Path of the state dir, for temporary files
+    rotation_pending: AtomicBool,  // Set if contents may still be plain or use an old key
+}
+
+// Header of an encrypted content
+struct Header {
+    cipher: Cipher,
+    key_id: u32,
+    salt: [u8; SALT_LEN],
+    bytes: [u8; HEADER_LEN as usize], // The header as stored, authenticated by every segment
+}
+
+impl Header {
+    fn new(cipher: Cipher, key_id: u32) -> Header {
+        let mut salt = [0; SALT_LEN];
+        encryption::random_bytes(&mut salt);
+
+        let mut bytes = [0; HEADER_LEN as usize];
+        bytes[..6].copy_from_slice(MAGIC);
+        bytes[6] = cipher.id();
+        bytes[8..12].copy_from_slice(&key_id.to_be_bytes());
+        bytes[12..28].copy_from_slice(&salt);
+        bytes[28..32].copy_from_slice(&(SEGMENT_SIZE as u32).to_be_bytes());
+
+        Header {
+            cipher,
+            key_id,
+            salt,
+            bytes,
+        }
+    }
+
+    // Returns None if the bytes are not the header of an encrypted content
+    fn parse(bytes: &[u8]) -> Option<Header> {
+        if bytes.len() != HEADER_LEN as usize || &bytes[..6] != MAGIC {
+            return None;
+        }
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[28..32]);
+        if u32::from_be_bytes(word) as u64 != SEGMENT_SIZE {
+            return None;
+        }
+
+        word.copy_from_slice(&bytes[8..12]);
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&bytes[12..28]);
+        let mut header_bytes = [0; HEADER_LEN as usize];
+        header_bytes.copy_from_slice(bytes);
+
+        Some(Header {
+            cipher: Cipher::from_id(bytes[6])?,
+            key_id: u32::from_be_bytes(word),
+            salt,
+            bytes: header_bytes,
+        })
+    }
+
+    // Additional data of a segment, the header and whether it is the last one
+    fn aad(&self, last: bool) -> Vec<u8> {
+        let mut aad = self.bytes.to_vec();
+        aad.push(last as u8);
+        aad
+    }
+}
+
+// Number of segments of an encrypted content, given the size of its stored segments
+fn segment_count(sealed_size: u64) -> u64 {
+    sealed_size.div_ceil(SEALED_SEGMENT_SIZE).max(1)
+}
+
+impl EncryptedBlobStore {
+    // Opens the keyring in the state dir, panics if the keys can not be read
+    pub fn new(
+        inner: Arc<dyn BlobStoreFunc>,
+        config: &EncryptionConfig,
+        path: &str,
+    ) -> EncryptedBlobStore {
+        let use_passphrase = match config.key_source.as_deref() {
+            None | Some("file") => false,
+            Some("passphrase") => true,
+            Some(other) => panic!("Unknown key_source {}", other),
+        };
+        let keyring = Keyring::open(path, use_passphrase)
+            .unwrap_or_else(|err| panic!("Could not open encryption keys: {}", err));
+
+        EncryptedBlobStore {
+            inner,
+            keyring: RwLock::new(keyring),
+            cipher: Cipher::from_config(config.cipher.as_deref()),
+            path: String::from(path),
+            rotation_pending: AtomicBool::new(true),
+        }
+    }
+
+    // Adds a new key for all new contents, existing contents are re-encrypted in the background
+    pub fn rotate_key(&self) -> std::io::Result<u32> {
+        let key_id = self.keyring.write().unwrap().rotate()?;
+        self.rotation_pending.store(true, Ordering::Relaxed);
+        Ok(key_id)
+    }
+
+    // Returns the id of the key which encrypts new contents
+    pub fn current_key_id(&self) -> u32 {
+        self.keyring.read().unwrap().current().0
+    }
+
+    // Returns true once after a rotation, when contents have to be checked for old keys
+    pub fn take_rotation_pending(&self) -> bool {
+        self.rotation_pending.swap(false, Ordering::Relaxed)
+    }
+
+    // Makes the KeyRotationService check the contents again, after some could not be re-encrypted
+    pub fn retry_rotation(&self) {
+        self.rotation_pending.store(true, Ordering::Relaxed);
+    }
+
+    // Returns true if the content is plain or not encrypted with the current key and cipher
+    pub fn needs_reencryption(&self, key: &str) -> std::io::Result<bool> {
+        Ok(match self.header(key)? {
+            Some((header, _)) => {
+                header.key_id != self.current_key_id() || header.cipher != self.cipher
+            }
+            None => true,
+        })
+    }
+
+    // Encrypts the content again with the current key, through a temporary file
+    pub fn reencrypt(&self, key: &str) -> std::io::Result<()> {
+        let temp_path = file_store::new_temp_file_path(&self.path)?;
+        let result = (|| {
+            let size = self.size(key)?;
+            let mut file = BufWriter::new(File::create(&temp_path)?);
+            let mut start = 0;
+            while start < size {
+                let len = self.chunk_size().min(size - start);
+                file.write_all(&self.get_range(key, start, len)?)?;
+                start += len;
+            }
+            file.into_inner()?.sync_all()?;
+            self.put_file(key, &temp_path)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /* Reads the header of the content stored under the given key and the size of its segments.
+     * Returns None for plain contents.
+     */
+    fn header(&self, key: &str) -> std::io::Result<Option<(Header, u64)>> {
+        let stored_size = self.inner.size(key)?;
+        if stored_size < HEADER_LEN + TAG_LEN as u64 {
+            return Ok(None);
+        }
+
+        let header = Header::parse(&self.inner.get_range(key, 0, HEADER_LEN)?);
+        Ok(header.map(|header| (header, stored_size - HEADER_LEN)))
+    }
+
+    // Returns the key of the content with the given header
+    fn content_key(&self, header: &Header) -> std::io::Result<Key> {
+        let key = self
+            .keyring
+            .read()
+            .unwrap()
+            .key(header.key_id)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("encryption key {} is missing", header.key_id),
+                )
+            })?;
+        Ok(encryption::content_key(&key, &header.salt))
+    }
+
+    // Encrypts a whole content, reading it segment by segment
+    fn encrypt<R: Read, W: Write>(&self, mut reader: R, mut writer: W) -> std::io::Result<()> {
+        let (key_id, key) = self.keyring.read().unwrap().current();
+        let header = Header::new(self.cipher, key_id);
+        let key = encryption::content_key(&key, &header.salt);
+        writer.write_all(&header.bytes)?;
+
+        // Read one segment ahead, to know which one is the last
+        let mut segment = read_segment(&mut reader)?;
+        let mut index = 0;
+        loop {
+            let next = read_segment(&mut reader)?;
+            let last = next.is_empty();
+            writer.write_all(&self.cipher.seal(&key, index, &header.aad(last), &segment))?;
+            if last {
+                return writer.flush();
+            }
+            segment = next;
+            index += 1;
+        }
+    }
+}
+
+// Reads up to one segment, returns an empty buffer at the end of the content
+fn read_segment<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut segment = vec![];
+    reader.take(SEGMENT_SIZE).read_to_end(&mut segment)?;
+    Ok(segment)
+}
+
+impl BlobStoreFunc for EncryptedBlobStore {
+    fn put(&self, key: &str, content: &[u8]) -> std::io::Result<()> {
+        let mut encrypted = vec![];
+        self.encrypt(content, &mut encrypted)?;
+        self.inner.put(key, &encrypted)
+    }
+
+    fn put_file(&self, key: &str, temp_path: &str) -> std::io::Result<()> {
+        let encrypted_path = format!("{}.enc", temp_path);
+        let result = File::open(temp_path)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(File::create(&encrypted_path)?);
+                self.encrypt(file, &mut writer)?;
+                writer.into_inner()?.sync_all()
+            })
+            .and_then(|_| self.inner.put_file(key, &encrypted_path));
+
+        match result {
+            Ok(_) => std::fs::remove_file(temp_path),
+            Err(err) => {
+                let _ = std::fs::remove_file(&encrypted_path);
+                Err(err)
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let size = self.size(key)?;
+        self.get_range(key, 0, size)
+    }
+
+    fn get_range(&self, key: &str, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let (header, sealed_size) = match self.header(key)? {
+            Some(header) => header,
+            None => return self.inner.get_range(key, start, len),
+        };
+
+        let segments = segment_count(sealed_size);
+        let size = sealed_size.saturating_sub(segments * TAG_LEN as u64);
+        let end = start.saturating_add(len).min(size);
+        if start >= end {
+            return Ok(vec![]);
+        }
+
+        // Read all segments covering the range at once
+        let first = start / SEGMENT_SIZE;
+        let last = (end - 1) / SEGMENT_SIZE;
+        let offset = first * SEALED_SEGMENT_SIZE;
+        let sealed_len = ((last + 1) * SEALED_SEGMENT_SIZE).min(sealed_size) - offset;
+        let sealed = self.inner.get_range(key, HEADER_LEN + offset, sealed_len)?;
+
+        let content_key = self.content_key(&header)?;
+        let mut plain = Vec::with_capacity(sealed.len());
+        for (i, segment) in sealed.chunks(SEALED_SEGMENT_SIZE as usize).enumerate() {
+            let index = first + i as u64;
+            let aad = header.aad(index == segments - 1);
+            plain.extend(header.cipher.open(&content_key, index, &aad, segment)?);
+        }
+
+        let skip = (start - first * SEGMENT_SIZE) as usize;
+        plain.truncate(skip + (end - start) as usize);
+        plain.drain(..skip);
+        Ok(plain)
+    }
+
+    fn size(&self, key: &str) -> std::io::Result<u64> {
+        match self.header(key)? {
+            Some((_, sealed_size)) => {
+                Ok(sealed_size.saturating_sub(segment_count(sealed_size) * TAG_LEN as u64))
+            }
+            None => self.inner.size(key),
+        }
+    }
+
+    fn remove(&self, key: &str) -> std::io::Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn quarantine(&self, key: &str) -> std::io::Result<()> {
+        self.inner.quarantine(key)
+    }
+
+    fn trash(&self, key: &str) -> std::io::Result<()> {
+        self.inner.trash(key)
+    }
+
+    fn restore(&self, key: &str) -> std::io::Result<()> {
+        self.inner.restore(key)
+    }
+
+    fn purge(&self, key: &str) -> std::io::Result<()> {
+        self.inner.purge(key)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn relocate(&self, key: &str) -> std::io::Result<bool> {
+        self.inner.relocate(key)
+    }
+
+    fn keys(&self) -> std::io::Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        self.inner.capacity()
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        self.inner.free_space()
+    }
+
+    // Whole segments, so no segment has to be decrypted twice
+    fn chunk_size(&self) -> u64 {
+        (self.inner.chunk_size() / SEGMENT_SIZE).max(1) * SEGMENT_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryBlobStore;
+
+    // Three segments, the last one partly filled
+    const SIZE: usize = 2 * SEGMENT_SIZE as usize + 1000;
+
+    fn store(cipher: &str, dir: &tempfile::TempDir) -> (EncryptedBlobStore, Arc<MemoryBlobStore>) {
+        let inner = Arc::new(MemoryBlobStore::new());
+        let config = EncryptionConfig {
+            cipher: Some(String::from(cipher)),
+            key_source: None,
+        };
+        let store = EncryptedBlobStore::new(inner.clone(), &config, dir.path().to_str().unwrap());
+        (store, inner)
+    }
+
+    fn content() -> Vec<u8> {
+        (0..SIZE).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Range of the given stored segment, including its tag
+    fn sealed_segment(index: u64) -> std::ops::Range<usize> {
+        let start = (HEADER_LEN + index * SEALED_SEGMENT_SIZE) as usize;
+        start..start + SEALED_SEGMENT_SIZE as usize
+    }
+
+    fn assert_invalid(store: &EncryptedBlobStore, key: &str) {
+        let err = store.get(key).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        for cipher in &["aes-256-gcm", "chacha20-poly1305"] {
+            let (store, inner) = store(cipher, &dir);
+            let content = content();
+            store.put("key", &content).unwrap();
+
+            assert_ne!(
+                inner.get("key").unwrap()[HEADER_LEN as usize..][..100],
+                content[..100]
+            );
+            assert_eq!(store.size("key").unwrap(), SIZE as u64);
+            assert_eq!(store.get("key").unwrap(), content);
+            assert_eq!(
+                store.get_range("key", SEGMENT_SIZE - 10, 20).unwrap(),
+                content[SEGMENT_SIZE as usize - 10..][..20]
+            );
+            assert!(!store.needs_reencryption("key").unwrap());
+
+            store.put("empty", b"").unwrap();
+            assert_eq!(store.get("empty").unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = store("aes-256-gcm", &dir);
+        store.put("key", &content()).unwrap();
+
+        let mut stored = inner.get("key").unwrap();
+        stored[sealed_segment(1).start + 5] ^= 1;
+        inner.put("key", &stored).unwrap();
+
+        assert_invalid(&store, "key");
+        assert!(store.get_range("key", 0, 100).is_ok());
+    }
+
+    #[test]
+    fn tampered_header_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = store("chacha20-poly1305", &dir);
+        store.put("key", &content()).unwrap();
+
+        // The salt changes the key of the content, the byte after the cipher id only the aad
+        for position in &[7, 20] {
+            let mut stored = inner.get("key").unwrap();
+            stored[*position] ^= 1;
+            inner.put("tampered", &stored).unwrap();
+            assert_invalid(&store, "tampered");
+        }
+    }
+
+    #[test]
+    fn truncated_content_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = store("aes-256-gcm", &dir);
+        store.put("key", &content()).unwrap();
+
+        // Without the last segment, the one before it is not marked as last
+        let stored = inner.get("key").unwrap();
+        inner.put("key", &stored[..sealed_segment(1).end]).unwrap();
+        assert_eq!(store.size("key").unwrap(), 2 * SEGMENT_SIZE);
+        assert_invalid(&store, "key");
+    }
+
+    #[test]
+    fn reordered_segments_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = store("chacha20-poly1305", &dir);
+        store.put("key", &content()).unwrap();
+
+        let stored = inner.get("key").unwrap();
+        let mut reordered = stored[..HEADER_LEN as usize].to_vec();
+        reordered.extend_from_slice(&stored[sealed_segment(1)]);
+        reordered.extend_from_slice(&stored[sealed_segment(0)]);
+        reordered.extend_from_slice(&stored[sealed_segment(2).start..]);
+        inner.put("key", &reordered).unwrap();
+
+        assert_invalid(&store, "key");
+    }
+}
@@ -0,0 +1,480 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::Sha256;
+use log::info;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use crate::state_file;
+
+/*
+ * Encryption
+ * Keys and ciphers used to encrypt the contents of all files at rest, see EncryptedBlobStore.
+ * The keys are kept in {path}/encryption_keys.json. By default the file holds the keys
+ * themselves, otherwise only random salts, and the keys are derived from a passphrase given in
+ * the environment variable NODE_PASSPHRASE. A rotation adds a new key, which encrypts all new
+ * contents. Old keys are kept, so contents encrypted with them stay readable.
+ *
+ * Every content gets its own key, derived from the key in the keyring and a random salt with
+ * HMAC-SHA256, so the number of a segment can serve as its nonce.
+ */
+
+pub const KEY_LEN: usize = 32; // Length of all keys, AES-256 and ChaCha20 use 256 bit keys
+pub const SALT_LEN: usize = 16; // Length of the random salt of every content
+pub const TAG_LEN: usize = 16; // Length of the authentication tag of every segment
+
+const PASSPHRASE_VAR: &str = "NODE_PASSPHRASE"; // Environment variable which holds the passphrase
+const KEY_CHECK: &[u8] = b"peerdata key check"; // Authenticated to tell a wrong passphrase apart
+
+pub type Key = [u8; KEY_LEN];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305, // As in RFC 8439
+}
+
+impl Cipher {
+    // Returns the cipher for new contents configured in config.json, AES-256-GCM by default
+    pub fn from_config(cipher: Option<&str>) -> Cipher {
+        match cipher {
+            None | Some("aes-256-gcm") => Cipher::Aes256Gcm,
+            Some("chacha20-poly1305") => Cipher::ChaCha20Poly1305,
+            Some(other) => panic!("Unknown cipher {}", other),
+        }
+    }
+
+    // Id of the cipher in the header of an encrypted content
+    pub fn id(&self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            3 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /* Encrypts one segment of a content, returns the ciphertext followed by the tag
+     *
+     * key: Key of the content
+     * index: Number of the segment, used as nonce
+     * aad: Additional data which is authenticated, but not encrypted
+     */
+    pub fn seal(&self, key: &Key, index: u64, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+        self.seal_nonce(key, &nonce(index), aad, plain)
+    }
+
+    // Decrypts a segment encrypted with seal, fails with InvalidData if it was altered
+    pub fn open(
+        &self,
+        key: &Key,
+        index: u64,
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        if sealed.len() < TAG_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated segment"));
+        }
+
+        self.open_nonce(key, &nonce(index), aad, sealed)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "segment could not be authenticated"))
+    }
+
+    fn seal_nonce(&self, key: &Key, nonce: &[u8], aad: &[u8], plain: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: plain, aad };
+        let sealed = match self {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), payload)
+            }
+        };
+        // Only fails for segments of many gigabytes
+        sealed.expect("segment too large to encrypt")
+    }
+
+    // Returns None if the segment could not be authenticated
+    fn open_nonce(&self, key: &Key, nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload { msg: sealed, aad };
+        match self {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key.into())
+                .decrypt(nonce.into(), payload)
+                .ok(),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into())
+                .decrypt(nonce.into(), payload)
+                .ok(),
+        }
+    }
+}
+
+// The segment number fills the lower 64 bits of the 96 bit nonce
+fn nonce(index: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+// Derives the key of a single content from a key of the keyring
+pub fn content_key(key: &Key, salt: &[u8]) -> Key {
+    hmac(key, salt)
+}
+
+// Fills a buffer with random bytes of the operating system
+pub fn random_bytes(buf: &mut [u8]) {
+    rand::rngs::OsRng.fill_bytes(buf);
+}
+
+fn hmac(key: &[u8], input: &[u8]) -> Key {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(input);
+    let mut output = [0; KEY_LEN];
+    output.copy_from_slice(mac.result().code());
+    output
+}
+
+/*
+ * Keyring
+ * All keys which were used to encrypt contents, the newest one encrypts new contents.
+ */
+
+#[derive(Deserialize, Serialize, Clone)]
+struct KeyEntry {
+    id: u32,
+    created_at: i64,      // Unix timestamp of the rotation which added the key
+    key: Option<String>,  // Hex encoded key, if it is stored in the file
+    salt: Option<String>, // Hex encoded salt, if the key is derived from the passphrase
+    check: String,        // Hex encoded HMAC of a constant, to detect a wrong passphrase
+}
+
+// Content of encryption_keys.json
+#[derive(Deserialize, Serialize)]
+struct KeyringFile {
+    current: u32, // Id of the key which encrypts new contents
+    keys: Vec<KeyEntry>,
+}
+
+pub struct Keyring {
+    path: String,               // Path of encryption_keys.json
+    passphrase: Option<String>, // Passphrase to derive the keys from, None if they are stored
+    current: u32,               // Id of the key which encrypts new contents
+    entries: Vec<KeyEntry>,     // Keys as stored in the file
+    keys: HashMap<u32, Key>,    // Keys by their id
+}
+
+impl Keyring {
+    /* Reads the keys from the state dir, or creates the first key if there are none yet.
+     * Fails if the passphrase is missing or does not match the keys.
+     *
+     * path: Path of the state dir
+     * use_passphrase: Derive the keys from the passphrase instead of storing them
+     */
+    pub fn open(path: &str, use_passphrase: bool) -> std::io::Result<Keyring> {
+        let passphrase = if use_passphrase {
+            match std::env::var(PASSPHRASE_VAR) {
+                Ok(passphrase) if !passphrase.is_empty() => Some(passphrase),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} is not set", PASSPHRASE_VAR),
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut keyring = Keyring {
+            path: format!("{}/encryption_keys.json", path),
+            passphrase,
+            current: 0,
+            entries: vec![],
+            keys: HashMap::new(),
+        };
+
+        let content = match std::fs::read_to_string(&keyring.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                keyring.rotate()?;
+                return Ok(keyring);
+            }
+            Err(err) => return Err(err),
+        };
+
+        let file: KeyringFile = serde_json::from_str(&content)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        for entry in &file.keys {
+            let key = keyring.entry_key(entry)?;
+            keyring.keys.insert(entry.id, key);
+        }
+        if !keyring.keys.contains_key(&file.current) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("current key {} is missing", file.current),
+            ));
+        }
+        keyring.current = file.current;
+        keyring.entries = file.keys;
+
+        Ok(keyring)
+    }
+
+    // Returns the id and the key which encrypt new contents
+    pub fn current(&self) -> (u32, Key) {
+        (self.current, self.keys[&self.current])
+    }
+
+    // Returns the key with the given id
+    pub fn key(&self, id: u32) -> Option<Key> {
+        self.keys.get(&id).copied()
+    }
+
+    // Adds a new key, which encrypts all new contents from now on. Returns its id.
+    pub fn rotate(&mut self) -> std::io::Result<u32> {
+        let id = self.entries.iter().map(|entry| entry.id).max().unwrap_or(0) + 1;
+
+        let mut secret = [0; KEY_LEN];
+        let mut entry = KeyEntry {
+            id,
+            created_at: chrono::Utc::now().timestamp(),
+            key: None,
+            salt: None,
+            check: String::new(),
+        };
+        match &self.passphrase {
+            Some(_) => {
+                let mut salt = [0; SALT_LEN];
+                random_bytes(&mut salt);
+                entry.salt = Some(to_hex(&salt));
+            }
+            None => {
+                random_bytes(&mut secret);
+                entry.key = Some(to_hex(&secret));
+            }
+        }
+        let key = self.derive(&entry)?;
+        entry.check = to_hex(&hmac(&key, KEY_CHECK));
+
+        let mut entries = self.entries.clone();
+        entries.push(entry);
+        self.save(id, &entries)?;
+
+        self.entries = entries;
+        self.keys.insert(id, key);
+        self.current = id;
+        info!("Created encryption key {}", id);
+        Ok(id)
+    }
+
+    // Returns the key of an entry and checks it against the stored check value
+    fn entry_key(&self, entry: &KeyEntry) -> std::io::Result<Key> {
+        let key = self.derive(entry)?;
+        if to_hex(&hmac(&key, KEY_CHECK)) != entry.check {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("key {} does not match, wrong passphrase?", entry.id),
+            ));
+        }
+        Ok(key)
+    }
+
+    // Reads the stored key of an entry, or derives it from the passphrase
+    fn derive(&self, entry: &KeyEntry) -> std::io::Result<Key> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("key {} is malformed", entry.id),
+            )
+        };
+
+        let mut key = [0; KEY_LEN];
+        match (&entry.key, &entry.salt, &self.passphrase) {
+            (Some(stored), _, _) => {
+                let stored = from_hex(stored).filter(|stored| stored.len() == KEY_LEN);
+                key.copy_from_slice(&stored.ok_or_else(invalid)?);
+            }
+            (None, Some(salt), Some(passphrase)) => {
+                let salt = from_hex(salt).ok_or_else(invalid)?;
+                scrypt(
+                    passphrase.as_bytes(),
+                    &salt,
+                    &ScryptParams::new(15, 8, 1),
+                    &mut key,
+                );
+            }
+            (None, Some(_), None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("key {} is derived from a passphrase", entry.id),
+                ))
+            }
+            (None, None, _) => return Err(invalid()),
+        }
+        Ok(key)
+    }
+
+    // Writes the keys to disk, only readable by the owner
+    fn save(&self, current: u32, entries: &[KeyEntry]) -> std::io::Result<()> {
+        let file = KeyringFile {
+            current,
+            keys: entries.to_vec(),
+        };
+        let serialized = serde_json::to_string_pretty(&file).unwrap();
+        state_file::write_atomic_private(&self.path, serialized.as_bytes())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIPHERS: [Cipher; 2] = [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305];
+
+    fn hex(hex: &str) -> Vec<u8> {
+        from_hex(hex).unwrap()
+    }
+
+    fn key(hex_key: &str) -> Key {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&hex(hex_key));
+        key
+    }
+
+    // NIST CAVP gcmEncryptExtIV256.rsp, a vector with AAD and plaintext
+    #[test]
+    fn aes_256_gcm_known_answer() {
+        let key = key("92e11dcdaa866f5ce790fd24501f92509aacf4cb8b1339d50c9c1240935dd08b");
+        let nonce = hex("ac93a1a6145299bde902f21a");
+        let aad = hex("1e0889016f67601c8ebea4943bc23ad6");
+        let plain = hex("2d71bcfa914e4ac045b2aa60955fad24");
+        let sealed = hex("8995ae2e6df3dbf96fac7b7137bae67feca5aa77d51d4a0a14d9c51e1da474ab");
+
+        let cipher = Cipher::Aes256Gcm;
+        assert_eq!(cipher.seal_nonce(&key, &nonce, &aad, &plain), sealed);
+        assert_eq!(cipher.open_nonce(&key, &nonce, &aad, &sealed), Some(plain));
+    }
+
+    // RFC 8439 section 2.8.2
+    #[test]
+    fn chacha20_poly1305_known_answer() {
+        let key = key("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f");
+        let nonce = hex("070000004041424344454647");
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plain = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+            tip for the future, sunscreen would be it.";
+        let sealed = hex(concat!(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6",
+            "3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36",
+            "92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+            "3ff4def08e4b7a9de576d26586cec64b61161ae10b594f09e26a7e902ecbd060",
+            "0691",
+        ));
+
+        let cipher = Cipher::ChaCha20Poly1305;
+        assert_eq!(cipher.seal_nonce(&key, &nonce, &aad, plain), sealed);
+        assert_eq!(
+            cipher.open_nonce(&key, &nonce, &aad, &sealed),
+            Some(plain.to_vec())
+        );
+    }
+
+    #[test]
+    fn segment_number_fills_lower_nonce_bits() {
+        let key = [7; KEY_LEN];
+        let nonce = hex("000000000102030405060708");
+        for cipher in &CIPHERS {
+            assert_eq!(
+                cipher.seal(&key, 0x0102030405060708, b"aad", b"plain"),
+                cipher.seal_nonce(&key, &nonce, b"aad", b"plain")
+            );
+        }
+    }
+
+    #[test]
+    fn open_rejects_altered_segments() {
+        let key = [7; KEY_LEN];
+        for cipher in &CIPHERS {
+            let sealed = cipher.seal(&key, 1, b"aad", b"some plain content");
+            assert_eq!(
+                cipher.open(&key, 1, b"aad", &sealed).unwrap(),
+                b"some plain content"
+            );
+
+            let mut altered = sealed.clone();
+            altered[0] ^= 1;
+            assert!(cipher.open(&key, 1, b"aad", &altered).is_err());
+            let mut altered = sealed.clone();
+            *altered.last_mut().unwrap() ^= 1;
+            assert!(cipher.open(&key, 1, b"aad", &altered).is_err());
+
+            assert!(cipher.open(&key, 2, b"aad", &sealed).is_err());
+            assert!(cipher.open(&key, 1, b"aae", &sealed).is_err());
+            assert!(cipher.open(&[8; KEY_LEN], 1, b"aad", &sealed).is_err());
+            assert!(cipher
+                .open(&key, 1, b"aad", &sealed[..TAG_LEN - 1])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn cipher_ids_round_trip() {
+        for cipher in &CIPHERS {
+            assert_eq!(Cipher::from_id(cipher.id()), Some(*cipher));
+        }
+        assert_eq!(Cipher::from_id(0), None);
+        assert_eq!(Cipher::from_id(2), None);
+        assert_eq!(
+            Cipher::from_config(Some("chacha20-poly1305")),
+            Cipher::ChaCha20Poly1305
+        );
+    }
+
+    #[test]
+    fn keyring_is_kept_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        // A temporary file left by a crash must not pass on its permissions
+        let keys_path = dir.path().join("encryption_keys.json");
+        std::fs::write(dir.path().join("encryption_keys.json.tmp"), "").unwrap();
+
+        let mut keyring = Keyring::open(path, false).unwrap();
+        let (first, first_key) = keyring.current();
+        let second = keyring.rotate().unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&keys_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!dir.path().join("encryption_keys.json.tmp").exists());
+
+        let keyring = Keyring::open(path, false).unwrap();
+        assert_eq!(keyring.current().0, second);
+        assert_eq!(keyring.key(first), Some(first_key));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::file_store::{FileEntry, FileSource};
+
+/*
+ * Eviction
+ * Decides which files make room for a recovery, when the capacity of the node is exhausted.
+ * Only replicas recovered from other nodes are evicted. Uploaded files and files of unknown
+ * origin may be the only copy in the network, pinned and retained files are kept by request
+ * of the operator. Evicted files are announced to the monitor with the next ping, so it can
+ * place the replica on another node.
+ */
+
+pub trait EvictionPolicyFunc: Send + Sync {
+    fn name(&self) -> &str;                        // Name of the policy in config.json
+    fn order(&self, candidates: &mut [FileEntry]); // Sorts the candidates, the first one is evicted first
+}
+
+// Creates the eviction policy configured in config.json, eviction is disabled by default
+pub fn from_config(name: Option<&str>) -> Option<Box<dyn EvictionPolicyFunc>> {
+    match name {
+        None | Some("none") => None,
+        Some("lru") => Some(Box::new(LeastRecentlyUsed)),
+        Some("lfu") => Some(Box::new(LeastFrequentlyUsed)),
+        Some("oldest_recovered") => Some(Box::new(OldestRecoveredFirst)),
+        Some(other) => panic!("Unknown eviction_policy {}", other),
+    }
+}
+
+// Returns true if the file may be evicted to make room for another one
+pub fn is_evictable(file_entry: &FileEntry) -> bool {
+    matches!(file_entry.source, FileSource::Recovered { .. }) && !file_entry.is_held()
+}
+
+// Files never downloaded count as used when they were stored
+fn last_used(file_entry: &FileEntry) -> i64 {
+    file_entry.last_accessed.unwrap_or(file_entry.created_at)
+}
+
+// Evicts the file which was not downloaded for the longest time
+pub struct LeastRecentlyUsed;
+
+impl EvictionPolicyFunc for LeastRecentlyUsed {
+    fn name(&self) -> &str {
+        "lru"
+    }
+
+    fn order(&self, candidates: &mut [FileEntry]) {
+        candidates.sort_by_key(last_used);
+    }
+}
+
+// Evicts the file with the fewest downloads, the least recently used one among equals
+pub struct LeastFrequentlyUsed;
+
+impl EvictionPolicyFunc for LeastFrequentlyUsed {
+    fn name(&self) -> &str {
+        "lfu"
+    }
+
+    fn order(&self, candidates: &mut [FileEntry]) {
+        candidates.sort_by_key(|file_entry| (file_entry.access_count, last_used(file_entry)));
+    }
+}
+
+// Evicts the file which was recovered first
+pub struct OldestRecoveredFirst;
+
+impl EvictionPolicyFunc for OldestRecoveredFirst {
+    fn name(&self) -> &str {
+        "oldest_recovered"
+    }
+
+    fn order(&self, candidates: &mut [FileEntry]) {
+        candidates.sort_by_key(|file_entry| file_entry.created_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::tests::open_app_state;
+    use crate::compression::StoredContent;
+    use crate::config_store::ContentHasher;
+    use crate::file_store::FileAccess;
+    use std::collections::HashMap;
+
+    fn recovered() -> FileSource {
+        FileSource::Recovered {
+            node_addr: String::from("http://node"),
+        }
+    }
+
+    fn file(hash: &str, created_at: i64, last_accessed: Option<i64>, count: u64) -> FileEntry {
+        FileEntry {
+            hash: String::from(hash),
+            created_at,
+            last_accessed,
+            access_count: count,
+            source: recovered(),
+            ..Default::default()
+        }
+    }
+
+    fn order(policy: &dyn EvictionPolicyFunc) -> Vec<String> {
+        let mut candidates = vec![
+            file("a", 1, Some(40), 5),
+            file("b", 2, None, 0),
+            file("c", 3, Some(10), 1),
+            file("d", 4, Some(30), 1),
+        ];
+        policy.order(&mut candidates);
+        candidates.into_iter().map(|f| f.hash).collect()
+    }
+
+    #[test]
+    fn policies_order_the_candidates() {
+        assert_eq!(order(&LeastRecentlyUsed), vec!["b", "c", "d", "a"]);
+        assert_eq!(order(&LeastFrequentlyUsed), vec!["b", "c", "d", "a"]);
+        assert_eq!(order(&OldestRecoveredFirst), vec!["a", "b", "c", "d"]);
+
+        // Never downloaded files count as used when they were stored
+        let mut candidates = vec![file("old", 5, Some(20), 0), file("new", 10, None, 0)];
+        LeastFrequentlyUsed.order(&mut candidates);
+        assert_eq!(candidates[0].hash, "new");
+    }
+
+    #[test]
+    fn only_recovered_files_without_hold_are_evicted() {
+        let now = chrono::Utc::now().timestamp();
+        assert!(is_evictable(&file("a", 0, None, 0)));
+        assert!(is_evictable(&FileEntry {
+            retain_until: Some(now - 10),
+            ..file("a", 0, None, 0)
+        }));
+        assert!(!is_evictable(&FileEntry {
+            pinned: true,
+            ..file("a", 0, None, 0)
+        }));
+        assert!(!is_evictable(&FileEntry {
+            retain_until: Some(now + 60),
+            ..file("a", 0, None, 0)
+        }));
+        assert!(!is_evictable(&FileEntry {
+            source: FileSource::Upload,
+            ..file("a", 0, None, 0)
+        }));
+        assert!(!is_evictable(&FileEntry {
+            source: FileSource::Unknown,
+            ..file("a", 0, None, 0)
+        }));
+    }
+
+    #[test]
+    fn make_room_evicts_in_policy_order_and_skips_held_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "eviction_policy": "lru" });
+        let app_state = open_app_state(&dir, options).unwrap();
+
+        // Stores files last downloaded at the given time, the pinned one is the least recent
+        let mut hashes = HashMap::new();
+        let mut accesses = HashMap::new();
+        for (name, last_accessed, source) in [
+            ("pinned", 10, recovered()),
+            ("uploaded", 20, FileSource::Upload),
+            ("recent", 300, recovered()),
+            ("stale", 100, recovered()),
+        ] {
+            let mut hasher = ContentHasher::new();
+            hasher.input(name.as_bytes());
+            let hash = hasher.result();
+            let mut file_store = app_state.file_store.write().unwrap();
+            let content = StoredContent::identity(name.len() as u64);
+            file_store
+                .insert_file(&hash, content, "text/plain", name, source)
+                .unwrap();
+            file_store
+                .blob_store()
+                .put(&hash.hash, name.as_bytes())
+                .unwrap();
+            let access = FileAccess {
+                last_accessed,
+                count: 1,
+            };
+            accesses.insert(hash.hash.clone(), access);
+            hashes.insert(name, hash.hash);
+        }
+        let mut file_store = app_state.file_store.write().unwrap();
+        file_store.record_accesses(&accesses).unwrap();
+        file_store.set_pinned(&hashes["pinned"], true).unwrap();
+        let capacity_left = file_store.capacity_left();
+        drop(file_store);
+
+        assert!(app_state.make_room(capacity_left + 1));
+        let file_store = app_state.file_store.read().unwrap();
+        assert_eq!(
+            file_store.evicted_hashes().unwrap(),
+            vec![hashes["stale"].clone()]
+        );
+        assert!(file_store.get_file(&hashes["stale"]).unwrap().is_none());
+        assert!(file_store.blob_store().get(&hashes["stale"]).is_err());
+        for name in ["pinned", "uploaded", "recent"] {
+            assert!(file_store.get_file(&hashes[name]).unwrap().is_some());
+        }
+
+        // Held and uploaded files are never evicted, even if that is not enough
+        drop(file_store);
+        assert!(!app_state.make_room(capacity_left + 100));
+    }
+}
@@ -0,0 +1,334 @@
+use crate::file_store::{FileEntry, FileSource};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/*
+ * File Query
+ * Filters, sorting and pagination of the file listing, GET /files. The parameters are read from
+ * the query string, unknown sort keys, orders or sources are rejected by the server. Every
+ * FileStore answers the same query with the same page, files with equal sort keys are ordered
+ * by their hash.
+ */
+
+pub const DEFAULT_PAGE_SIZE: u64 = 100; // Files per page, if the query sets no limit
+pub const MAX_PAGE_SIZE: u64 = 1000; // Upper bound of the limit of a query
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    CreatedAt,
+    Size,
+    FileName,
+    LastAccessed, // Files never downloaded come first in ascending order
+    AccessCount,
+    Hash,
+}
+
+impl SortKey {
+    // SQL expression of the sort key in the files table
+    pub fn column(self) -> &'static str {
+        match self {
+            SortKey::CreatedAt => "created_at",
+            SortKey::Size => "size",
+            SortKey::FileName => "file_name",
+            SortKey::LastAccessed => "COALESCE(last_accessed, 0)",
+            SortKey::AccessCount => "access_count",
+            SortKey::Hash => "hash",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc, // Newest or largest files first
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceFilter {
+    Unknown,
+    Upload,
+    Recovered,
+}
+
+impl SourceFilter {
+    // Value of the source column in the files table
+    pub fn name(self) -> &'static str {
+        match self {
+            SourceFilter::Unknown => "unknown",
+            SourceFilter::Upload => "upload",
+            SourceFilter::Recovered => "recovered",
+        }
+    }
+
+    fn matches(self, source: &FileSource) -> bool {
+        matches!(
+            (self, source),
+            (SourceFilter::Unknown, FileSource::Unknown)
+                | (SourceFilter::Upload, FileSource::Upload)
+                | (SourceFilter::Recovered, FileSource::Recovered { .. })
+        )
+    }
+}
+
+// Query string of GET /files, all parameters are optional
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct FileQuery {
+    pub offset: u64,                  // Number of matching files to skip
+    pub limit: Option<u64>,           // Number of files per page, see DEFAULT_PAGE_SIZE and MAX_PAGE_SIZE
+    pub sort: SortKey,                // Sort key, created_at by default
+    pub order: SortOrder,             // Sort order, descending by default
+    pub content_type: Option<String>, // Media type like "image/png", or a whole type like "image/*"
+    pub min_size: Option<u64>,        // Smallest size in bytes
+    pub max_size: Option<u64>,        // Largest size in bytes
+    pub source: Option<SourceFilter>, // Where the files came from
+    pub tag: Option<String>,          // Tag the files have to carry
+}
+
+// One page of the file listing
+#[derive(Serialize, Debug, Default)]
+pub struct FilePage {
+    pub total: u64,            // Number of files matching the filters
+    pub offset: u64,           // Position of the first file of the page
+    pub limit: u64,            // Maximal number of files per page
+    pub files: Vec<FileEntry>, // Files of the page
+}
+
+// How the content_type filter matches the content type of a file
+pub enum ContentTypeFilter {
+    Prefix(String), // Type followed by a slash, for "image/*"
+    Exact(String),  // Media type, parameters like the charset are ignored
+}
+
+impl FileQuery {
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+
+    // Returns the content_type filter in lowercase, content types are case insensitive
+    pub fn content_type_filter(&self) -> Option<ContentTypeFilter> {
+        let content_type = self.content_type.as_ref()?.trim().to_lowercase();
+        Some(match content_type.strip_suffix('*') {
+            Some(prefix) if prefix.ends_with('/') => ContentTypeFilter::Prefix(prefix.to_string()),
+            _ => ContentTypeFilter::Exact(content_type),
+        })
+    }
+
+    // Returns true if the file passes all filters
+    pub fn matches(&self, file_entry: &FileEntry) -> bool {
+        let content_type = file_entry.content_type.to_lowercase();
+        let content_type_matches = match self.content_type_filter() {
+            None => true,
+            Some(ContentTypeFilter::Prefix(prefix)) => content_type.starts_with(&prefix),
+            Some(ContentTypeFilter::Exact(media_type)) => {
+                content_type == media_type || content_type.starts_with(&format!("{};", media_type))
+            }
+        };
+
+        content_type_matches
+            && self.min_size.is_none_or(|min| file_entry.size >= min)
+            && self.max_size.is_none_or(|max| file_entry.size <= max)
+            && self
+                .source
+                .is_none_or(|source| source.matches(&file_entry.source))
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| file_entry.tags.contains(tag))
+    }
+
+    // Orders two files by the sort key of the query, then by their hash
+    pub fn compare(&self, a: &FileEntry, b: &FileEntry) -> Ordering {
+        let ordering = match self.sort {
+            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::FileName => a.file_name.cmp(&b.file_name),
+            SortKey::LastAccessed => a
+                .last_accessed
+                .unwrap_or(0)
+                .cmp(&b.last_accessed.unwrap_or(0)),
+            SortKey::AccessCount => a.access_count.cmp(&b.access_count),
+            SortKey::Hash => Ordering::Equal,
+        }
+        .then_with(|| a.hash.cmp(&b.hash));
+
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+
+    // Filters, sorts and pages the given files
+    pub fn page(&self, files: impl Iterator<Item = FileEntry>) -> FilePage {
+        let mut matching: Vec<FileEntry> = files
+            .filter(|file_entry| self.matches(file_entry))
+            .collect();
+        matching.sort_by(|a, b| self.compare(a, b));
+
+        FilePage {
+            total: matching.len() as u64,
+            offset: self.offset,
+            limit: self.limit(),
+            files: matching
+                .into_iter()
+                .skip(self.offset as usize)
+                .take(self.limit() as usize)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a query string like GET /files does
+    async fn parse(query: &str) -> Option<FileQuery> {
+        warp::test::request()
+            .path(&format!("/files?{}", query))
+            .filter(&warp::query::<FileQuery>())
+            .await
+            .ok()
+    }
+
+    fn file(hash: &str, content_type: &str, size: u64, created_at: i64) -> FileEntry {
+        FileEntry {
+            hash: String::from(hash),
+            content_type: String::from(content_type),
+            size,
+            created_at,
+            ..Default::default()
+        }
+    }
+
+    fn hashes(page: &FilePage) -> Vec<&str> {
+        page.files.iter().map(|f| f.hash.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn parses_the_query_string() {
+        let query = parse("").await.unwrap();
+        assert_eq!(query.sort, SortKey::CreatedAt);
+        assert_eq!(query.order, SortOrder::Desc);
+        assert_eq!(query.limit(), DEFAULT_PAGE_SIZE);
+
+        let query = parse("sort=last_accessed&order=asc&limit=5000&source=recovered&tag=a%20b")
+            .await
+            .unwrap();
+        assert_eq!(query.sort, SortKey::LastAccessed);
+        assert_eq!(query.order, SortOrder::Asc);
+        assert_eq!(query.limit(), MAX_PAGE_SIZE);
+        assert_eq!(query.source, Some(SourceFilter::Recovered));
+        assert_eq!(query.tag.as_deref(), Some("a b"));
+
+        assert!(parse("sort=color").await.is_none());
+        assert!(parse("order=up").await.is_none());
+        assert!(parse("source=mirror").await.is_none());
+        assert!(parse("min_size=-1").await.is_none());
+    }
+
+    #[test]
+    fn filters_by_content_type_size_source_and_tag() {
+        let mut text = file("a", "Text/Plain; charset=utf-8", 10, 0);
+        text.tags = vec![String::from("invoices")];
+        let mut image = file("b", "image/png", 1000, 0);
+        image.source = FileSource::Recovered {
+            node_addr: String::from("http://node"),
+        };
+        let plain = file("c", "text/plain-extended", 100, 0);
+
+        let query = |query: FileQuery| {
+            [&text, &image, &plain]
+                .iter()
+                .filter(|file_entry| query.matches(file_entry))
+                .map(|file_entry| file_entry.hash.as_str())
+                .collect::<Vec<&str>>()
+        };
+        let content_type = |content_type: &str| FileQuery {
+            content_type: Some(String::from(content_type)),
+            ..Default::default()
+        };
+
+        assert_eq!(query(content_type("text/plain")), vec!["a"]);
+        assert_eq!(query(content_type("TEXT/*")), vec!["a", "c"]);
+        assert_eq!(query(content_type("image/*")), vec!["b"]);
+        assert_eq!(
+            query(FileQuery {
+                min_size: Some(10),
+                max_size: Some(100),
+                ..Default::default()
+            }),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            query(FileQuery {
+                source: Some(SourceFilter::Recovered),
+                ..Default::default()
+            }),
+            vec!["b"]
+        );
+        assert_eq!(
+            query(FileQuery {
+                source: Some(SourceFilter::Unknown),
+                ..Default::default()
+            }),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            query(FileQuery {
+                tag: Some(String::from("invoice")),
+                ..Default::default()
+            }),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            query(FileQuery {
+                tag: Some(String::from("invoices")),
+                ..Default::default()
+            }),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn sorts_and_pages_the_matching_files() {
+        let files = vec![
+            file("d", "text/plain", 30, 2),
+            file("a", "text/plain", 10, 1),
+            file("c", "text/plain", 30, 3),
+            file("b", "image/png", 20, 4),
+        ];
+
+        // Newest first by default
+        let page = FileQuery::default().page(files.clone().into_iter());
+        assert_eq!(hashes(&page), vec!["b", "c", "d", "a"]);
+
+        // Equal sizes are ordered by hash, in the direction of the order
+        let by_size = |order| FileQuery {
+            sort: SortKey::Size,
+            order,
+            ..Default::default()
+        };
+        let page = by_size(SortOrder::Asc).page(files.clone().into_iter());
+        assert_eq!(hashes(&page), vec!["a", "b", "c", "d"]);
+        let page = by_size(SortOrder::Desc).page(files.clone().into_iter());
+        assert_eq!(hashes(&page), vec!["d", "c", "b", "a"]);
+
+        // The total counts all matching files, not only those of the page
+        let query = FileQuery {
+            offset: 1,
+            limit: Some(1),
+            content_type: Some(String::from("text/plain")),
+            ..Default::default()
+        };
+        let page = query.page(files.into_iter());
+        assert_eq!(page.total, 3);
+        assert_eq!((page.offset, page.limit), (1, 1));
+        assert_eq!(hashes(&page), vec!["d"]);
+    }
+}
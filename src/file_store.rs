@@ -1,14 +1,34 @@
+use crate::blob_store::BlobStoreFunc;
+use crate::compression::{self, Encoding, StoredContent};
+use crate::config_store::{ContentHash, ContentHasher};
+use crate::file_query::{FilePage, FileQuery};
+use crate::state_file::{self, StateError};
 use log::debug;
-use log::{error, info};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-#[derive(Debug)]
+const FILE_STATE_VERSION: u64 = 3; // Version of the schema of file_state.json
+pub const RECOVER_DELAY: i64 = 5 * 60; // Seconds between recoveries of a file, doubled after every failed attempt
+pub const MAX_RECOVER_ATTEMPTS: u32 = 8; // Failed recoveries after which a file is reported as lost
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct RecoverEntry {
     pub hash: String,
+    #[serde(with = "timestamp")]
     pub last_checked: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub bad_sources: Vec<String>, // Nodes which failed to send the file or sent data not matching the hash
+    #[serde(default)]
+    pub attempts: u32, // Failed recoveries so far
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CorruptedReplica {
+    pub hash: String,      // Hash of the requested file
+    pub node_addr: String, // Node which sent data not matching the hash
 }
 
 impl RecoverEntry {
@@ -16,139 +36,555 @@ impl RecoverEntry {
         let t1 = self.last_checked.timestamp();
         let t2 = chrono::Utc::now().timestamp();
         let dif = t2 - t1;
-        dif > 5 * 60
+        dif > self.delay()
     }
+
+    // Seconds to wait after the last attempt
+    pub fn delay(&self) -> i64 {
+        RECOVER_DELAY << self.attempts.min(MAX_RECOVER_ATTEMPTS)
+    }
+}
+
+// Where a file came from
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FileSource {
+    #[default]
+    Unknown,                         // Stored before sources were recorded, or adopted by fsck
+    Upload,                          // Uploaded to this node
+    Recovered { node_addr: String }, // Downloaded from another node
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct FileEntry {
     pub hash: String,
+    #[serde(default)]
+    pub legacy_hash: Option<String>, // SHA-1 hash of the file, missing for files stored before SHA-256
     pub file_name: String,
     pub content_type: String,
-    path: String,
+    #[serde(default)]
+    pub size: u64, // Size of the file in bytes
+    #[serde(default)]
+    pub pinned: bool, // Pinned files are never deleted on request of the monitor
+    #[serde(default)]
+    pub retain_until: Option<i64>, // Unix timestamp until which the file is kept, even if the monitor deletes it
+    #[serde(default)]
+    pub created_at: i64, // Unix timestamp when the file was stored, 0 if unknown
+    #[serde(default)]
+    pub source: FileSource,
+    #[serde(default)]
+    pub last_accessed: Option<i64>, // Unix timestamp of the last download or lookup
+    #[serde(default)]
+    pub access_count: u64, // Number of downloads and lookups
+    #[serde(default)]
+    pub tags: Vec<String>, // Tags given by the user
+    #[serde(default)]
+    pub encoding: Encoding, // Encoding of the content in the BlobStore
+    #[serde(default)]
+    pub stored_size: Option<u64>, // Size of the content in the BlobStore, if it differs from size
 }
 
 impl FileEntry {
-    pub fn content(&self) -> Option<Vec<u8>> {
-        match std::fs::read(&self.path) {
-            Ok(content) => Some(content),
-            Err(_err) => None,
+    /* Creates the FileEntry for a newly stored file. If the file was stored before, its holds,
+     * tags, access statistics and origin are kept.
+     *
+     * replaced: FileEntry of the file stored before under the same hash
+     */
+    pub fn new(
+        hash: &ContentHash,
+        content: StoredContent,
+        content_type: &str,
+        file_name: &str,
+        source: FileSource,
+        replaced: Option<FileEntry>,
+    ) -> FileEntry {
+        let file_entry = FileEntry {
+            hash: hash.hash.clone(),
+            legacy_hash: Some(hash.legacy_hash.clone()),
+            file_name: String::from(file_name),
+            content_type: String::from(content_type),
+            size: content.size,
+            encoding: content.encoding,
+            stored_size: Some(content.stored_size).filter(|&stored| stored != content.size),
+            ..Default::default()
+        };
+
+        match replaced {
+            Some(replaced) => FileEntry {
+                pinned: replaced.pinned,
+                retain_until: replaced.retain_until,
+                created_at: replaced.created_at,
+                source: replaced.source,
+                last_accessed: replaced.last_accessed,
+                access_count: replaced.access_count,
+                tags: replaced.tags,
+                ..file_entry
+            },
+            None => FileEntry {
+                created_at: chrono::Utc::now().timestamp(),
+                source,
+                ..file_entry
+            },
         }
     }
+
+    // Returns the space the content takes in the BlobStore
+    pub fn disk_size(&self) -> u64 {
+        self.stored_size.unwrap_or(self.size)
+    }
+
+    // Returns true if a pin or retention hold prevents the deletion of the file
+    pub fn is_held(&self) -> bool {
+        self.pinned
+            || self
+                .retain_until
+                .is_some_and(|until| until > chrono::Utc::now().timestamp())
+    }
+}
+
+// Downloads and lookups of a file, counted in memory until they are written in a batch
+#[derive(Clone, Debug, Default)]
+pub struct FileAccess {
+    pub last_accessed: i64, // Unix timestamp of the last download or lookup
+    pub count: u64,         // Number of downloads and lookups
+}
+
+// File deleted on request of the monitor, kept in the trash until it is restored or purged
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrashEntry {
+    pub file_entry: FileEntry,
+    pub deleted_at: i64, // Unix timestamp of the deletion
+}
+
+// Content of file_state.json, the queues are kept as well so pending work survives a restart
+#[derive(Deserialize, Default)]
+struct FileState {
+    files: HashMap<String, FileEntry>,
+    #[serde(default)]
+    files_to_recover: Vec<RecoverEntry>,
+    #[serde(default)]
+    files_to_distribute: Vec<String>,
+    #[serde(default)]
+    rejected_hashes: Vec<String>,
+    #[serde(default)]
+    uploaded_hashes: Vec<String>,
+    #[serde(default)]
+    corrupted_hashes: Vec<String>,
+    #[serde(default)]
+    lost_hashes: Vec<String>,
+    #[serde(default)]
+    corrupted_replicas: Vec<CorruptedReplica>,
+    #[serde(default)]
+    refused_deletions: Vec<String>,
+    #[serde(default)]
+    trash: HashMap<String, TrashEntry>,
+    #[serde(default)]
+    evicted_hashes: Vec<String>,
 }
 
 pub struct FileStore {
-    path: String,                       // Path where the files are located
+    path: String,                       // Path of the state dir
+    blob_store: Arc<dyn BlobStoreFunc>, // Stores the content of the files
     files_to_sync: Vec<RecoverEntry>,   // List of hashes to download from other nodes
     files_to_distribute: Vec<String>,   // List of hashes to distribute to monitors
     files: HashMap<String, FileEntry>,  // All files that are stored on this node
+    legacy_index: HashMap<String, String>, // Maps SHA-1 hashes to the SHA-256 hash of a file
     capacity: u64,                      // Total space on hdd
+    used: u64,                          // Space used by all stored files
+    reserved: u64,                      // Space reserved for running uploads and recoveries
+    written: AtomicU64,                 // Part of the reserved space already written to disk
     hashes_to_reject: Vec<String>,      // List of hashes that could not be processed
     new_hashes: Vec<String>,            // List of hashes of downloaded files since last ping
+    corrupted_hashes: Vec<String>,      // List of hashes of quarantined files since last ping
+    lost_hashes: Vec<String>,           // List of hashes of files whose content vanished since last ping
+    corrupted_replicas: Vec<CorruptedReplica>, // Replicas on other nodes found corrupted since last ping
+    refused_deletions: Vec<String>,     // List of hashes the monitor wanted to delete, but are held
+    trash: HashMap<String, TrashEntry>, // Deleted files which can still be restored
+    evicted_hashes: Vec<String>,        // List of hashes of files evicted to make room since last ping
+    dirty: AtomicBool,                  // Set by every change, cleared once the state is written to disk
 }
 
+/* Metadata of all stored files and the queues of pending work. Every method which reads or
+ * changes them fails if the store can not be read or written, so a failure is never mistaken
+ * for a missing file or an empty queue.
+ */
 pub trait FileStoreFunc {
-    fn new(capacity: u64, path: &str) -> FileStore;
-    fn get_file(&self, hash: &str) -> Option<&FileEntry>;               // Returns FileEntry for given hash
-    fn save_file(&mut self, hash: &str, content: &[u8], content_type: &str, file_name: &str); // saves file in FileStore. Includes creation of new file and FileEntry
-    fn remove_file(&mut self, hash: &str);                              // Removes file from FileStore and disk
-    fn insert_files_to_recover(&mut self, entries: Vec<RecoverEntry>);  // Inserts file to list of files to recover
-    fn next_file_to_recover(&mut self) -> Option<RecoverEntry>;         // Returns next hash to recover, if it exists
-    fn insert_file_to_distribute(&mut self, hash: &str);                // Inserts hash in list of files to distribute
-    fn next_file_to_distribute(&mut self) -> Option<String>;            // Returns next hash to distribute, if it exists
-    fn hashes(&self) -> Vec<String>;                                    // Returns list of all hashes stored in FileStore
-    fn capacity_left(&self) -> u64;                                     // Returns available free space on disk
-    fn reject_hash(&mut self, hash: &str);                              // Adds given hash to list of hashes to reject
-    fn rejected_hashes(&self) -> Vec<String>;                           // Returns all rejected hashes
-    fn clear_rejected_hashes(&mut self);                                // Clears list of all rejected hashes
-    fn serialize_state(&self);                                          // saves current FileStore to disk as a JSON object
-    fn deserialize_state(path: &str) -> HashMap<String, FileEntry>;     // Reads FileState from a file
-    fn uploaded_hashes(&self) -> Vec<String>;                           // Returns list of all new hashes since last ping
-    fn add_hash_to_uploaded_hashes(&mut self, hash: &str);              // Adds hash to new hashes
-    fn clear_uploaded_hashes(&mut self);                                // Clears list of new hashes
+    fn new(capacity: u64, path: &str, blob_store: Arc<dyn BlobStoreFunc>) -> Self where Self: Sized;
+    fn blob_store(&self) -> Arc<dyn BlobStoreFunc>;                     // Returns the BlobStore which stores the content of the files
+    fn get_file(&self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Returns FileEntry for given SHA-256 or SHA-1 hash
+    fn insert_file(&mut self, hash: &ContentHash, content: StoredContent, content_type: &str, file_name: &str, source: FileSource) -> std::io::Result<()>; // Creates the FileEntry for content already stored in the BlobStore
+    fn temp_file_path(&self) -> std::io::Result<String>;                // Returns a new unique path for a temporary file inside the state dir
+    fn remove_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Removes FileEntry from FileStore, the content has to be removed by the caller
+    fn quarantine_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Removes FileEntry of a corrupted file and reports it with the next ping
+    fn corrupted_hashes(&self) -> std::io::Result<Vec<String>>;         // Returns all quarantined hashes since last ping
+    fn clear_corrupted_hashes(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the quarantined hashes which were sent with a ping
+    fn remove_lost_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Removes FileEntry of a file whose content is gone and reports it with the next ping
+    fn report_lost_hash(&mut self, hash: &str) -> std::io::Result<()>;  // Reports a file which could not be recovered as lost with the next ping
+    fn lost_hashes(&self) -> std::io::Result<Vec<String>>;              // Returns all lost hashes since last ping
+    fn clear_lost_hashes(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the lost hashes which were sent with a ping
+    fn evict_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Removes FileEntry of a file evicted to make room and reports it with the next ping
+    fn evicted_hashes(&self) -> std::io::Result<Vec<String>>;           // Returns all evicted hashes since last ping
+    fn clear_evicted_hashes(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the evicted hashes which were sent with a ping
+    fn report_corrupted_replica(&mut self, hash: &str, node_addr: &str) -> std::io::Result<()>; // Remembers that a node sent corrupted data for a hash
+    fn corrupted_replicas(&self) -> std::io::Result<Vec<CorruptedReplica>>; // Returns all corrupted replicas found since last ping
+    fn clear_corrupted_replicas(&mut self, reported: &[CorruptedReplica]) -> std::io::Result<()>; // Removes the corrupted replicas which were sent with a ping
+    fn set_pinned(&mut self, hash: &str, pinned: bool) -> std::io::Result<Option<FileEntry>>; // Pins or unpins a file, returns the updated FileEntry
+    fn set_retention(&mut self, hash: &str, retain_until: Option<i64>) -> std::io::Result<Option<FileEntry>>; // Sets or clears the retention hold of a file
+    fn set_tags(&mut self, hash: &str, tags: Vec<String>) -> std::io::Result<Option<FileEntry>>; // Replaces the tags of a file
+    fn record_accesses(&mut self, accesses: &HashMap<String, FileAccess>) -> std::io::Result<()>; // Adds downloads and lookups counted since the last call
+    fn refuse_deletion(&mut self, hash: &str) -> std::io::Result<()>;   // Remembers a deletion refused because of a hold, to report it with the next ping
+    fn refused_deletions(&self) -> std::io::Result<Vec<String>>;        // Returns all refused deletions since last ping
+    fn clear_refused_deletions(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the refused deletions which were sent with a ping
+    fn trash_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Moves FileEntry to the trash, the content has to be moved by the caller
+    fn restore_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>>; // Moves FileEntry back from the trash, the content has to be moved by the caller
+    fn purge_file(&mut self, hash: &str) -> std::io::Result<Option<TrashEntry>>; // Removes FileEntry from the trash, the content has to be removed by the caller
+    fn trashed_files(&self) -> std::io::Result<Vec<TrashEntry>>;        // Returns all files in the trash, oldest deletion first
+    fn trash_used(&self) -> std::io::Result<u64>;                       // Returns space used by the files in the trash
+    fn trash_excess(&self) -> std::io::Result<u64>;                     // Returns how much the trash exceeds the space not used or reserved by other files
+    fn insert_files_to_recover(&mut self, entries: Vec<RecoverEntry>) -> std::io::Result<()>; // Inserts file to list of files to recover
+    fn next_file_to_recover(&mut self) -> std::io::Result<Option<RecoverEntry>>; // Returns next hash to recover, if it exists
+    fn insert_file_to_distribute(&mut self, hash: &str) -> std::io::Result<()>; // Inserts hash in list of files to distribute
+    fn next_file_to_distribute(&mut self) -> std::io::Result<Option<String>>; // Returns next hash to distribute, if it exists
+    fn hashes(&self) -> std::io::Result<Vec<String>>;                   // Returns list of all hashes stored in FileStore
+    fn files(&self) -> std::io::Result<Vec<FileEntry>>;                 // Returns the FileEntries of all stored files
+    fn list_files(&self, query: &FileQuery) -> std::io::Result<FilePage>; // Returns one page of the stored files matching the query
+    fn legacy_hashes(&self) -> std::io::Result<HashMap<String, String>>; // Returns the SHA-1 hash for every file that has one
+    fn capacity(&self) -> u64;                                          // Returns the total space for files, the sum of all healthy data dirs if there are several
+    fn capacity_left(&self) -> u64;                                     // Returns the space left for files, at most the free space on disk, minus reserved space not written yet
+    fn reserve_space(&mut self, size: u64) -> bool;                     // Reserves space for an incoming file, fails if not enough space is left
+    fn mark_written(&self, size: u64);                                  // Marks reserved space as written to disk, the free space on disk already shrank by it
+    fn unmark_written(&self, size: u64);                                // Undoes mark_written, before the written bytes are stored or removed
+    fn release_space(&mut self, size: u64);                             // Releases reserved space, after the file was stored or its transfer failed
+    fn reject_hash(&mut self, hash: &str) -> std::io::Result<()>;       // Adds given hash to list of hashes to reject
+    fn rejected_hashes(&self) -> std::io::Result<Vec<String>>;          // Returns all rejected hashes
+    fn clear_rejected_hashes(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the rejected hashes which were sent with a ping
+    fn serialize_state(&self);                                          // saves current FileStore to disk, if it changed since the last call
+    fn uploaded_hashes(&self) -> std::io::Result<Vec<String>>;          // Returns list of all new hashes since last ping
+    fn add_hash_to_uploaded_hashes(&mut self, hash: &str) -> std::io::Result<()>; // Adds hash to new hashes
+    fn clear_uploaded_hashes(&mut self, reported: &[String]) -> std::io::Result<()>; // Removes the new hashes which were sent with a ping
 }
 
 impl FileStoreFunc for FileStore {
-    fn new(capacity: u64, path: &str) -> FileStore {
-        // Read state from filke
-        let file_state_path = format!("{}/file_state.json", path);
-        let files = FileStore::deserialize_state(&file_state_path);
-        
+    fn new(capacity: u64, path: &str, blob_store: Arc<dyn BlobStoreFunc>) -> FileStore {
+        prepare_state_dir(path);
+
+        // Read state from file
+        let state = FileStore::deserialize_state(path, &blob_store);
+
         // Output all saved files
-        let tmp = files
+        let tmp = state
+            .files
             .values()
             .map(|fe| format!("{}: {}", &fe.hash, &fe.file_name))
             .collect::<Vec<String>>();
         info!("FileStore initialized: {:?}", tmp);
 
-        if let Err(err) = std::fs::create_dir_all(path) {
-            if !std::path::Path::new(path).exists() {
-                panic!("{}", err);
-            }
-        }
-
-        FileStore {
+        let mut file_store = FileStore {
             path: String::from(path),
-            files_to_sync: vec![],
-            files_to_distribute: vec![],
-            files,
+            blob_store,
+            files_to_sync: state.files_to_recover,
+            files_to_distribute: state.files_to_distribute,
+            files: state.files,
+            legacy_index: HashMap::new(),
             capacity,
-            hashes_to_reject: vec![],
-            new_hashes: vec![],
+            used: 0,
+            reserved: 0,
+            written: AtomicU64::new(0),
+            hashes_to_reject: state.rejected_hashes,
+            new_hashes: state.uploaded_hashes,
+            corrupted_hashes: state.corrupted_hashes,
+            lost_hashes: state.lost_hashes,
+            corrupted_replicas: state.corrupted_replicas,
+            refused_deletions: state.refused_deletions,
+            trash: state.trash,
+            evicted_hashes: state.evicted_hashes,
+            dirty: AtomicBool::new(false),
+        };
+
+        file_store.migrate_legacy_hashes();
+
+        // States written before sizes were tracked lack them, afterwards the usage is tracked on every change
+        for file_entry in file_store.files.values_mut() {
+            if file_entry.size == 0 {
+                file_entry.size = file_store
+                    .blob_store
+                    .size(&file_entry.hash)
+                    .unwrap_or(0);
+            }
         }
+        file_store.used = file_store.files.values().map(|fe| fe.disk_size()).sum();
+
+        file_store.legacy_index = file_store
+            .files
+            .values()
+            .filter_map(|fe| {
+                fe.legacy_hash
+                    .as_ref()
+                    .map(|legacy_hash| (legacy_hash.clone(), fe.hash.clone()))
+            })
+            .collect();
+
+        file_store
+    }
+
+    fn blob_store(&self) -> Arc<dyn BlobStoreFunc> {
+        self.blob_store.clone()
     }
 
-    fn get_file(&self, hash: &str) -> Option<&FileEntry> {
+    fn get_file(&self, hash: &str) -> std::io::Result<Option<FileEntry>> {
         debug!("[FileStore.get_file] {}", hash);
-        self.files.get(hash)
+        Ok(self.file_entry(hash).cloned())
     }
 
-    fn remove_file(&mut self, hash: &str) {
+    fn remove_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
         debug!("[FileStore.remove_file] {}", hash);
-        // Check if file is found on disk and delete it
-        // Then remove FileEntry from store
-        if let Some(file_entry) = self.files.get(hash) {
-            match std::fs::remove_file(&file_entry.path) {
-                Ok(_) => {
-                    info!("Removed file {}", hash);
-                    self.files.remove(hash);
-                }
-                Err(err) => error!("{}", err),
+        let file_entry = match self.remove_entry(hash) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
+
+    fn quarantine_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.quarantine_file] {}", hash);
+        let file_entry = match self.remove_entry(hash) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.corrupted_hashes.push(file_entry.hash.clone());
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
+
+    fn corrupted_hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.corrupted_hashes.clone())
+    }
+
+    fn clear_corrupted_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.corrupted_hashes, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn remove_lost_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.remove_lost_file] {}", hash);
+        let file_entry = match self.remove_entry(hash) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.lost_hashes.push(file_entry.hash.clone());
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
+
+    fn report_lost_hash(&mut self, hash: &str) -> std::io::Result<()> {
+        self.lost_hashes.push(String::from(hash));
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn lost_hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.lost_hashes.clone())
+    }
+
+    fn clear_lost_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.lost_hashes, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn evict_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.evict_file] {}", hash);
+        let file_entry = match self.remove_entry(hash) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.evicted_hashes.push(file_entry.hash.clone());
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
+
+    fn evicted_hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.evicted_hashes.clone())
+    }
+
+    fn clear_evicted_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.evicted_hashes, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn report_corrupted_replica(&mut self, hash: &str, node_addr: &str) -> std::io::Result<()> {
+        self.corrupted_replicas.push(CorruptedReplica {
+            hash: String::from(hash),
+            node_addr: String::from(node_addr),
+        });
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn corrupted_replicas(&self) -> std::io::Result<Vec<CorruptedReplica>> {
+        Ok(self.corrupted_replicas.clone())
+    }
+
+    fn clear_corrupted_replicas(&mut self, reported: &[CorruptedReplica]) -> std::io::Result<()> {
+        remove_reported(&mut self.corrupted_replicas, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn set_pinned(&mut self, hash: &str, pinned: bool) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.set_pinned] {}: {}", hash, pinned);
+        Ok(self.update_entry(hash, |file_entry| file_entry.pinned = pinned))
+    }
+
+    fn set_retention(
+        &mut self,
+        hash: &str,
+        retain_until: Option<i64>,
+    ) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.set_retention] {}: {:?}", hash, retain_until);
+        Ok(self.update_entry(hash, |file_entry| file_entry.retain_until = retain_until))
+    }
+
+    fn set_tags(&mut self, hash: &str, tags: Vec<String>) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.set_tags] {}: {:?}", hash, tags);
+        Ok(self.update_entry(hash, |file_entry| file_entry.tags = tags))
+    }
+
+    fn record_accesses(&mut self, accesses: &HashMap<String, FileAccess>) -> std::io::Result<()> {
+        for (hash, access) in accesses {
+            let primary = match self.file_entry(hash) {
+                Some(file_entry) => file_entry.hash.clone(),
+                None => continue,
+            };
+            if let Some(file_entry) = self.files.get_mut(&primary) {
+                file_entry.last_accessed = Some(access.last_accessed);
+                file_entry.access_count += access.count;
+                self.mark_dirty();
             }
         }
+        Ok(())
     }
 
-    fn save_file(&mut self, hash: &str, content: &[u8], content_type: &str, file_name: &str) {
-        debug!(
-            "[FileStore.save_file] hash: {}, file_name: {}",
-            hash, file_name
+    fn refuse_deletion(&mut self, hash: &str) -> std::io::Result<()> {
+        self.refused_deletions.push(String::from(hash));
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn refused_deletions(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.refused_deletions.clone())
+    }
+
+    fn clear_refused_deletions(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.refused_deletions, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn trash_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.trash_file] {}", hash);
+        let file_entry = match self.remove_entry(hash) {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.trash.insert(
+            file_entry.hash.clone(),
+            TrashEntry {
+                file_entry: file_entry.clone(),
+                deleted_at: chrono::Utc::now().timestamp(),
+            },
         );
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
 
-        // Create dir if not exist
-        let file_dir = std::path::Path::new(&self.path).join("files");
-        if !file_dir.exists() {
-            let _ = std::fs::create_dir_all(file_dir);
+    fn restore_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[FileStore.restore_file] {}", hash);
+        let file_entry = match self.trash_key(hash) {
+            Some(primary) => self.trash.remove(&primary).unwrap().file_entry,
+            None => return Ok(None),
+        };
+        self.remove_entry(&file_entry.hash);
+        self.used += file_entry.disk_size();
+        if let Some(legacy_hash) = &file_entry.legacy_hash {
+            self.legacy_index
+                .insert(legacy_hash.clone(), file_entry.hash.clone());
         }
+        self.files.insert(file_entry.hash.clone(), file_entry.clone());
+        self.mark_dirty();
+        Ok(Some(file_entry))
+    }
 
-        // Create physical file
-        let file_path = format!("{}/files/{}", self.path, hash);
-        let mut file = std::fs::File::create(&file_path).unwrap();
-        let _res = file.write_all(content);
-
-        // Create file entry
-        let file_entry = FileEntry {
-            hash: String::from(hash),
-            file_name: String::from(file_name),
-            content_type: String::from(content_type),
-            path: String::from(&file_path),
+    fn purge_file(&mut self, hash: &str) -> std::io::Result<Option<TrashEntry>> {
+        debug!("[FileStore.purge_file] {}", hash);
+        let trash_entry = match self.trash_key(hash) {
+            Some(primary) => self.trash.remove(&primary),
+            None => return Ok(None),
         };
-        self.files.insert(String::from(hash), file_entry);
+        self.mark_dirty();
+        Ok(trash_entry)
+    }
+
+    fn trashed_files(&self) -> std::io::Result<Vec<TrashEntry>> {
+        let mut trashed_files = self.trash.values().cloned().collect::<Vec<TrashEntry>>();
+        trashed_files.sort_by_key(|entry| entry.deleted_at);
+        Ok(trashed_files)
+    }
+
+    fn trash_used(&self) -> std::io::Result<u64> {
+        Ok(self
+            .trash
+            .values()
+            .map(|entry| entry.file_entry.disk_size())
+            .sum())
+    }
+
+    // The trash takes space on disk as well, so it is purged when the disk runs full
+    fn trash_excess(&self) -> std::io::Result<u64> {
+        let excess =
+            (self.used + self.reserved + self.trash_used()?).saturating_sub(self.capacity());
+        Ok(match self.blob_store.free_space() {
+            Some(free_space) => excess.max(self.unwritten().saturating_sub(free_space)),
+            None => excess,
+        })
+    }
+
+    fn insert_file(
+        &mut self,
+        hash: &ContentHash,
+        content: StoredContent,
+        content_type: &str,
+        file_name: &str,
+        source: FileSource,
+    ) -> std::io::Result<()> {
+        debug!(
+            "[FileStore.insert_file] hash: {}, file_name: {}",
+            hash.hash, file_name
+        );
+
+        let replaced = self.files.get(&hash.hash).cloned();
+        let file_entry = FileEntry::new(hash, content, content_type, file_name, source, replaced);
+        self.used += file_entry.disk_size();
+        self.legacy_index
+            .insert(hash.legacy_hash.clone(), hash.hash.clone());
+        if let Some(replaced) = self.files.insert(hash.hash.clone(), file_entry) {
+            self.used = self.used.saturating_sub(replaced.disk_size());
+        }
+        self.mark_dirty();
+        Ok(())
     }
 
-    fn insert_files_to_recover(&mut self, entries: Vec<RecoverEntry>) {
+    fn temp_file_path(&self) -> std::io::Result<String> {
+        new_temp_file_path(&self.path)
+    }
+
+    fn insert_files_to_recover(&mut self, entries: Vec<RecoverEntry>) -> std::io::Result<()> {
         for entry in entries {
-            if self.files.contains_key(&entry.hash) {
-                self.reject_hash(&entry.hash);
+            if self.file_entry(&entry.hash).is_some() {
+                self.hashes_to_reject.push(entry.hash.clone());
                 debug!(
                     "[FileStore.insert_files_to_recover] Rejected {}",
                     &entry.hash
@@ -158,102 +594,429 @@ impl FileStoreFunc for FileStore {
                 self.files_to_sync.push(entry);
             }
         }
+        self.mark_dirty();
+        Ok(())
     }
 
-    fn next_file_to_recover(&mut self) -> Option<RecoverEntry> {
-        self.files_to_sync
+    fn next_file_to_recover(&mut self) -> std::io::Result<Option<RecoverEntry>> {
+        let index = match self
+            .files_to_sync
             .iter()
             .position(|entry| entry.waited_enough())
-            .and_then(|index| Some(self.files_to_sync.remove(index)))
+        {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let entry = self.files_to_sync.remove(index);
+        self.mark_dirty();
+        Ok(Some(entry))
     }
 
-    fn insert_file_to_distribute(&mut self, hash: &str) {
+    fn insert_file_to_distribute(&mut self, hash: &str) -> std::io::Result<()> {
         self.files_to_distribute.push(String::from(hash));
+        self.mark_dirty();
+        Ok(())
     }
 
-    fn next_file_to_distribute(&mut self) -> Option<String> {
+    fn next_file_to_distribute(&mut self) -> std::io::Result<Option<String>> {
         if self.files_to_distribute.is_empty() {
-            return None;
+            return Ok(None);
         }
 
-        Some(self.files_to_distribute.remove(0))
+        let hash = self.files_to_distribute.remove(0);
+        self.mark_dirty();
+        Ok(Some(hash))
+    }
+
+    fn hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.files.keys().cloned().collect::<Vec<String>>())
+    }
+
+    fn files(&self) -> std::io::Result<Vec<FileEntry>> {
+        Ok(self.files.values().cloned().collect::<Vec<FileEntry>>())
     }
 
-    fn hashes(&self) -> Vec<String> {
-        self.files
-            .keys()
-            .clone()
-            .map(|k| String::from(k))
-            .collect::<Vec<String>>()
+    fn list_files(&self, query: &FileQuery) -> std::io::Result<FilePage> {
+        Ok(query.page(self.files.values().cloned()))
     }
 
+    fn legacy_hashes(&self) -> std::io::Result<HashMap<String, String>> {
+        Ok(self
+            .files
+            .values()
+            .filter_map(|fe| {
+                fe.legacy_hash
+                    .as_ref()
+                    .map(|legacy_hash| (fe.hash.clone(), legacy_hash.clone()))
+            })
+            .collect())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.blob_store.capacity().unwrap_or(self.capacity)
+    }
+
+    // Limited by the capacity and by the space actually left on the disk
     fn capacity_left(&self) -> u64 {
-        let used = self.files.values().fold(0, |acc, file_entry| {
-            let metadata = std::fs::metadata(&file_entry.path).unwrap();
-            acc + metadata.len()
-        });
+        let capacity_left = self.capacity().saturating_sub(self.used + self.reserved);
+        match self.blob_store.free_space() {
+            Some(free_space) => capacity_left.min(free_space.saturating_sub(self.unwritten())),
+            None => capacity_left,
+        }
+    }
 
-        if used > self.capacity {
-            return 0;
+    fn reserve_space(&mut self, size: u64) -> bool {
+        if size > self.capacity_left() {
+            debug!("[FileStore.reserve_space] Not enough space for {} bytes", size);
+            return false;
         }
 
-        self.capacity - used
+        self.reserved += size;
+        true
+    }
+
+    fn mark_written(&self, size: u64) {
+        self.written.fetch_add(size, Ordering::Relaxed);
     }
 
-    fn reject_hash(&mut self, hash: &str) {
+    fn unmark_written(&self, size: u64) {
+        self.written.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn release_space(&mut self, size: u64) {
+        self.reserved = self.reserved.saturating_sub(size);
+    }
+
+    fn reject_hash(&mut self, hash: &str) -> std::io::Result<()> {
         self.hashes_to_reject.push(String::from(hash));
+        self.mark_dirty();
+        Ok(())
     }
 
-    fn rejected_hashes(&self) -> Vec<String> {
-        let x = self
-            .hashes_to_reject
-            .iter()
-            .map(|hash| String::from(hash))
-            .collect::<Vec<String>>();
-        return x;
+    fn rejected_hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.hashes_to_reject.clone())
     }
 
-    fn clear_rejected_hashes(&mut self) {
-        self.hashes_to_reject.clear();
+    fn clear_rejected_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.hashes_to_reject, reported);
+        self.mark_dirty();
+        Ok(())
     }
 
     fn serialize_state(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
         let path = format!("{}/file_state.json", self.path);
-        let serialized = serde_json::to_string(&self.files).unwrap();
-        let mut file = File::create(path).unwrap();
-        let _ = file.write_all(serialized.as_bytes());
+        let state = serde_json::json!({
+            "version": FILE_STATE_VERSION,
+            "files": &self.files,
+            "files_to_recover": &self.files_to_sync,
+            "files_to_distribute": &self.files_to_distribute,
+            "rejected_hashes": &self.hashes_to_reject,
+            "uploaded_hashes": &self.new_hashes,
+            "corrupted_hashes": &self.corrupted_hashes,
+            "lost_hashes": &self.lost_hashes,
+            "corrupted_replicas": &self.corrupted_replicas,
+            "refused_deletions": &self.refused_deletions,
+            "trash": &self.trash,
+            "evicted_hashes": &self.evicted_hashes,
+        });
+        let serialized = serde_json::to_string(&state).unwrap();
+        if let Err(err) = state_file::write_atomic(&path, serialized.as_bytes()) {
+            error!("Could not write {}: {}", path, err);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
     }
 
-    fn deserialize_state(path: &str) -> HashMap<String, FileEntry> {
-        let result = File::open(path)
-            .and_then(|mut file| {
-                let mut contents = String::new();
-                let _ = file.read_to_string(&mut contents);
-                Ok(contents)
-            })
-            .and_then(|content| {
-                let files: HashMap<String, FileEntry> = serde_json::from_str(&content).unwrap();
-                Ok(files)
-            });
+    fn uploaded_hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.new_hashes.clone())
+    }
+
+    fn add_hash_to_uploaded_hashes(&mut self, hash: &str) -> std::io::Result<()> {
+        self.new_hashes.push(String::from(hash));
+        self.mark_dirty();
+        Ok(())
+    }
+
+    fn clear_uploaded_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        remove_reported(&mut self.new_hashes, reported);
+        self.mark_dirty();
+        Ok(())
+    }
+}
+
+impl FileStore {
+    /* Most changes are not written right away, which would rewrite the whole state for every
+     * access or hold. The ping service writes them every 30 seconds, and the node once more when
+     * it stops. New files are written before their upload or recovery is confirmed, see
+     * AppState::announce_new_file.
+     */
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Reserved space which is not written to disk yet, the written part is missing in free_space
+    fn unwritten(&self) -> u64 {
+        self.reserved.saturating_sub(self.written.load(Ordering::Relaxed))
+    }
+
+    // Reads FileState from the state dir, rebuilds it from the files if it is corrupted
+    fn deserialize_state(path: &str, blob_store: &Arc<dyn BlobStoreFunc>) -> FileState {
+        let file_state_path = format!("{}/file_state.json", path);
+        let migrations: [state_file::Migration; 2] =
+            [FileStore::migrate_state_v1, FileStore::migrate_state_v2];
+
+        let result = state_file::read_state(&file_state_path, &migrations).and_then(|value| {
+            value
+                .map(|value| {
+                    serde_json::from_value::<FileState>(value)
+                        .map_err(|err| StateError::Malformed(err.to_string()))
+                })
+                .transpose()
+        });
 
         match result {
-            Ok(files) => files,
-            Err(_err) => HashMap::new(),
+            Ok(Some(state)) => state,
+            Ok(None) => FileStore::rebuild_state(blob_store),
+            Err(StateError::UnsupportedVersion(version)) => panic!(
+                "{} was written by a newer version of the node (version {})",
+                file_state_path, version
+            ),
+            Err(err) => {
+                error!("Could not read {}: {}", file_state_path, err);
+                state_file::set_aside(&file_state_path);
+                FileStore::rebuild_state(blob_store)
+            }
         }
     }
 
-    fn uploaded_hashes(&self) -> Vec<String> {
-        self.new_hashes
-            .iter()
-            .map(|hash| String::from(hash))
-            .collect()
+    // Returns all queued recoveries, including those which have to wait before the next attempt
+    pub fn files_to_recover(&self) -> &[RecoverEntry] {
+        &self.files_to_sync
     }
 
-    fn add_hash_to_uploaded_hashes(&mut self, hash: &str) {
-        self.new_hashes.push(String::from(hash));
+    // Returns all hashes waiting to be distributed
+    pub fn files_to_distribute(&self) -> &[String] {
+        &self.files_to_distribute
+    }
+
+    // Applies f to the FileEntry for the given SHA-256 or SHA-1 hash and saves the state
+    fn update_entry<F: FnOnce(&mut FileEntry)>(&mut self, hash: &str, f: F) -> Option<FileEntry> {
+        let primary = self.file_entry(hash)?.hash.clone();
+        let file_entry = self.files.get_mut(&primary)?;
+        f(file_entry);
+        let file_entry = file_entry.clone();
+        self.mark_dirty();
+        Some(file_entry)
+    }
+
+    // Returns FileEntry for given SHA-256 or SHA-1 hash, without cloning it
+    fn file_entry(&self, hash: &str) -> Option<&FileEntry> {
+        self.files.get(hash).or_else(|| {
+            self.legacy_index
+                .get(hash)
+                .and_then(|primary| self.files.get(primary))
+        })
+    }
+
+    // Returns the key of the trashed file for given SHA-256 or SHA-1 hash
+    fn trash_key(&self, hash: &str) -> Option<String> {
+        if self.trash.contains_key(hash) {
+            return Some(String::from(hash));
+        }
+        self.trash
+            .values()
+            .find(|entry| entry.file_entry.legacy_hash.as_deref() == Some(hash))
+            .map(|entry| entry.file_entry.hash.clone())
+    }
+
+    // Version 1 was a plain map of all FileEntries
+    fn migrate_state_v1(files: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "version": 2, "files": files })
+    }
+
+    // Version 2 did not persist the queues, they start empty
+    fn migrate_state_v2(mut state: serde_json::Value) -> serde_json::Value {
+        if let Some(state) = state.as_object_mut() {
+            state.insert(String::from("version"), serde_json::json!(3));
+        }
+        state
+    }
+
+    /* Recreates the FileEntries from the contents found in the BlobStore, in case the state is
+     * missing or corrupted. File names and content types are lost, so the hash is used
+     * as file name.
+     */
+    fn rebuild_state(blob_store: &Arc<dyn BlobStoreFunc>) -> FileState {
+        let mut files = HashMap::new();
+        let keys = match blob_store.keys() {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("Could not list stored files: {}", err);
+                return FileState::default();
+            }
+        };
+
+        for key in keys {
+            let (content_hash, content) = match compression::identify_blob(blob_store.clone(), &key) {
+                Ok(identified) => identified,
+                Err(err) => {
+                    error!("Could not read {}: {}", key, err);
+                    continue;
+                }
+            };
+
+            // Store the file under its actual hash
+            if key != content_hash.hash {
+                if let Err(err) = blob_store.rename(&key, &content_hash.hash) {
+                    error!("Could not rename {}: {}", key, err);
+                    continue;
+                }
+            }
+
+            files.insert(
+                content_hash.hash.clone(),
+                FileEntry {
+                    hash: content_hash.hash.clone(),
+                    legacy_hash: Some(content_hash.legacy_hash),
+                    file_name: content_hash.hash.clone(),
+                    content_type: String::from("application/octet-stream"),
+                    size: content.size,
+                    encoding: content.encoding,
+                    stored_size: Some(content.stored_size).filter(|&stored| stored != content.size),
+                    ..Default::default()
+                },
+            );
+        }
+
+        if !files.is_empty() {
+            warn!("Rebuilt file state from {} stored files", files.len());
+        }
+        FileState {
+            files,
+            ..FileState::default()
+        }
+    }
+
+    // Removes the FileEntry for the given SHA-256 or SHA-1 hash, without touching the disk
+    fn remove_entry(&mut self, hash: &str) -> Option<FileEntry> {
+        let primary = self.file_entry(hash)?.hash.clone();
+        let file_entry = self.files.remove(&primary)?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        if let Some(legacy_hash) = &file_entry.legacy_hash {
+            self.legacy_index.remove(legacy_hash);
+        }
+        Some(file_entry)
+    }
+
+    /* One-time migration of files, which are still stored under their SHA-1 hash.
+     * Every such file gets rehashed with SHA-256 and renamed, its old hash is kept as
+     * legacy hash so it stays resolvable. Afterwards the state is written to disk.
+     */
+    fn migrate_legacy_hashes(&mut self) {
+        let legacy_entries = self
+            .files
+            .values()
+            .filter(|fe| fe.legacy_hash.is_none())
+            .map(|fe| fe.hash.clone())
+            .collect::<Vec<String>>();
+
+        if legacy_entries.is_empty() {
+            return;
+        }
+
+        info!("Migrating {} files to SHA-256", legacy_entries.len());
+        for old_hash in legacy_entries {
+            let mut file_entry = self.files.remove(&old_hash).unwrap();
+
+            let content_hash = match ContentHasher::hash_blob(self.blob_store.as_ref(), &old_hash) {
+                Ok(content_hash) => content_hash,
+                Err(err) => {
+                    error!("Could not migrate {}: {}", old_hash, err);
+                    self.files.insert(old_hash, file_entry);
+                    continue;
+                }
+            };
+
+            if content_hash.legacy_hash != old_hash {
+                warn!("Content of {} does not match its hash", old_hash);
+            }
+
+            if let Err(err) = self.blob_store.rename(&old_hash, &content_hash.hash) {
+                error!("Could not migrate {}: {}", old_hash, err);
+                self.files.insert(old_hash, file_entry);
+                continue;
+            }
+
+            info!("Migrated {} to {}", old_hash, content_hash.hash);
+            file_entry.hash = content_hash.hash;
+            file_entry.legacy_hash = Some(old_hash);
+            self.files.insert(file_entry.hash.clone(), file_entry);
+        }
+
+        self.mark_dirty();
+    }
+}
+
+/*
+ * State dir
+ * Functions shared by all FileStore implementations. Temporary files of running uploads are
+ * always stored in {path}/tmp, independent of the BlobStore.
+ */
+
+// Creates the state dir and removes temporary files of uploads that were interrupted by a shutdown
+pub fn prepare_state_dir(path: &str) {
+    if let Err(err) = std::fs::create_dir_all(path) {
+        if !std::path::Path::new(path).exists() {
+            panic!("{}", err);
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(std::path::Path::new(path).join("tmp"));
+}
+
+// Returns a new unique path inside the tmp dir
+pub fn new_temp_file_path(path: &str) -> std::io::Result<String> {
+    let temp_dir = std::path::Path::new(path).join("tmp");
+    std::fs::create_dir_all(&temp_dir)?;
+
+    let name = format!(
+        "{}-{}",
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        rand::random::<u32>()
+    );
+    Ok(temp_dir.join(name).to_string_lossy().into_owned())
+}
+
+// Removes one entry for every reported one, entries added after the ping was generated are kept
+fn remove_reported<T: PartialEq>(list: &mut Vec<T>, reported: &[T]) {
+    for entry in reported {
+        if let Some(index) = list.iter().position(|e| e == entry) {
+            list.remove(index);
+        }
+    }
+}
+
+// Stores the time of a RecoverEntry as unix timestamp, like the SQLite FileStore does
+mod timestamp {
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(time.timestamp())
     }
 
-    fn clear_uploaded_hashes(&mut self) {
-        self.new_hashes.clear();
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let timestamp = i64::deserialize(deserializer)?;
+        Utc.timestamp_opt(timestamp, 0)
+            .single()
+            .ok_or_else(|| serde::de::Error::custom("invalid timestamp"))
     }
 }
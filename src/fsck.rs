@@ -0,0 +1,386 @@
+use chrono::{TimeZone, Utc};
+use log::{error, info, warn};
+use std::collections::HashSet;
+
+use crate::app_state::AppState;
+use crate::compression;
+use crate::config;
+use crate::file_store::{FileSource, FileStoreFunc, RecoverEntry};
+use crate::stat_store::{StatStore, StatStoreFunc};
+
+/*
+ * Fsck
+ * Reconciles the FileStore with the contents stored in the BlobStore. Runs on every start before
+ * the services are started, and on demand with the fsck subcommand. It detects
+ * - missing files: a FileEntry exists, but its content is gone
+ * - orphaned files: content exists, but no FileEntry refers to it
+ * - size mismatches: the size of the content differs from the size in the FileEntry
+ * Orphaned files are adopted if their content matches their key, otherwise they are quarantined.
+ * Adopted files are announced to the monitor and distributed, like a new upload, as the node
+ * may have crashed before it did so.
+ * Files with a wrong size are rehashed, so either the size gets corrected or the file quarantined.
+ * Missing files can not be repaired locally, they are reported to the monitor as lost and
+ * recovered from other nodes.
+ */
+
+#[derive(Default)]
+pub struct FsckReport {
+    pub missing: Vec<String>,         // Hashes of files whose content is gone
+    pub orphaned: Vec<String>,        // Keys of contents without FileEntry
+    pub size_mismatches: Vec<String>, // Hashes of files whose content has a different size
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.orphaned.is_empty() && self.size_mismatches.is_empty()
+    }
+}
+
+/* Compare all FileEntries with the contents of the BlobStore, without changing anything.
+ * Fails if the BlobStore can not be listed, so an unreachable BlobStore is never mistaken
+ * for lost files.
+ */
+pub fn check(file_store: &dyn FileStoreFunc) -> std::io::Result<FsckReport> {
+    let blob_store = file_store.blob_store();
+    let keys = blob_store.keys()?.into_iter().collect::<HashSet<String>>();
+    let hashes = file_store.hashes()?;
+
+    let mut report = FsckReport::default();
+    for hash in &hashes {
+        if !keys.contains(hash) {
+            report.missing.push(hash.clone());
+            continue;
+        }
+
+        let file_entry = match file_store.get_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => continue,
+        };
+        if blob_store.size(hash)? != file_entry.disk_size() {
+            report.size_mismatches.push(hash.clone());
+        }
+    }
+
+    let hashes = hashes.into_iter().collect::<HashSet<String>>();
+    report.orphaned = keys
+        .into_iter()
+        .filter(|key| !hashes.contains(key))
+        .collect();
+
+    Ok(report)
+}
+
+/* Repair everything found by check, as far as possible
+ *
+ * report: Result of check
+ * drop_missing: Removes the FileEntries of missing and corrupted files and reports them with the
+ *               next ping. Otherwise they are kept, so the next start finds them missing.
+ * Stops at the first error of the file_store, the remaining problems are found again by the next check.
+ */
+pub fn repair(
+    file_store: &mut dyn FileStoreFunc,
+    report: &FsckReport,
+    drop_missing: bool,
+) -> std::io::Result<()> {
+    let blob_store = file_store.blob_store();
+
+    for key in &report.orphaned {
+        let (content_hash, content) = match compression::identify_blob(blob_store.clone(), key) {
+            Ok(identified) => identified,
+            Err(err) => {
+                error!("Could not read orphaned file {}: {}", key, err);
+                continue;
+            }
+        };
+
+        // Content which does not match its key can not be attributed to any file
+        if *key != content_hash.hash && *key != content_hash.legacy_hash {
+            warn!("Orphaned file {} does not match its hash, quarantined", key);
+            if let Err(err) = blob_store.quarantine(key) {
+                error!("Could not quarantine {}: {}", key, err);
+            }
+            continue;
+        }
+
+        if *key != content_hash.hash {
+            if let Err(err) = blob_store.rename(key, &content_hash.hash) {
+                error!("Could not rename {}: {}", key, err);
+                continue;
+            }
+        }
+
+        // File name and content type are lost, like in a rebuilt state
+        let file_name = content_hash.hash.clone();
+        file_store.insert_file(
+            &content_hash,
+            content,
+            "application/octet-stream",
+            &file_name,
+            FileSource::Unknown,
+        )?;
+        file_store.add_hash_to_uploaded_hashes(&content_hash.hash)?;
+        file_store.insert_file_to_distribute(&content_hash.hash)?;
+        info!("Adopted orphaned file {}", content_hash.hash);
+    }
+
+    for hash in &report.size_mismatches {
+        let file_entry = match file_store.get_file(hash) {
+            Ok(Some(file_entry)) => file_entry,
+            _ => continue,
+        };
+
+        // The encoding of the FileEntry may be wrong as well
+        let intact = compression::identify_blob(blob_store.clone(), hash)
+            .ok()
+            .filter(|(content_hash, _)| content_hash.matches(hash));
+
+        match intact {
+            Some((content_hash, content)) => {
+                file_store.insert_file(
+                    &content_hash,
+                    content,
+                    &file_entry.content_type,
+                    &file_entry.file_name,
+                    file_entry.source.clone(),
+                )?;
+                info!("Corrected size of {} to {} bytes", hash, content.stored_size);
+            }
+            None => {
+                error!("File {} is corrupted", hash);
+                if let Err(err) = blob_store.quarantine(hash) {
+                    error!("Could not quarantine {}: {}", hash, err);
+                } else if drop_missing {
+                    file_store.quarantine_file(hash)?;
+                    recover(file_store, hash)?;
+                }
+            }
+        }
+    }
+
+    if drop_missing {
+        for hash in &report.missing {
+            error!("File {} is lost", hash);
+            file_store.remove_lost_file(hash)?;
+            recover(file_store, hash)?;
+        }
+    }
+
+    file_store.serialize_state();
+    Ok(())
+}
+
+// Check and repair the FileStore of the node during startup
+pub fn reconcile(app_state: &AppState) {
+    let mut file_store = app_state.file_store.write().unwrap();
+    let report = match check(file_store.as_ref()) {
+        Ok(report) => report,
+        Err(err) => {
+            error!("Could not check stored files: {}", err);
+            return;
+        }
+    };
+
+    if report.is_clean() {
+        return;
+    }
+
+    log_report(&report);
+    if let Err(err) = repair(file_store.as_mut(), &report, true) {
+        error!("Could not repair stored files: {}", err);
+    }
+}
+
+/* Entry point of the fsck subcommand, which checks the state dir of a stopped node.
+ * Missing files are only logged, they are dropped and reported to the monitor on the next start.
+ *
+ * args: Arguments after the subcommand, the path of the state dir and optionally --repair
+ */
+pub fn run(args: &[String]) -> std::io::Result<()> {
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "usage: node-app fsck <state dir> [--repair]",
+            ))
+        }
+    };
+    let repair_files = args.iter().any(|arg| arg == "--repair");
+
+    let config = config::parse_config(path);
+    let stats = StatStore::deserialize_state(path);
+    let opened = AppState::open_blob_store(&config, path);
+    let mut file_store =
+        AppState::open_file_store(&config, stats.capacity.value, path, opened.blob_store);
+
+    let report = check(file_store.as_ref())?;
+    log_report(&report);
+    if report.is_clean() {
+        info!("No problems found");
+        return Ok(());
+    }
+
+    if repair_files {
+        repair(file_store.as_mut(), &report, false)?;
+        info!("Missing and corrupted files are reported to the monitor on the next start");
+        return Ok(());
+    }
+
+    info!("Run with --repair to fix the problems");
+    std::process::exit(1);
+}
+
+fn log_report(report: &FsckReport) {
+    info!(
+        "Checked stored files: {} missing, {} orphaned, {} with wrong size",
+        report.missing.len(),
+        report.orphaned.len(),
+        report.size_mismatches.len()
+    );
+    for hash in &report.missing {
+        warn!("Missing: {}", hash);
+    }
+    for key in &report.orphaned {
+        warn!("Orphaned: {}", key);
+    }
+    for hash in &report.size_mismatches {
+        warn!("Wrong size: {}", hash);
+    }
+}
+
+// Enqueue a file to be recovered from another node, like a file found corrupted by the scrub
+fn recover(file_store: &mut dyn FileStoreFunc, hash: &str) -> std::io::Result<()> {
+    file_store.insert_files_to_recover(vec![RecoverEntry {
+        hash: String::from(hash),
+        last_checked: Utc.with_ymd_and_hms(1970, 1, 1, 0, 1, 1).unwrap(),
+        bad_sources: vec![],
+        attempts: 0,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryBlobStore;
+    use crate::compression::StoredContent;
+    use crate::config_store::{ContentHash, ContentHasher};
+    use crate::file_store::FileStore;
+    use std::sync::Arc;
+
+    fn file_store(dir: &tempfile::TempDir) -> FileStore {
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        FileStore::new(1 << 30, dir.path().to_str().unwrap(), blob_store)
+    }
+
+    fn content_hash(content: &[u8]) -> ContentHash {
+        let mut hasher = ContentHasher::new();
+        hasher.input(content);
+        hasher.result()
+    }
+
+    #[test]
+    fn adopted_orphans_are_announced_and_distributed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = file_store(&dir);
+        let blob_store = file_store.blob_store();
+        let orphan = content_hash(b"orphan");
+        blob_store.put(&orphan.hash, b"orphan").unwrap();
+
+        let report = check(&file_store).unwrap();
+        assert_eq!(report.orphaned, vec![orphan.hash.clone()]);
+        repair(&mut file_store, &report, true).unwrap();
+
+        let file_entry = file_store.get_file(&orphan.hash).unwrap().unwrap();
+        assert_eq!(file_entry.size, 6);
+        assert_eq!(
+            file_store.uploaded_hashes().unwrap(),
+            vec![orphan.hash.clone()]
+        );
+        assert_eq!(
+            file_store.next_file_to_distribute().unwrap(),
+            Some(orphan.hash)
+        );
+        assert!(check(&file_store).unwrap().is_clean());
+    }
+
+    // Stores the FileEntry of a content with the given stored size, the content itself is not stored
+    fn insert(file_store: &mut FileStore, content: &[u8], stored_size: u64) -> ContentHash {
+        let hash = content_hash(content);
+        let mut stored = StoredContent::identity(content.len() as u64);
+        stored.stored_size = stored_size;
+        file_store
+            .insert_file(&hash, stored, "text/plain", "file.txt", FileSource::Upload)
+            .unwrap();
+        hash
+    }
+
+    #[test]
+    fn missing_files_are_reported_lost_and_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = file_store(&dir);
+        let missing = insert(&mut file_store, b"missing", 7);
+
+        let report = check(&file_store).unwrap();
+        assert_eq!(report.missing, vec![missing.hash.clone()]);
+
+        // The fsck subcommand keeps them, they are dropped on the next start
+        repair(&mut file_store, &report, false).unwrap();
+        assert!(file_store.get_file(&missing.hash).unwrap().is_some());
+
+        repair(&mut file_store, &report, true).unwrap();
+        assert!(file_store.get_file(&missing.hash).unwrap().is_none());
+        assert_eq!(
+            file_store.lost_hashes().unwrap(),
+            vec![missing.hash.clone()]
+        );
+        let recover = file_store.next_file_to_recover().unwrap().unwrap();
+        assert_eq!(recover.hash, missing.hash);
+        assert!(check(&file_store).unwrap().is_clean());
+    }
+
+    #[test]
+    fn orphans_are_adopted_under_their_hash_or_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = file_store(&dir);
+        let blob_store = file_store.blob_store();
+        let legacy = content_hash(b"legacy");
+        blob_store.put(&legacy.legacy_hash, b"legacy").unwrap();
+        blob_store.put("not-a-hash", b"garbage").unwrap();
+
+        let report = check(&file_store).unwrap();
+        assert_eq!(report.orphaned.len(), 2);
+        repair(&mut file_store, &report, true).unwrap();
+
+        // Contents stored under their SHA-1 hash are moved to their SHA-256 hash
+        assert_eq!(blob_store.keys().unwrap(), vec![legacy.hash.clone()]);
+        let file_entry = file_store.get_file(&legacy.legacy_hash).unwrap().unwrap();
+        assert_eq!(file_entry.hash, legacy.hash);
+        assert!(check(&file_store).unwrap().is_clean());
+    }
+
+    #[test]
+    fn wrong_sizes_are_corrected_or_the_file_is_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = file_store(&dir);
+        let blob_store = file_store.blob_store();
+        let intact = insert(&mut file_store, b"intact", 100);
+        blob_store.put(&intact.hash, b"intact").unwrap();
+        let corrupted = insert(&mut file_store, b"corrupted", 9);
+        blob_store.put(&corrupted.hash, b"trunc").unwrap();
+
+        let report = check(&file_store).unwrap();
+        assert_eq!(report.size_mismatches.len(), 2);
+        repair(&mut file_store, &report, true).unwrap();
+
+        let file_entry = file_store.get_file(&intact.hash).unwrap().unwrap();
+        assert_eq!(file_entry.disk_size(), 6);
+        assert!(file_store.get_file(&corrupted.hash).unwrap().is_none());
+        assert_eq!(
+            file_store.corrupted_hashes().unwrap(),
+            vec![corrupted.hash.clone()]
+        );
+        assert_eq!(blob_store.keys().unwrap(), vec![intact.hash.clone()]);
+        let recover = file_store.next_file_to_recover().unwrap().unwrap();
+        assert_eq!(recover.hash, corrupted.hash);
+    }
+}
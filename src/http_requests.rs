@@ -1,6 +1,7 @@
 use crate::app_state::Ping;
 use crate::config_store::Monitor;
-use crate::server::DownloadResponse;
+use crate::download;
+use crate::server::{HoldResponse, RotateKeyResponse};
 use crate::stat_store::Stats;
 use log::error;
 use serde::{Deserialize, Serialize};
@@ -52,6 +53,7 @@ pub async fn register_on_manager(
 
 #[derive(Deserialize)]
 pub struct LookupMonitorResponse {
+    #[allow(dead_code)]
     pub hash: String,       // Hash of the searched file
     pub node_addr: String,  // Node which stores the file
 }
@@ -80,6 +82,7 @@ pub async fn lookup_hash_on_monitor(
 
 #[derive(Deserialize)]
 pub struct PingResponse {
+    #[allow(dead_code)]
     pub status: String,                 // Not used at the moment
     pub files_to_recover: Vec<String>,  // Array of the files the node should downlaod from other nodes
     pub files_to_delete: Vec<String>,   // Array of the files the node should delete
@@ -103,70 +106,131 @@ pub async fn ping_monitor(ping: &Ping, monitor_addr: &str) -> Result<PingRespons
     }
 }
 
-/* Download the file for the given hash from another monitor
- * Reads the file information and content from response and returns it as a DownloadResponse
+/* Pin or unpin a file on the node running on this machine
+ *
+ * node_addr: Url of the node, only reachable on the loopback address
+ * api_token: Admin token, not needed if the node trusts local requests
+ * hash: Hash of the file
+ * pinned: Whether the file should be pinned
+ */
+pub async fn set_pin_on_node(
+    node_addr: &str,
+    api_token: Option<&str>,
+    hash: &str,
+    pinned: bool,
+) -> Result<HoldResponse, reqwest::Error> {
+    let url = format!("{}/files/{}/pin", node_addr, hash);
+    let request = if pinned {
+        reqwest::Client::new().put(&url)
+    } else {
+        reqwest::Client::new().delete(&url)
+    };
+
+    let response = with_token(request, api_token)
+        .send()
+        .await?
+        .error_for_status()?;
+    response.json::<HoldResponse>().await
+}
+
+/* Set or clear the retention hold of a file on the node running on this machine
+ *
+ * node_addr: Url of the node, only reachable on the loopback address
+ * api_token: Admin token, not needed if the node trusts local requests
+ * hash: Hash of the file
+ * retain_until: Unix timestamp until which the file is kept, None clears the hold
+ */
+pub async fn set_retention_on_node(
+    node_addr: &str,
+    api_token: Option<&str>,
+    hash: &str,
+    retain_until: Option<i64>,
+) -> Result<HoldResponse, reqwest::Error> {
+    let url = format!("{}/files/{}/retention", node_addr, hash);
+    let request = match retain_until {
+        Some(retain_until) => reqwest::Client::new()
+            .put(&url)
+            .json(&serde_json::json!({ "retain_until": retain_until })),
+        None => reqwest::Client::new().delete(&url),
+    };
+
+    let response = with_token(request, api_token)
+        .send()
+        .await?
+        .error_for_status()?;
+    response.json::<HoldResponse>().await
+}
+
+/* Add a new encryption key on the node running on this machine
+ *
+ * node_addr: Url of the node, only reachable on the loopback address
+ * api_token: Admin token, not needed if the node trusts local requests
+ */
+pub async fn rotate_key_on_node(
+    node_addr: &str,
+    api_token: Option<&str>,
+) -> Result<RotateKeyResponse, reqwest::Error> {
+    let url = format!("{}/encryption/rotate", node_addr);
+    let response = with_token(reqwest::Client::new().post(&url), api_token)
+        .send()
+        .await?
+        .error_for_status()?;
+    response.json::<RotateKeyResponse>().await
+}
+
+// Sends the API token as bearer token, if there is one
+fn with_token(request: reqwest::RequestBuilder, api_token: Option<&str>) -> reqwest::RequestBuilder {
+    match api_token {
+        Some(api_token) => request.bearer_auth(api_token),
+        None => request,
+    }
+}
+
+/* Request the file for the given hash from another node
+ * Returns the response as soon as its headers arrived, so the size of the file is known
+ * before its content gets downloaded.
  * 
  * node_addr: Url of the node which helds the file
  * hash: Hash of the file to download
  */
-pub async fn download_from_node(
+pub async fn request_download_from_node(
     node_addr: &str,
     hash: &str,
-) -> Result<DownloadResponse, reqwest::Error> {
+) -> Result<reqwest::Response, reqwest::Error> {
     let url = format!("{}/download/{}", node_addr, hash);
     let response = reqwest::Client::new().get(&url).send().await?;
 
-    match response.error_for_status() {
-        Ok(res) => {
-            // let result = res.json::<DownloadResponse>().await?;
-            // return Ok(result);
-            let headers = res.headers().clone();
-
-            let octet_stream =
-                &reqwest::header::HeaderValue::from_str("application/octet-stream").unwrap();
-            let content_type = headers
-                .get("Content-Type")
-                .or(Some(octet_stream))
-                .unwrap()
-                .to_str()
-                .unwrap();
-
-            let header_value = headers
-                .get("content-disposition")
-                .unwrap()
-                .to_str()
-                .unwrap();
-
-            let content = res.bytes().await.unwrap().to_vec();
-            Ok(DownloadResponse {
-                hash: String::from(hash),
-                content,
-                content_type: String::from(content_type),
-                file_name: get_file_name(header_value),
-            })
-        }
-        Err(err) => Err(err),
-    }
+    response.error_for_status()
 }
 
-// Read the file name from a header of a http response
-fn get_file_name(header_value: &str) -> String {
-    if !header_value.contains("filename") {
-        error!("Header does not contain filename!");
-        return String::from("Does not contain filename");
-    }
+/* Reads the content type and file name of the file from the headers of response, the content
+ * is left to the caller to stream
+ * 
+ * res: Response of request_download_from_node
+ */
+pub fn read_download_info(res: &reqwest::Response) -> (String, String) {
+    let content_type = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream");
 
-    match header_value.split(";").find(|s| s.contains("filename")) {
-        Some(result) => {
-            let parts: Vec<&str> = result.split("=").collect();
-            let mut name = String::from(parts[1]);
-            name.retain(|c| c != '\'');
-            let result = name.trim();
-            return String::from(result);
-        }
+    let header_value = res
+        .headers()
+        .get("content-disposition")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    (String::from(content_type), get_file_name(header_value))
+}
+
+// Read the file name from the Content-Disposition header of a http response, quoted or RFC 5987 encoded
+fn get_file_name(header_value: &str) -> String {
+    match download::disposition_file_name(header_value) {
+        Some(file_name) => file_name,
         None => {
             error!("Could not extract filename from header");
-            return String::from("unknown");
+            String::from("unknown")
         }
     }
 }
@@ -0,0 +1,121 @@
+use log::{error, info};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::app_state::AppState;
+use crate::blob_store::BlobStoreFunc;
+use crate::encrypted_blob_store::EncryptedBlobStore;
+
+/*
+ * KeyRotationService
+ * Re-encrypts stored files with the current key after a key rotation, and encrypts files stored
+ * before encryption was enabled. It runs once after the start and after every rotation, files
+ * which fail are retried on the next check. Does nothing if encryption is disabled.
+ *
+ * timeout: The amount of time in seconds between two checks for a pending rotation
+ */
+
+pub struct KeyRotationService {
+    pub app_state: Arc<AppState>,
+    pub timeout: u64,
+}
+
+impl KeyRotationService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Key rotation service started");
+            let stop_services = self.app_state.stop_services.clone();
+
+            loop {
+                if let Some(encryption) = &self.app_state.encryption {
+                    if encryption.take_rotation_pending() {
+                        self.reencrypt(encryption.clone()).await;
+                    }
+                }
+
+                // If flag is set, exit thread
+                if stop_services.load(Ordering::Relaxed) {
+                    info!("Shutting down key rotation service");
+                    break;
+                }
+
+                tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        info!("Key rotation service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, timeout: u64) -> KeyRotationService {
+        KeyRotationService { app_state, timeout }
+    }
+
+    // Re-encrypt every stored file which is plain or uses an old key, one file at a time
+    async fn reencrypt(&self, encryption: Arc<EncryptedBlobStore>) {
+        let keys = {
+            let encryption = encryption.clone();
+            tokio::task::spawn_blocking(move || encryption.keys())
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+        };
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("Could not list stored files: {}", err);
+                encryption.retry_rotation();
+                return;
+            }
+        };
+
+        let mut reencrypted = 0;
+        for key in keys {
+            if self.app_state.stop_services.load(Ordering::Relaxed) {
+                encryption.retry_rotation();
+                return;
+            }
+
+            // Files removed in the meantime must not be written again
+            match self.app_state.file_store.read().unwrap().get_file(&key) {
+                Ok(Some(_)) => {}
+                Ok(None) => continue,
+                Err(_) => {
+                    encryption.retry_rotation();
+                    return;
+                }
+            }
+
+            let result = {
+                let encryption = encryption.clone();
+                let key = key.clone();
+                tokio::task::spawn_blocking(move || {
+                    if !encryption.needs_reencryption(&key)? {
+                        return Ok(false);
+                    }
+                    encryption.reencrypt(&key).map(|_| true)
+                })
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+            };
+
+            match result {
+                Ok(true) => reencrypted += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    error!("Could not re-encrypt {}: {}", key, err);
+                    encryption.retry_rotation();
+                }
+            }
+        }
+
+        if reencrypted > 0 {
+            info!(
+                "Re-encrypted {} files with key {}",
+                reencrypted,
+                encryption.current_key_id()
+            );
+        }
+    }
+}
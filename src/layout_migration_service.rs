@@ -0,0 +1,108 @@
+use log::{error, info};
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::app_state::AppState;
+use crate::blob_store::BlobStoreFunc;
+
+/*
+ * LayoutMigrationService
+ * Moves the stored contents to their place in the directory layout configured in config.json,
+ * after shard_levels of the local BlobStore changed. It runs once after the start, while the
+ * node keeps serving files. Contents which were not moved yet stay readable, see LocalBlobStore.
+ *
+ * batch_size: The number of contents moved in one blocking task
+ */
+
+pub struct LayoutMigrationService {
+    pub app_state: Arc<AppState>,
+    pub batch_size: usize,
+}
+
+impl LayoutMigrationService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Layout migration service started");
+            self.migrate().await;
+        })
+        .await
+        .unwrap();
+
+        info!("Layout migration service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, batch_size: usize) -> LayoutMigrationService {
+        LayoutMigrationService {
+            app_state,
+            batch_size,
+        }
+    }
+
+    // Relocate all stored contents in batches, stops early if the node shuts down
+    async fn migrate(&self) {
+        let blob_store = self.app_state.file_store.read().unwrap().blob_store();
+        let keys = {
+            let blob_store = blob_store.clone();
+            tokio::task::spawn_blocking(move || blob_store.keys())
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+        };
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("Could not list stored files: {}", err);
+                return;
+            }
+        };
+
+        let mut moved = 0;
+        for batch in keys.chunks(self.batch_size.max(1)) {
+            if self.app_state.stop_services.load(Ordering::Relaxed) {
+                info!(
+                    "Moved {} files before shutdown, continuing on next start",
+                    moved
+                );
+                return;
+            }
+
+            let blob_store = blob_store.clone();
+            let batch = batch.to_vec();
+            let relocated =
+                tokio::task::spawn_blocking(move || relocate_batch(blob_store.as_ref(), &batch))
+                    .await;
+            let (batch_moved, errors) = match relocated {
+                Ok(relocated) => relocated,
+                Err(err) => {
+                    error!("Could not move stored files: {}", err);
+                    return;
+                }
+            };
+
+            moved += batch_moved;
+            for (key, err) in errors {
+                error!("Could not move {} to its new place: {}", key, err);
+            }
+        }
+
+        if moved > 0 {
+            info!("Moved {} files to the configured layout", moved);
+        }
+    }
+}
+
+// Returns the number of moved contents and the errors of contents which could not be moved
+fn relocate_batch(
+    blob_store: &dyn BlobStoreFunc,
+    keys: &[String],
+) -> (usize, Vec<(String, std::io::Error)>) {
+    let mut moved = 0;
+    let mut errors = vec![];
+    for key in keys {
+        match blob_store.relocate(key) {
+            Ok(true) => moved += 1,
+            Ok(false) => {}
+            Err(err) => errors.push((key.clone(), err)),
+        }
+    }
+    (moved, errors)
+}
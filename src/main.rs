@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return, clippy::new_ret_no_self)]
+
 extern crate async_trait;
 extern crate crypto;
 extern crate ctrlc;
@@ -8,34 +10,73 @@ extern crate rusqlite;
 extern crate serde;
 
 mod app_state;
+mod blob_store;
+mod cli;
+mod compression;
 mod config;
 mod config_store;
 mod distribution_service;
+mod download;
+mod encrypted_blob_store;
+mod encryption;
+mod eviction;
+mod file_query;
 mod file_store;
+mod fsck;
 mod http_requests;
+mod key_rotation_service;
+mod layout_migration_service;
+mod multi_dir_blob_store;
 mod ping_service;
+mod placement;
 mod recover_service;
+mod s3_blob_store;
+mod scrub_service;
 mod server;
+mod sqlite_file_store;
 mod stat_store;
+mod storage_health_service;
+mod state_file;
+mod token_store;
+mod trash_service;
+mod upload_expiry_service;
+mod upload_store;
 
 use app_state::AppState;
 use config_store::ConfigStoreFunc;
 use distribution_service::DistributionService;
 use fern::colors::{Color, ColoredLevelConfig};
 use http_requests::{register_on_manager, RegisterResponse};
+use key_rotation_service::KeyRotationService;
+use layout_migration_service::LayoutMigrationService;
 use log::info;
 use ping_service::PingService;
 use recover_service::RecoverService;
+use scrub_service::ScrubService;
 use stat_store::StatStoreFunc;
 use std::env;
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
+use storage_health_service::StorageHealthService;
 use tokio::sync::oneshot;
+use trash_service::TrashService;
+use upload_expiry_service::UploadExpiryService;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     setup_logger();
 
+    // Check the stored files of a stopped node, instead of starting it
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("fsck") {
+        return fsck::run(&args[2..]);
+    }
+
+    // Change the holds of a file, rotate the encryption key or manage the API tokens on the running node
+    if let Some(command) = args.get(1).filter(|command| cli::is_command(command)) {
+        return cli::run(command, &args[2..]).await;
+    }
+
     // Set stop_services flag to true when terminating with ctrl+c
     let stop_services: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     setup_close_handler(stop_services.clone(), shutdown_tx);
@@ -43,10 +84,11 @@ async fn main() -> std::io::Result<()> {
     let force_ping = Arc::new(AtomicBool::new(true));
 
     // Read config and stats from file
-    let args: Vec<String> = env::args().collect();
     let state_path: &String = &args[1].parse::<String>().unwrap();
     let config_from_file = config::parse_config(state_path);
     let stats = stat_store::StatStore::deserialize_state(state_path);
+    let scrub_interval = config_from_file.scrub_interval.unwrap_or(24 * 60 * 60);
+    let scrub_rate = config_from_file.scrub_rate.unwrap_or(10 * 1024 * 1024).max(1);
 
     // Register on manager
     let register_response = run_registration(
@@ -72,25 +114,51 @@ async fn main() -> std::io::Result<()> {
         stop_services.clone(),
         force_ping.clone(),
         state_path,
-    ));
+    )?);
     info!(
         "Region: {}",
         app_state.stat_store.read().unwrap().stats.region
     );
 
+    // Repair the FileStore before any file gets served
+    fsck::reconcile(&app_state);
+
     //  Create background services
     let ping_service = PingService::new(app_state.clone(), 30);
     let recover_service = RecoverService::new(app_state.clone(), 10);
     let distribution_service = DistributionService::new(app_state.clone(), 10);
+    let scrub_service = ScrubService::new(app_state.clone(), scrub_interval, scrub_rate);
+    let trash_service = TrashService::new(app_state.clone(), 10);
+    let key_rotation_service = KeyRotationService::new(app_state.clone(), 10);
+    let layout_migration_service = LayoutMigrationService::new(app_state.clone(), 1000);
+    let storage_health_service = StorageHealthService::new(app_state.clone(), 30);
+    let upload_expiry_service = UploadExpiryService::new(app_state.clone(), 60);
 
     // Start background services
     let server_fut = server::start_server(app_state.clone(), shutdown_rx);
     let ping_fut = ping_service.start();
     let recover_fut = recover_service.start();
     let distribution_fut = distribution_service.start();
+    let scrub_fut = scrub_service.start();
+    let trash_fut = trash_service.start();
+    let key_rotation_fut = key_rotation_service.start();
+    let layout_migration_fut = layout_migration_service.start();
+    let storage_health_fut = storage_health_service.start();
+    let upload_expiry_fut = upload_expiry_service.start();
 
     info!("Services started");
-    let _ = tokio::try_join!(server_fut, ping_fut, recover_fut, distribution_fut);
+    let _ = tokio::try_join!(
+        server_fut,
+        ping_fut,
+        recover_fut,
+        distribution_fut,
+        scrub_fut,
+        trash_fut,
+        key_rotation_fut,
+        layout_migration_fut,
+        storage_health_fut,
+        upload_expiry_fut
+    );
 
     info!("Sending shutdown signal");
     let fingerprint = app_state.config_store.read().unwrap().fingerprint();
@@ -109,7 +177,7 @@ fn setup_logger() {
         .info(Color::White)
         .debug(Color::White)
         .trace(Color::BrightBlack);
-    let colors_level = colors_line.clone().info(Color::Green);
+    let colors_level = colors_line.info(Color::Green);
 
     fern::Dispatch::new()
         .chain(std::io::stdout())
@@ -143,8 +211,8 @@ async fn run_registration(
     stats: &stat_store::Stats,
     ipv6: Option<String>,
 ) -> RegisterResponse {
-    let register_request = http_requests::RegisterRequest::from_stats(&stats, ipv6);
-    let response = register_on_manager(&manager_addr, register_request).await;
+    let register_request = http_requests::RegisterRequest::from_stats(stats, ipv6);
+    let response = register_on_manager(manager_addr, register_request).await;
     match response {
         Ok(result) => result,
         Err(err) => panic!("{}", err),
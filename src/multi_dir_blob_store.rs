@@ -0,0 +1,524 @@
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use crate::blob_store::{self, BlobStoreFunc, LocalBlobStore};
+use crate::config::LocalConfig;
+use crate::placement::{self, Candidate, PlacementPolicyFunc};
+
+/*
+ * MultiDirBlobStore
+ * Spreads the contents over several data dirs, usually on separate disks, each with its own
+ * capacity. Every data dir is a LocalBlobStore, the placement policy picks the dir of a new
+ * content. The capacity of the node is the sum of all healthy data dirs. A data dir never takes
+ * more than its capacity, or than the free space on its disk allows.
+ *
+ * A data dir fails if it can not be written during the regular health check, or if an
+ * operation on it fails with an I/O error. A failed dir is taken out: its contents are no longer
+ * listed, so they are reported lost and recovered from other nodes, and no new contents are
+ * placed on it. A failed dir which passes the health check again is taken back in, its contents
+ * are adopted like orphaned files.
+ */
+
+const PROBE_NAME: &str = ".probe"; // File written by the health check in every data dir
+const PROBE_CONTENT: &[u8] = b"peerdata health check";
+
+struct DataDir {
+    path: String,          // Path of the data dir
+    capacity: u64,         // Space in bytes the dir may use for files
+    store: LocalBlobStore, // Stores the contents in the dir
+    healthy: AtomicBool,   // Unset while the dir is taken out
+}
+
+// Data dir and size of a content
+#[derive(Clone, Copy)]
+struct Location {
+    dir: usize,
+    size: u64,
+}
+
+// Contents of all healthy data dirs
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, Location>, // Data dir of every content
+    used: Vec<u64>,                   // Space used by the contents of every data dir
+}
+
+// State of a data dir, as returned by GET /storage
+#[derive(Serialize)]
+pub struct DataDirStatus {
+    pub path: String,
+    pub capacity: u64, // Space in bytes the dir may use for files
+    pub used: u64,     // Space used by the stored files
+    pub free: u64,     // Space left for files, limited by the capacity and the free space on disk
+    pub files: usize,  // Number of stored files
+    pub healthy: bool, // False if the dir was taken out after an error
+}
+
+pub struct MultiDirBlobStore {
+    dirs: Vec<DataDir>,                      // All data dirs in the order of config.json
+    placement: Box<dyn PlacementPolicyFunc>, // Picks the data dir of new contents
+    index: RwLock<Index>,                    // Contents of all healthy data dirs
+    changed: AtomicBool,                     // Set when a data dir was taken out or back in
+}
+
+impl MultiDirBlobStore {
+    // Indexes the contents of all data dirs, dirs which can not be written start out failed
+    pub fn new(config: &LocalConfig, min_free_space: u64) -> MultiDirBlobStore {
+        let data_dirs = config.data_dirs.clone().unwrap_or_default();
+        if data_dirs.is_empty() {
+            panic!("data_dirs must contain at least one data dir");
+        }
+
+        let shard_levels = config.shard_levels.unwrap_or(0);
+        let dirs = data_dirs
+            .into_iter()
+            .map(|data_dir| DataDir {
+                store: LocalBlobStore::new(&data_dir.path, shard_levels, min_free_space),
+                path: data_dir.path,
+                capacity: data_dir.capacity,
+                healthy: AtomicBool::new(false),
+            })
+            .collect::<Vec<DataDir>>();
+
+        let multi_dir_store = MultiDirBlobStore {
+            index: RwLock::new(Index {
+                files: HashMap::new(),
+                used: vec![0; dirs.len()],
+            }),
+            dirs,
+            placement: placement::from_config(config.placement.as_deref()),
+            changed: AtomicBool::new(false),
+        };
+        multi_dir_store.check_health();
+        multi_dir_store.changed.store(false, Ordering::Relaxed);
+
+        info!(
+            "Storing files in {} data dirs, placement {}",
+            multi_dir_store.dirs.len(),
+            multi_dir_store.placement.name()
+        );
+        multi_dir_store
+    }
+
+    /* Writes a probe file to every data dir. Healthy dirs which fail are taken out, failed dirs
+     * which pass are indexed and taken back in.
+     */
+    pub fn check_health(&self) {
+        for (dir, data_dir) in self.dirs.iter().enumerate() {
+            let probed = probe(&data_dir.path);
+            let healthy = data_dir.healthy.load(Ordering::Relaxed);
+            match probed {
+                Err(err) if healthy => self.fail(dir, &err),
+                Err(err) => warn!("Data dir {} is still failed: {}", data_dir.path, err),
+                Ok(()) if !healthy => self.take_in(dir),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    // Returns true once after a data dir was taken out or back in, so the files can be reconciled
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> Vec<DataDirStatus> {
+        let index = self.index.read().unwrap();
+        self.dirs
+            .iter()
+            .enumerate()
+            .map(|(dir, data_dir)| DataDirStatus {
+                path: data_dir.path.clone(),
+                capacity: data_dir.capacity,
+                used: index.used[dir],
+                free: self.free(dir, &index),
+                files: index
+                    .files
+                    .values()
+                    .filter(|location| location.dir == dir)
+                    .count(),
+                healthy: data_dir.healthy.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    // Indexes the contents of a data dir and takes it in
+    fn take_in(&self, dir: usize) {
+        let data_dir = &self.dirs[dir];
+        let keys = match data_dir.store.keys() {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("Could not list files in {}: {}", data_dir.path, err);
+                return;
+            }
+        };
+
+        let mut index = self.index.write().unwrap();
+        for key in keys {
+            if let Some(location) = index.files.get(&key) {
+                let other = &self.dirs[location.dir].path;
+                warn!(
+                    "{} is stored in {} and {}, ignoring the latter",
+                    key, other, data_dir.path
+                );
+                continue;
+            }
+
+            match data_dir.store.size(&key) {
+                Ok(size) => {
+                    index.files.insert(key, Location { dir, size });
+                    index.used[dir] += size;
+                }
+                Err(err) => error!("Could not read {} in {}: {}", key, data_dir.path, err),
+            }
+        }
+
+        data_dir.healthy.store(true, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Relaxed);
+        info!(
+            "Data dir {} is healthy, {} bytes used",
+            data_dir.path, index.used[dir]
+        );
+    }
+
+    // Takes a data dir out, its contents are treated as lost
+    fn fail(&self, dir: usize, err: &Error) {
+        let data_dir = &self.dirs[dir];
+        if !data_dir.healthy.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        error!("Data dir {} failed, taking it out: {}", data_dir.path, err);
+
+        let mut index = self.index.write().unwrap();
+        index.files.retain(|_, location| location.dir != dir);
+        index.used[dir] = 0;
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    // Takes the data dir out if the result is an I/O error, errors of single contents are passed on
+    fn observe<T>(&self, dir: usize, result: std::io::Result<T>) -> std::io::Result<T> {
+        if let Err(err) = &result {
+            if is_failure(err) {
+                self.fail(dir, err);
+            }
+        }
+        result
+    }
+
+    fn locate(&self, key: &str) -> std::io::Result<Location> {
+        self.index
+            .read()
+            .unwrap()
+            .files
+            .get(key)
+            .copied()
+            .ok_or_else(|| blob_store::not_found(key))
+    }
+
+    // Returns the data dir of an existing content, or the one the placement policy picks
+    fn place(&self, key: &str, size: u64) -> std::io::Result<usize> {
+        let index = self.index.read().unwrap();
+        if let Some(location) = index.files.get(key) {
+            return Ok(location.dir);
+        }
+
+        let candidates = self
+            .healthy_dirs()
+            .map(|dir| Candidate {
+                dir,
+                free: self.free(dir, &index),
+            })
+            .filter(|candidate| candidate.free >= size)
+            .collect::<Vec<Candidate>>();
+
+        self.placement.choose(&candidates).ok_or_else(|| {
+            Error::new(
+                ErrorKind::StorageFull,
+                format!("no data dir has {} bytes left", size),
+            )
+        })
+    }
+
+    fn healthy_dirs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.dirs.len()).filter(move |dir| self.dirs[*dir].healthy.load(Ordering::Relaxed))
+    }
+
+    // Space left in a data dir, the disk may be fuller than the capacity suggests
+    fn free(&self, dir: usize, index: &Index) -> u64 {
+        let data_dir = &self.dirs[dir];
+        let free = data_dir.capacity.saturating_sub(index.used[dir]);
+        match data_dir.store.free_space() {
+            Some(free_space) => free.min(free_space),
+            None => free,
+        }
+    }
+
+    fn insert(&self, key: &str, dir: usize, size: u64) {
+        let mut index = self.index.write().unwrap();
+        let location = Location { dir, size };
+        if let Some(replaced) = index.files.insert(String::from(key), location) {
+            index.used[replaced.dir] = index.used[replaced.dir].saturating_sub(replaced.size);
+        }
+        index.used[dir] += size;
+    }
+
+    fn forget(&self, key: &str) {
+        let mut index = self.index.write().unwrap();
+        if let Some(location) = index.files.remove(key) {
+            index.used[location.dir] = index.used[location.dir].saturating_sub(location.size);
+        }
+    }
+
+    /* Runs op on the trash of every healthy data dir, until one holds the content
+     *
+     * op: Operation on the store of a data dir, fails with NotFound if the content is not there
+     */
+    fn find_in_trash(
+        &self,
+        key: &str,
+        op: impl Fn(&LocalBlobStore) -> std::io::Result<()>,
+    ) -> std::io::Result<usize> {
+        for (dir, data_dir) in self.dirs.iter().enumerate() {
+            if !data_dir.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            match self.observe(dir, op(&data_dir.store)) {
+                Ok(()) => return Ok(dir),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(blob_store::not_found(key))
+    }
+}
+
+impl BlobStoreFunc for MultiDirBlobStore {
+    fn put(&self, key: &str, content: &[u8]) -> std::io::Result<()> {
+        let dir = self.place(key, content.len() as u64)?;
+        self.observe(dir, self.dirs[dir].store.put(key, content))?;
+        self.insert(key, dir, content.len() as u64);
+        Ok(())
+    }
+
+    fn put_file(&self, key: &str, temp_path: &str) -> std::io::Result<()> {
+        let size = std::fs::metadata(temp_path)?.len();
+        let dir = self.place(key, size)?;
+        self.observe(dir, self.dirs[dir].store.put_file(key, temp_path))?;
+        self.insert(key, dir, size);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let location = self.locate(key)?;
+        self.observe(location.dir, self.dirs[location.dir].store.get(key))
+    }
+
+    fn get_range(&self, key: &str, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let location = self.locate(key)?;
+        let store = &self.dirs[location.dir].store;
+        self.observe(location.dir, store.get_range(key, start, len))
+    }
+
+    fn size(&self, key: &str) -> std::io::Result<u64> {
+        let location = self.locate(key)?;
+        self.observe(location.dir, self.dirs[location.dir].store.size(key))
+    }
+
+    fn remove(&self, key: &str) -> std::io::Result<()> {
+        let location = self.locate(key)?;
+        match self.observe(location.dir, self.dirs[location.dir].store.remove(key)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            result => {
+                self.forget(key);
+                result
+            }
+        }
+    }
+
+    fn quarantine(&self, key: &str) -> std::io::Result<()> {
+        let location = match self.locate(key) {
+            Ok(location) => location,
+            Err(_) => return Ok(()),
+        };
+        let store = &self.dirs[location.dir].store;
+        self.observe(location.dir, store.quarantine(key))?;
+        self.forget(key);
+        Ok(())
+    }
+
+    fn trash(&self, key: &str) -> std::io::Result<()> {
+        let location = match self.locate(key) {
+            Ok(location) => location,
+            Err(_) => return Ok(()),
+        };
+        self.observe(location.dir, self.dirs[location.dir].store.trash(key))?;
+        self.forget(key);
+        Ok(())
+    }
+
+    fn restore(&self, key: &str) -> std::io::Result<()> {
+        let dir = self.find_in_trash(key, |store| store.restore(key))?;
+        let size = self.observe(dir, self.dirs[dir].store.size(key))?;
+        self.insert(key, dir, size);
+        Ok(())
+    }
+
+    fn purge(&self, key: &str) -> std::io::Result<()> {
+        self.find_in_trash(key, |store| store.purge(key))?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let location = self.locate(from)?;
+        let store = &self.dirs[location.dir].store;
+        self.observe(location.dir, store.rename(from, to))?;
+        self.forget(from);
+        self.insert(to, location.dir, location.size);
+        Ok(())
+    }
+
+    fn relocate(&self, key: &str) -> std::io::Result<bool> {
+        let location = self.locate(key)?;
+        self.observe(location.dir, self.dirs[location.dir].store.relocate(key))
+    }
+
+    fn keys(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.index.read().unwrap().files.keys().cloned().collect())
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some(self.healthy_dirs().map(|dir| self.dirs[dir].capacity).sum())
+    }
+
+    fn free_space(&self) -> Option<u64> {
+        let index = self.index.read().unwrap();
+        Some(self.healthy_dirs().map(|dir| self.free(dir, &index)).sum())
+    }
+
+    fn chunk_size(&self) -> u64 {
+        64 * 1024
+    }
+}
+
+// Errors of missing contents or exhausted space say nothing about the health of the disk
+fn is_failure(err: &Error) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::AlreadyExists
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::InvalidInput
+            | ErrorKind::StorageFull
+    )
+}
+
+// Writes, reads and removes a small file in the data dir, the files dir is created if needed
+fn probe(path: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(format!("{}/files", path))?;
+
+    let probe_path = format!("{}/{}", path, PROBE_NAME);
+    std::fs::write(&probe_path, PROBE_CONTENT)?;
+    let content = std::fs::read(&probe_path)?;
+    std::fs::remove_file(&probe_path)?;
+
+    if content != PROBE_CONTENT {
+        return Err(Error::new(ErrorKind::InvalidData, "probe file was altered"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::tests::check_blob_store;
+    use crate::config::DataDirConfig;
+
+    // Opens a store on new data dirs with the given capacities
+    fn open(
+        root: &tempfile::TempDir,
+        capacities: &[u64],
+        placement: &str,
+    ) -> (MultiDirBlobStore, Vec<std::path::PathBuf>) {
+        let paths = (0..capacities.len())
+            .map(|dir| root.path().join(format!("data{}", dir)))
+            .collect::<Vec<std::path::PathBuf>>();
+        let config = LocalConfig {
+            shard_levels: None,
+            data_dirs: Some(
+                paths
+                    .iter()
+                    .zip(capacities)
+                    .map(|(path, &capacity)| DataDirConfig {
+                        path: String::from(path.to_str().unwrap()),
+                        capacity,
+                    })
+                    .collect(),
+            ),
+            placement: Some(String::from(placement)),
+        };
+        (MultiDirBlobStore::new(&config, 0), paths)
+    }
+
+    #[test]
+    fn multi_dir_blob_store() {
+        let root = tempfile::tempdir().unwrap();
+        let (store, _) = open(&root, &[1 << 20, 1 << 20], "round_robin");
+        check_blob_store(&store, "", root.path());
+    }
+
+    #[test]
+    fn places_files_in_dirs_with_space_left() {
+        let root = tempfile::tempdir().unwrap();
+        let (store, paths) = open(&root, &[100, 1000], "most_free");
+
+        store.put("large", &[0; 950]).unwrap();
+        assert!(paths[1].join("files/large").is_file());
+
+        // Only the first dir has room left for it
+        store.put("next", &[0; 80]).unwrap();
+        assert!(paths[0].join("files/next").is_file());
+
+        let err = store.put("too large", &[0; 60]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(store.capacity(), Some(1100));
+        assert_eq!(store.free_space(), Some(20 + 50));
+    }
+
+    #[test]
+    fn failed_dirs_are_taken_out_and_back_in() {
+        let root = tempfile::tempdir().unwrap();
+        let (store, paths) = open(&root, &[1000, 1000], "round_robin");
+        store.put("first", b"first").unwrap();
+        assert!(paths[0].join("files/first").is_file());
+
+        // A dir which can not be written fails the health check
+        let moved = root.path().join("moved");
+        std::fs::rename(&paths[0], &moved).unwrap();
+        std::fs::write(&paths[0], b"not a dir").unwrap();
+        store.check_health();
+        assert!(store.take_changed());
+        assert!(!store.status()[0].healthy);
+        assert_eq!(store.keys().unwrap(), Vec::<String>::new());
+        assert_eq!(store.capacity(), Some(1000));
+
+        // New files go to the remaining dir only
+        store.put("second", b"second").unwrap();
+        store.put("third", b"third").unwrap();
+        assert!(paths[1].join("files/second").is_file());
+        assert!(paths[1].join("files/third").is_file());
+
+        // Once it is healthy again, its files are indexed again
+        std::fs::remove_file(&paths[0]).unwrap();
+        std::fs::rename(&moved, &paths[0]).unwrap();
+        store.check_health();
+        assert!(store.take_changed());
+        assert!(store.status()[0].healthy);
+        let mut keys = store.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["first", "second", "third"]);
+        assert_eq!(store.get("first").unwrap(), b"first");
+    }
+}
@@ -5,7 +5,7 @@ use std::time::Duration;
 
 use crate::app_state::AppState;
 use crate::config_store::ConfigStoreFunc;
-use crate::file_store::{FileStoreFunc, RecoverEntry};
+use crate::file_store::RecoverEntry;
 use crate::http_requests::{ping_monitor, PingResponse};
 use crate::stat_store::StatStoreFunc;
 
@@ -46,7 +46,8 @@ impl PingService {
                     let _ = PingService::ping_monitor(self.app_state.clone()).await;
 
                     // save current state to disk, in case the node get killed unexpectedly
-                    self.app_state.serialize_state();
+                    let app_state = self.app_state.clone();
+                    let _ = tokio::task::spawn_blocking(move || app_state.serialize_state()).await;
 
                     // Reset flag
                     force_ping.swap(false, Ordering::Relaxed);
@@ -61,7 +62,7 @@ impl PingService {
                     }
 
                     // Otherwise send thread to sleep
-                    std::thread::sleep(Duration::from_secs(1));
+                    tokio::time::delay_for(Duration::from_secs(1)).await;
                 }
             }
         })
@@ -79,10 +80,19 @@ impl PingService {
 
     // Generates new ping and send it to the monitor
     async fn ping_monitor(app_state: Arc<AppState>) {
-        let ping = app_state.generate_ping();
+        let ping = match app_state.generate_ping() {
+            Ok(ping) => ping,
+            Err(err) => {
+                error!("Skipping ping, the stored files can not be listed: {}", err);
+                return;
+            }
+        };
         let monitor = app_state.config_store.read().unwrap().monitor();
         match ping_monitor(&ping, &monitor.addr).await {
             Ok(ping_response) => {
+                if let Err(err) = app_state.clear_reported(&ping) {
+                    error!("Could not clear the entries sent with the ping: {}", err);
+                }
                 PingService::handle_request_success(app_state.clone(), ping_response);
             }
             Err(err) => {
@@ -92,33 +102,38 @@ impl PingService {
     }
 
     fn handle_request_success(app_state: Arc<AppState>, ping_response: PingResponse) {
-        let dt = Utc.ymd(1970, 1, 1).and_hms(0, 1, 1);
+        let dt = Utc.with_ymd_and_hms(1970, 1, 1, 0, 1, 1).unwrap();
         // Create FileEntry for every hash in response which needs to be recovered
         let entries = ping_response
             .files_to_recover
             .into_iter()
             .map(|f| RecoverEntry {
                 hash: f,
-                last_checked: dt.clone(),
+                last_checked: dt,
+                bad_sources: vec![],
+                attempts: 0,
             })
             .collect::<Vec<RecoverEntry>>();
 
-        if entries.len() > 0 {
+        if !entries.is_empty() {
             info!("Files to sync {:?}", entries);
         }
 
         // Insert hashes into AppState
-        app_state
+        if let Err(err) = app_state
             .file_store
             .write()
             .unwrap()
-            .insert_files_to_recover(entries);
+            .insert_files_to_recover(entries)
+        {
+            error!("Could not enqueue the files to sync: {}", err);
+        }
 
         // Remove of all hashes which needs to be deleted
         ping_response
             .files_to_delete
             .iter()
-            .for_each(|hash| app_state.file_store.write().unwrap().remove_file(hash));
+            .for_each(|hash| app_state.remove_file_on_request(hash));
     }
 
     // Output error message
@@ -136,7 +151,7 @@ impl PingService {
         } else if error.is_timeout() {
             error!("Request timeout");
         } else {
-            error!("Unknown error: {}", error.to_string());
+            error!("Unknown error: {}", error);
         }
     }
 }
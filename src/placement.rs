@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Placement
+ * Decides which data dir stores a new file, when the node keeps its files in several data dirs,
+ * see MultiDirBlobStore. Only healthy data dirs with enough space left are offered.
+ */
+
+// Data dir which could store a new file
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+    pub dir: usize, // Position of the data dir in config.json
+    pub free: u64,  // Space left in the data dir
+}
+
+pub trait PlacementPolicyFunc: Send + Sync {
+    fn name(&self) -> &str;                                      // Name of the policy in config.json
+    fn choose(&self, candidates: &[Candidate]) -> Option<usize>; // Returns the data dir for the new file, candidates are ordered by dir
+}
+
+// Creates the placement policy configured in config.json, most free space by default
+pub fn from_config(name: Option<&str>) -> Box<dyn PlacementPolicyFunc> {
+    match name {
+        None | Some("most_free") => Box::new(MostFreeSpace),
+        Some("round_robin") => Box::new(RoundRobin::default()),
+        Some(other) => panic!("Unknown placement {}", other),
+    }
+}
+
+// Stores the file in the data dir with the most space left, so all dirs fill up evenly
+pub struct MostFreeSpace;
+
+impl PlacementPolicyFunc for MostFreeSpace {
+    fn name(&self) -> &str {
+        "most_free"
+    }
+
+    fn choose(&self, candidates: &[Candidate]) -> Option<usize> {
+        candidates
+            .iter()
+            .max_by_key(|candidate| candidate.free)
+            .map(|candidate| candidate.dir)
+    }
+}
+
+// Stores the files in the data dirs in turn, skipping dirs which are full or failed
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize, // Data dir which stores the next file, if it is a candidate
+}
+
+impl PlacementPolicyFunc for RoundRobin {
+    fn name(&self) -> &str {
+        "round_robin"
+    }
+
+    fn choose(&self, candidates: &[Candidate]) -> Option<usize> {
+        let next = self.next.load(Ordering::Relaxed);
+        let chosen = candidates
+            .iter()
+            .find(|candidate| candidate.dir >= next)
+            .or_else(|| candidates.first())?
+            .dir;
+        self.next.store(chosen + 1, Ordering::Relaxed);
+        Some(chosen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(free: &[(usize, u64)]) -> Vec<Candidate> {
+        free.iter()
+            .map(|&(dir, free)| Candidate { dir, free })
+            .collect()
+    }
+
+    #[test]
+    fn most_free_space_takes_the_emptiest_dir() {
+        let policy = MostFreeSpace;
+        assert_eq!(
+            policy.choose(&candidates(&[(0, 10), (1, 30), (2, 20)])),
+            Some(1)
+        );
+        assert_eq!(policy.choose(&candidates(&[(0, 10), (2, 20)])), Some(2));
+        assert_eq!(policy.choose(&[]), None);
+    }
+
+    #[test]
+    fn round_robin_skips_dirs_which_are_no_candidates() {
+        let policy = RoundRobin::default();
+        let all = candidates(&[(0, 10), (1, 10), (2, 10)]);
+        assert_eq!(policy.choose(&all), Some(0));
+        assert_eq!(policy.choose(&all), Some(1));
+
+        // Dir 2 is full or failed, the turn wraps around to the first dir
+        let without_last = candidates(&[(0, 10), (1, 10)]);
+        assert_eq!(policy.choose(&without_last), Some(0));
+        assert_eq!(policy.choose(&candidates(&[(0, 10), (2, 10)])), Some(2));
+        assert_eq!(policy.choose(&all), Some(0));
+        assert_eq!(policy.choose(&[]), None);
+    }
+}
@@ -1,16 +1,22 @@
 use log::{error, info};
+use std::io::ErrorKind;
 use std::sync::{atomic::Ordering, Arc};
 use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 
 use crate::app_state::AppState;
-use crate::config_store::ConfigStoreFunc;
-use crate::file_store::{FileStoreFunc, RecoverEntry};
-use crate::http_requests::{download_from_node, lookup_hash_on_monitor, LookupMonitorResponse};
+use crate::config_store::{ConfigStoreFunc, ContentHash, ContentHasher};
+use crate::file_store::{FileSource, RecoverEntry, MAX_RECOVER_ATTEMPTS};
+use crate::http_requests::{
+    lookup_hash_on_monitor, read_download_info, request_download_from_node, LookupMonitorResponse,
+};
 
 /*
  * RecoverService
  * Looks for hashes in the AppState which needs to be downloaded from other nodes. 
  * If such a hash is found, send a lookup request and download the node.
+ * Nodes which failed to send a file are not asked for it again. Failed recoveries are retried
+ * with a growing delay, after MAX_RECOVER_ATTEMPTS the file is reported to the monitor as lost.
  * 
  * timeout: The amount of time the service should wait if no hash is found in the queue.
  */
@@ -36,30 +42,35 @@ impl RecoverService {
 
             loop {
                 // Look for a hash in the AppState
-                let recover_opt = self
+                let recover_opt = match self
                     .app_state
                     .file_store
                     .write()
                     .unwrap()
-                    .next_file_to_recover();
+                    .next_file_to_recover()
+                {
+                    Ok(recover_opt) => recover_opt,
+                    Err(err) => {
+                        error!("Could not read the recover queue: {}", err);
+                        None
+                    }
+                };
 
                 // If a hash is found, look it up and download it
                 if let Some(entry) = recover_opt {
                     info!("Trying to recover {}", entry.hash);
 
-                    // The if there is still space left on the disk
+                    // The if there is still space left on the disk, or files can be evicted
                     let has_no_capacity =
-                        self.app_state.file_store.read().unwrap().capacity_left() <= 0;
-
-                    // If no space exists anymore, reject the hash
-                    if has_no_capacity {
-                        self.app_state
-                            .file_store
-                            .write()
-                            .unwrap()
-                            .reject_hash(&entry.hash);
+                        self.app_state.file_store.read().unwrap().capacity_left() == 0
+                            && !self.app_state.can_evict();
 
-                        info!("Rejected hash {}", &entry.hash)
+                    // A file deleted before can be taken back from the trash
+                    if RecoverService::restore_from_trash(&self.app_state, &entry.hash).await {
+                        info!("Recovered {} from the trash", entry.hash);
+                    } else if has_no_capacity {
+                        // If no space exists anymore, reject the hash
+                        RecoverService::reject(&self.app_state, &entry.hash);
                     } else {
                         // Send a lookup request
                         match lookup_hash_on_monitor(&entry.hash, &monitor.addr).await {
@@ -67,7 +78,7 @@ impl RecoverService {
                                 // Download the file from the node
                                 RecoverService::handle_lookup_success(
                                     self.app_state.clone(),
-                                    &entry.hash,
+                                    entry,
                                     result,
                                 )
                                 .await
@@ -75,15 +86,15 @@ impl RecoverService {
                             Err(err) => {
                                 RecoverService::handle_lookup_fail(
                                     self.app_state.clone(),
-                                    &entry.hash,
-                                    err,
+                                    entry,
+                                    &err.to_string(),
                                 )
                                 .await
                             }
                         }
                     }
                 } else {
-                    std::thread::sleep(Duration::from_secs(self.timeout));
+                    tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
                 }
 
                 if stop_services.load(Ordering::Relaxed) {
@@ -104,45 +115,283 @@ impl RecoverService {
         RecoverService { app_state, timeout }
     }
 
+    // Returns true if the file was found in the trash and restored
+    async fn restore_from_trash(app_state: &Arc<AppState>, hash: &str) -> bool {
+        let app_state = app_state.clone();
+        let hash = String::from(hash);
+        let restored = tokio::task::spawn_blocking(move || app_state.restore_file(&hash)).await;
+        matches!(restored, Ok(Ok(Some(_))))
+    }
+
     async fn handle_lookup_success(
         app_state: Arc<AppState>,
-        hash: &str,
+        mut entry: RecoverEntry,
         lookup_response: LookupMonitorResponse,
     ) {
         let node_addr = lookup_response.node_addr;
 
-        // Insert the downloaded file into the AppState
-        match download_from_node(&node_addr, hash).await {
-            Ok(result) => {
-                app_state.add_new_file(
-                    &result.content,
-                    &result.content_type,
-                    &result.file_name,
-                    false,
-                );
-                info!("Recovered file with hash {}", hash);
-                // let hash = app_state.add_new_file(&result.content, false);
-                // info!("Recovered file {} with hash {}", &result.content, hash)
+        // The monitor does not know which nodes failed before, they are not asked again
+        if entry.bad_sources.contains(&node_addr) {
+            let reason = format!("no other source than {} available", node_addr);
+            RecoverService::handle_lookup_fail(app_state, entry, &reason).await;
+            return;
+        }
+
+        let response = match request_download_from_node(&node_addr, &entry.hash).await {
+            Ok(response) => response,
+            Err(err) => {
+                entry.bad_sources.push(node_addr);
+                RecoverService::handle_lookup_fail(app_state, entry, &err.to_string()).await;
+                return;
             }
-            Err(err) => error!("{:?}", err),
+        };
+
+        // Reserve space for the file, before its content is downloaded
+        let mut reserved = response.content_length().unwrap_or(0);
+        if !RecoverService::reserve_space(&app_state, reserved).await {
+            RecoverService::reject(&app_state, &entry.hash);
+            return;
         }
-    }
 
-    // If the file could not be downloaded, reinsert the hash in the queue
-    async fn handle_lookup_fail(app_state: Arc<AppState>, hash: &str, error: reqwest::Error) {
-        let entries = vec![RecoverEntry {
-            hash: String::from(hash),
-            last_checked: chrono::Utc::now(),
-        }];
+        RecoverService::receive_file(app_state.clone(), entry, node_addr, response, &mut reserved)
+            .await;
 
+        // Stored files are counted as used space, the reservation is not needed anymore
         app_state
             .file_store
             .write()
             .unwrap()
-            .insert_files_to_recover(entries);
+            .release_space(reserved);
+    }
+
+    // Download the content of the file, verify and store it
+    async fn receive_file(
+        app_state: Arc<AppState>,
+        mut entry: RecoverEntry,
+        node_addr: String,
+        response: reqwest::Response,
+        reserved: &mut u64,
+    ) {
+        let (content_type, file_name) = read_download_info(&response);
+
+        let (content_hash, temp_path) =
+            match RecoverService::receive_content(&app_state, response, reserved).await {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::StorageFull => {
+                    RecoverService::reject(&app_state, &entry.hash);
+                    return;
+                }
+                Err(err) => {
+                    entry.bad_sources.push(node_addr);
+                    RecoverService::handle_lookup_fail(app_state, entry, &err.to_string()).await;
+                    return;
+                }
+            };
+
+        // Make sure the node sent the requested file, before storing it
+        if !content_hash.matches(&entry.hash) {
+            error!(
+                "Node {} sent corrupted data for {} (got {})",
+                node_addr, entry.hash, content_hash.hash
+            );
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            if let Err(err) = app_state
+                .file_store
+                .write()
+                .unwrap()
+                .report_corrupted_replica(&entry.hash, &node_addr)
+            {
+                error!("Could not report the replica of {}: {}", entry.hash, err);
+            }
+            entry.bad_sources.push(node_addr);
+
+            let reason = String::from("content does not match hash");
+            RecoverService::handle_lookup_fail(app_state, entry, &reason).await;
+            return;
+        }
+
+        // Move the downloaded file into the AppState
+        let hash = entry.hash.clone();
+        let state = app_state.clone();
+        let source = FileSource::Recovered { node_addr };
+        let stored_path = temp_path.clone();
+        let stored = tokio::task::spawn_blocking(move || {
+            state.add_new_file_from_path(
+                &content_hash,
+                &stored_path,
+                &content_type,
+                &file_name,
+                source,
+                false,
+            )
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+
+        match stored {
+            Ok(()) => info!("Recovered file with hash {}", hash),
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                RecoverService::handle_lookup_fail(app_state, entry, &err.to_string()).await;
+            }
+        }
+    }
+
+    /* Writes the content of response to a temporary file and hashes it on the way. Without a
+     * Content-Length, or if the node sends more, the reservation grows with every chunk. The
+     * written bytes are marked while receiving, as the free space on disk already shrank by them.
+     * Returns the hash and the path of the file, fails with StorageFull if there is no space left.
+     */
+    async fn receive_content(
+        app_state: &Arc<AppState>,
+        mut response: reqwest::Response,
+        reserved: &mut u64,
+    ) -> std::io::Result<(ContentHash, String)> {
+        let temp_path = app_state.file_store.read().unwrap().temp_file_path()?;
+        let mut hasher = ContentHasher::new();
+
+        let mut written = 0;
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp_path).await?;
+            let mut received = 0;
+
+            while let Some(chunk) = response.chunk().await.map_err(std::io::Error::other)? {
+                received += chunk.len() as u64;
+                if received > *reserved {
+                    let missing = received - *reserved;
+                    if !RecoverService::reserve_space(app_state, missing).await {
+                        return Err(std::io::Error::new(
+                            ErrorKind::StorageFull,
+                            "not enough space left for recovery",
+                        ));
+                    }
+                    *reserved += missing;
+                }
+
+                hasher.input(&chunk);
+                file.write_all(&chunk).await?;
+                app_state
+                    .file_store
+                    .read()
+                    .unwrap()
+                    .mark_written(chunk.len() as u64);
+                written += chunk.len() as u64;
+            }
+
+            file.sync_all().await
+        }
+        .await;
+
+        // The file is stored or removed right away, until then it may count twice
+        app_state
+            .file_store
+            .read()
+            .unwrap()
+            .unmark_written(written);
+
+        match result {
+            Ok(_) => Ok((hasher.result(), temp_path)),
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(err)
+            }
+        }
+    }
+
+    // Reserves space for a recovery, evicts other recovered files if there is not enough left
+    async fn reserve_space(app_state: &Arc<AppState>, size: u64) -> bool {
+        if app_state.file_store.write().unwrap().reserve_space(size) {
+            return true;
+        }
+        if !app_state.can_evict() {
+            return false;
+        }
+
+        let state = app_state.clone();
+        let made_room = tokio::task::spawn_blocking(move || state.make_room(size))
+            .await
+            .unwrap_or(false);
+        made_room && app_state.file_store.write().unwrap().reserve_space(size)
+    }
+
+    // If there is not enough space left for the file, tell the monitor to recover it elsewhere
+    fn reject(app_state: &Arc<AppState>, hash: &str) {
+        match app_state.file_store.write().unwrap().reject_hash(hash) {
+            Ok(()) => info!("Rejected hash {}", hash),
+            Err(err) => error!("Could not reject {}: {}", hash, err),
+        }
+    }
+
+    /* If the file could not be downloaded, reinsert the hash in the queue. After too many
+     * attempts the file is given up and reported as lost, so the monitor recovers it elsewhere.
+     */
+    async fn handle_lookup_fail(app_state: Arc<AppState>, mut entry: RecoverEntry, reason: &str) {
+        entry.attempts += 1;
+        if entry.attempts >= MAX_RECOVER_ATTEMPTS {
+            error!(
+                "Failed to recover {} {} times, reporting it as lost! Reason: {}",
+                entry.hash, entry.attempts, reason
+            );
+            let reported = app_state
+                .file_store
+                .write()
+                .unwrap()
+                .report_lost_hash(&entry.hash);
+            match reported {
+                Ok(()) => {
+                    app_state.force_ping.swap(true, Ordering::Relaxed);
+                }
+                Err(err) => error!("Could not report {} as lost: {}", entry.hash, err),
+            }
+            return;
+        }
+
         error!(
             "Failed to recover {}, trying again later! Reason: {}",
-            hash, error
+            entry.hash, reason
         );
+
+        entry.last_checked = chrono::Utc::now();
+        let hash = entry.hash.clone();
+        if let Err(err) = app_state
+            .file_store
+            .write()
+            .unwrap()
+            .insert_files_to_recover(vec![entry])
+        {
+            error!("Could not enqueue {} again: {}", hash, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::tests::open_app_state;
+
+    fn entry(attempts: u32) -> RecoverEntry {
+        RecoverEntry {
+            hash: String::from("lost"),
+            last_checked: chrono::Utc::now(),
+            bad_sources: vec![],
+            attempts,
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_files_as_lost_after_the_last_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = Arc::new(open_app_state(&dir, serde_json::json!({})).unwrap());
+
+        RecoverService::handle_lookup_fail(app_state.clone(), entry(0), "not found").await;
+        let lost_hashes = app_state.file_store.read().unwrap().lost_hashes().unwrap();
+        assert!(lost_hashes.is_empty());
+        assert!(!app_state.force_ping.load(Ordering::Relaxed));
+
+        let attempts = MAX_RECOVER_ATTEMPTS - 1;
+        RecoverService::handle_lookup_fail(app_state.clone(), entry(attempts), "not found").await;
+        let lost_hashes = app_state.file_store.read().unwrap().lost_hashes().unwrap();
+        assert_eq!(lost_hashes, vec![String::from("lost")]);
+        assert!(app_state.force_ping.load(Ordering::Relaxed));
     }
 }
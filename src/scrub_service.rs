@@ -0,0 +1,222 @@
+use chrono::{TimeZone, Utc};
+use log::{error, info, warn};
+use std::io::Read;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+
+use crate::app_state::AppState;
+use crate::blob_store::BlobStoreFunc;
+use crate::compression;
+use crate::config_store::ContentHasher;
+use crate::file_store::{FileEntry, RecoverEntry};
+
+/*
+ * ScrubService
+ * Periodically rehashes all stored files to detect corrupted data. Files are read with a bounded
+ * rate, so the scrub does not slow down uploads and downloads. A corrupted file gets quarantined,
+ * reported to the monitor with the next ping and enqueued to be recovered from another node.
+ *
+ * interval: The amount of time in seconds between two scrubs
+ * rate: The maximum amount of bytes per second read from the BlobStore
+ */
+
+pub struct ScrubService {
+    pub app_state: Arc<AppState>,
+    pub interval: u64,
+    pub rate: u64,
+}
+
+enum ScrubResult {
+    Intact,
+    Corrupted,
+    Aborted,
+}
+
+impl ScrubService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Scrub service started");
+            let stop_services = self.app_state.stop_services.clone();
+            let mut last_scrub = Instant::now();
+
+            loop {
+                if last_scrub.elapsed().as_secs() > self.interval {
+                    self.scrub().await;
+                    last_scrub = Instant::now();
+                }
+
+                // If flag is set, exit thread
+                if stop_services.load(Ordering::Relaxed) {
+                    info!("Shutting down scrub service");
+                    break;
+                }
+
+                tokio::time::delay_for(Duration::from_secs(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        info!("Scrub service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, interval: u64, rate: u64) -> ScrubService {
+        ScrubService {
+            app_state,
+            interval,
+            rate,
+        }
+    }
+
+    // Verify every file in the FileStore once
+    async fn scrub(&self) {
+        let hashes = match self.app_state.file_store.read().unwrap().hashes() {
+            Ok(hashes) => hashes,
+            Err(err) => {
+                error!("Could not list the files to scrub: {}", err);
+                return;
+            }
+        };
+        info!("Scrubbing {} files", hashes.len());
+
+        let started = Instant::now();
+        let mut bytes_read = 0;
+        let mut corrupted = 0;
+
+        let blob_store = self.app_state.file_store.read().unwrap().blob_store();
+        for hash in hashes {
+            // File was removed in the meantime, or can not be looked up right now
+            let file_entry = match self.app_state.file_store.read().unwrap().get_file(&hash) {
+                Ok(Some(file_entry)) => file_entry,
+                _ => continue,
+            };
+
+            match self
+                .verify_file(blob_store.clone(), &file_entry, started, &mut bytes_read)
+                .await
+            {
+                ScrubResult::Intact => {}
+                ScrubResult::Corrupted => {
+                    corrupted += 1;
+                    ScrubService::handle_corrupted_file(self.app_state.clone(), &hash);
+                }
+                ScrubResult::Aborted => return,
+            }
+        }
+
+        info!(
+            "Scrub finished in {}s, {} corrupted files found",
+            started.elapsed().as_secs(),
+            corrupted
+        );
+    }
+
+    /* Rehash the content stored under the given hash and compare it to the hash.
+     * Compressed contents are decompressed, the rate applies to the uncompressed bytes.
+     * After every chunk the service sleeps long enough to stay below the configured rate.
+     */
+    async fn verify_file(
+        &self,
+        blob_store: Arc<dyn BlobStoreFunc>,
+        file_entry: &FileEntry,
+        started: Instant,
+        bytes_read: &mut u64,
+    ) -> ScrubResult {
+        let hash = &file_entry.hash;
+        let chunk_size = blob_store.chunk_size();
+        let key = hash.clone();
+        let encoding = file_entry.encoding;
+        let mut reader =
+            match ScrubService::blocking(move || compression::open(blob_store, &key, encoding))
+                .await
+            {
+                Ok(reader) => reader,
+                Err(err) => {
+                    warn!("Could not open {}: {}", hash, err);
+                    return ScrubResult::Corrupted;
+                }
+            };
+
+        let mut hasher = ContentHasher::new();
+        loop {
+            if self.app_state.stop_services.load(Ordering::Relaxed) {
+                return ScrubResult::Aborted;
+            }
+
+            // The reader is moved into the blocking task and handed back with the chunk
+            let chunk = ScrubService::blocking(move || {
+                let mut chunk = vec![];
+                reader.by_ref().take(chunk_size).read_to_end(&mut chunk)?;
+                Ok((reader, chunk))
+            })
+            .await;
+            let chunk = match chunk {
+                Ok((returned, chunk)) => {
+                    reader = returned;
+                    chunk
+                }
+                Err(err) => {
+                    warn!("Could not read {}: {}", hash, err);
+                    return ScrubResult::Corrupted;
+                }
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.input(&chunk);
+
+            // Throttle reading to the configured rate
+            *bytes_read += chunk.len() as u64;
+            let expected = Duration::from_secs_f64(*bytes_read as f64 / self.rate as f64);
+            let elapsed = started.elapsed();
+            if expected > elapsed {
+                tokio::time::delay_for(expected - elapsed).await;
+            }
+        }
+
+        if hasher.result().matches(hash) {
+            ScrubResult::Intact
+        } else {
+            ScrubResult::Corrupted
+        }
+    }
+
+    // Run a blocking call, like reading from the BlobStore, without stalling the runtime
+    async fn blocking<T, F>(call: F) -> std::io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(call)
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    }
+
+    // Quarantine the file and enqueue it to be recovered from another node
+    fn handle_corrupted_file(app_state: Arc<AppState>, hash: &str) {
+        error!("File {} is corrupted", hash);
+
+        if let Err(err) = app_state.quarantine_file(hash) {
+            error!("Could not quarantine {}: {}", hash, err);
+        }
+
+        let entries = vec![RecoverEntry {
+            hash: String::from(hash),
+            last_checked: Utc.with_ymd_and_hms(1970, 1, 1, 0, 1, 1).unwrap(),
+            bad_sources: vec![],
+            attempts: 0,
+        }];
+        if let Err(err) = app_state
+            .file_store
+            .write()
+            .unwrap()
+            .insert_files_to_recover(entries)
+        {
+            error!("Could not enqueue {} to be recovered: {}", hash, err);
+        }
+
+        // Inform the monitor about the corrupted file
+        app_state.force_ping.swap(true, Ordering::Relaxed);
+    }
+}
@@ -1,22 +1,42 @@
-use crate::app_state::AppState;
-use crate::config_store::ConfigStoreFunc;
-use crate::file_store::FileStoreFunc;
+use crate::app_state::{AppState, UploadLock};
+use crate::compression::{self, Encoding};
+use crate::config_store::{ConfigStoreFunc, ContentHash};
+use crate::download::{self, Precondition, Preconditions};
+use crate::file_query::FileQuery;
+use crate::file_store::{FileEntry, FileSource};
 use crate::http_requests::lookup_hash_on_monitor;
+use crate::token_store::{ApiToken, Scope};
+use crate::upload_store::Upload;
 
 use bytes::buf::Buf;
+use futures::future;
 use futures::stream::StreamExt;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::oneshot;
+use warp::hyper::Body;
 use warp::Filter;
 
 /*
  * Server Backend
- * Offers HTTP entdpoints to download, lookup and upload a file.
+ * Offers HTTP entdpoints to download, lookup and upload a file. Files are uploaded with a
+ * multipart form, which redirects to the manager unless JSON is accepted, or as raw body of PUT /files.
+ * The uploads endpoints implement the tus protocol, so interrupted uploads can be resumed.
+ * Downloads answer HEAD and conditional requests, the hash of a file is its ETag.
  * The ping endpoint can be used to test if the node is visible to others outside the own network.
- * 
+ * The files endpoints list the stored files, filtered, sorted and paged, and return the metadata
+ * of a single file.
+ * The pin and retention endpoints protect files from deletion by the monitor, the trash endpoints
+ * list and restore deleted files, the storage endpoint shows the state of all data dirs and the
+ * tokens endpoints manage the API tokens.
+ * Uploads, deletions and these admin endpoints need an API token with the matching scope. Uploads
+ * are open to everyone until the first token is created, requests from the local machine need no
+ * token if trust_local_requests is set. Downloads, lookups, the file listing and ping are public.
+ *
  * receiver: channel which shuts the server down.
  */
 
@@ -32,6 +52,8 @@ pub async fn start_server(
     receiver: oneshot::Receiver<()>,
 ) -> std::io::Result<()> {
     let port = app_state.config_store.read().unwrap().port();
+    let auth_state = app_state.clone();
+    let auth = move |scope| authorized(scope, auth_state.clone());
     let state_filter = warp::any().map(move || app_state.clone());
 
     let cors = warp::cors()
@@ -44,13 +66,44 @@ pub async fn start_server(
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
             "content-type",
+            "content-disposition",
             "x-csrf-token",
+            "range",
+            "tus-resumable",
+            "upload-length",
+            "upload-metadata",
+            "upload-offset",
+            "if-match",
+            "if-none-match",
+            "if-modified-since",
+            "if-range",
+            "authorization",
         ])
-        .allow_methods(vec!["POST", "GET", "DELETE"]);
+        .expose_headers(vec![
+            "content-disposition",
+            "etag",
+            "location",
+            "tus-resumable",
+            "tus-version",
+            "tus-extension",
+            "tus-max-size",
+            "upload-offset",
+            "upload-length",
+            "upload-expires",
+            "upload-hash",
+            "www-authenticate",
+        ])
+        .allow_methods(vec!["POST", "GET", "PUT", "PATCH", "HEAD", "DELETE"]);
 
     let download_hash = warp::get()
+        .map(|| false)
+        .or(warp::head().map(|| true))
+        .unify()
         .and(warp::path("download"))
         .and(warp::path::param::<String>())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(preconditions())
         .and(state_filter.clone())
         .and_then(download);
 
@@ -62,16 +115,179 @@ pub async fn start_server(
 
     let upload_multipart = warp::post()
         .and(warp::path("upload"))
-        .and(warp::filters::multipart::form().max_length(1024 * 1024 * 10))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::body::stream())
         .and(state_filter.clone())
         .and_then(upload_multipart_fun);
 
+    let upload_raw = warp::put()
+        .and(warp::path!("files"))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-disposition"))
+        .and(warp::body::stream())
+        .and(state_filter.clone())
+        .and_then(upload_raw_fun);
+
+    let tus_options = warp::options()
+        .and(warp::path!("uploads"))
+        .and(state_filter.clone())
+        .and_then(tus_options_fun);
+
+    let tus_create = warp::post()
+        .and(warp::path!("uploads"))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(warp::header::optional::<u64>("upload-length"))
+        .and(warp::header::optional::<String>("upload-metadata"))
+        .and(state_filter.clone())
+        .and_then(tus_create_fun);
+
+    let tus_status = warp::head()
+        .and(warp::path!("uploads" / String))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(state_filter.clone())
+        .and_then(tus_status_fun);
+
+    let tus_append = warp::patch()
+        .and(warp::path!("uploads" / String))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(warp::header::optional::<u64>("upload-offset"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::stream())
+        .and(state_filter.clone())
+        .and_then(tus_append_fun);
+
+    let tus_terminate = warp::delete()
+        .and(warp::path!("uploads" / String))
+        .and(auth(Scope::Upload))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(state_filter.clone())
+        .and_then(tus_terminate_fun);
+
     let ping = warp::get().and(warp::path("ping")).and_then(ping_fun);
 
+    let list_files = warp::get()
+        .and(warp::path!("files"))
+        .and(warp::query::<FileQuery>())
+        .and(state_filter.clone())
+        .and_then(list_files_fun);
+
+    let file_info = warp::get()
+        .and(warp::path!("files" / String))
+        .and(state_filter.clone())
+        .and_then(file_info_fun);
+
+    let delete_file = warp::delete()
+        .and(warp::path!("files" / String))
+        .and(auth(Scope::Delete))
+        .and(state_filter.clone())
+        .and_then(delete_file_fun);
+
+    let tags = warp::put()
+        .and(warp::path!("files" / String / "tags"))
+        .and(auth(Scope::Admin))
+        .and(warp::body::json::<Vec<String>>())
+        .and(state_filter.clone())
+        .and_then(tags_fun);
+
+    let pin = warp::put()
+        .map(|| true)
+        .or(warp::delete().map(|| false))
+        .unify()
+        .and(warp::path!("files" / String / "pin"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(pin_fun);
+
+    let retention = warp::put()
+        .and(warp::path!("files" / String / "retention"))
+        .and(auth(Scope::Admin))
+        .and(warp::body::json::<RetentionRequest>())
+        .map(|hash, request: RetentionRequest| (hash, Some(request.retain_until)))
+        .untuple_one()
+        .or(warp::delete()
+            .and(warp::path!("files" / String / "retention"))
+            .and(auth(Scope::Admin))
+            .map(|hash| (hash, None))
+            .untuple_one())
+        .unify()
+        .and(state_filter.clone())
+        .and_then(retention_fun);
+
+    let trash = warp::get()
+        .and(warp::path!("trash"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(trash_fun);
+
+    let restore = warp::post()
+        .and(warp::path!("trash" / String / "restore"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(restore_fun);
+
+    let rotate_key = warp::post()
+        .and(warp::path!("encryption" / "rotate"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(rotate_key_fun);
+
+    let storage = warp::get()
+        .and(warp::path!("storage"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(storage_fun);
+
+    let list_tokens = warp::get()
+        .and(warp::path!("tokens"))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(list_tokens_fun);
+
+    let create_token = warp::post()
+        .and(warp::path!("tokens"))
+        .and(auth(Scope::Admin))
+        .and(warp::body::json::<TokenRequest>())
+        .and(state_filter.clone())
+        .and_then(create_token_fun);
+
+    let revoke_token = warp::delete()
+        .and(warp::path!("tokens" / String))
+        .and(auth(Scope::Admin))
+        .and(state_filter.clone())
+        .and_then(revoke_token_fun);
+
     let routes = download_hash
         .or(lookup_hash)
         .or(upload_multipart)
+        .or(upload_raw)
+        .or(tus_options)
+        .or(tus_create)
+        .or(tus_status)
+        .or(tus_append)
+        .or(tus_terminate)
         .or(ping)
+        .or(list_files)
+        .or(file_info)
+        .or(delete_file)
+        .or(tags)
+        .or(pin)
+        .or(retention)
+        .or(trash)
+        .or(restore)
+        .or(rotate_key)
+        .or(storage)
+        .or(list_tokens)
+        .or(create_token)
+        .or(revoke_token)
+        .recover(auth_rejection)
         .with(cors);
 
     let addr = std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
@@ -93,36 +309,121 @@ pub struct DownloadResponse {
     pub file_name: String,      // file name
 }
 
-async fn download(hash: String, state: Arc<AppState>) -> Result<impl warp::Reply, warp::Rejection> {
+/* Streams a stored file. HEAD requests get the same headers without the content. Conditional
+ * requests for a file the client has already are answered with 304 Not Modified.
+ */
+async fn download(
+    head: bool,
+    hash: String,
+    range: Option<String>,
+    accept_encoding: Option<String>,
+    preconditions: Preconditions,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     // Check if file with hash is stored on this node
-    match state.file_store.read().unwrap().get_file(&hash) {
-        Some(file_entry) => {
-            // Send file as response
+    let file_opt = match state.file_store.read().unwrap().get_file(&hash) {
+        Ok(file_opt) => file_opt,
+        Err(_) => {
             let response = warp::http::Response::builder()
-                .header("Content-Type", &file_entry.content_type)
-                .header(
-                    "Content-Disposition",
-                    format!(":attachment; filename={}", &file_entry.file_name),
-                )
-                .body(file_entry.content().unwrap())
+                .status(warp::http::StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())
                 .unwrap();
+            return Ok(response);
+        }
+    };
+    let blob_store = state.file_store.read().unwrap().blob_store();
 
-            return Ok(warp::reply::with_status(
-                response,
-                warp::http::StatusCode::OK,
-            ));
+    let size = match &file_opt {
+        Some(file_entry) => {
+            let blob_store = blob_store.clone();
+            let key = file_entry.hash.clone();
+            tokio::task::spawn_blocking(move || blob_store.size(&key))
+                .await
+                .ok()
+                .and_then(|size| size.ok())
         }
-        None => {
+        None => None,
+    };
+
+    match (file_opt, size) {
+        (Some(file_entry), Some(stored_size)) => {
+            let last_modified = Some(file_entry.created_at).filter(|&created_at| created_at > 0);
+            let mut headers = vec![
+                (
+                    "Content-Disposition",
+                    download::content_disposition(&file_entry.file_name),
+                ),
+                ("Cache-Control", String::from(download::CACHE_CONTROL)),
+            ];
+            if let Some(last_modified) = last_modified {
+                headers.push(("Last-Modified", download::http_date(last_modified)));
+            }
+
+            // Ranges always refer to the uncompressed content
+            let identity_etag = download::etag(&file_entry.hash, Encoding::Identity);
+            let range = range.filter(|_| preconditions.range_applies(&identity_etag, last_modified));
+
+            // Clients accepting the encoding get compressed files as they are, unless they ask for ranges
+            let (encoding, total, etag) = match file_entry.encoding {
+                Encoding::Identity => (Encoding::Identity, stored_size, identity_etag),
+                encoding => {
+                    headers.push(("Vary", String::from("Accept-Encoding")));
+                    if range.is_none()
+                        && download::accepts_encoding(accept_encoding.as_deref(), encoding.name())
+                    {
+                        headers.push(("Content-Encoding", String::from(encoding.name())));
+                        let etag = download::etag(&file_entry.hash, encoding);
+                        (Encoding::Identity, stored_size, etag)
+                    } else {
+                        (encoding, file_entry.size, identity_etag)
+                    }
+                }
+            };
+            headers.push(("ETag", etag.clone()));
+
+            match preconditions.evaluate(&etag, last_modified) {
+                Precondition::Passed => {}
+                Precondition::NotModified => {
+                    return Ok(download::status_response(
+                        warp::http::StatusCode::NOT_MODIFIED,
+                        headers,
+                    ))
+                }
+                Precondition::Failed => {
+                    return Ok(download::status_response(
+                        warp::http::StatusCode::PRECONDITION_FAILED,
+                        headers,
+                    ))
+                }
+            }
+
+            let range_request = download::parse_range(range.as_deref(), total);
+            let response = download::file_response(
+                blob_store,
+                &file_entry.hash,
+                encoding,
+                &file_entry.content_type,
+                total,
+                range_request,
+                headers,
+            );
+
+            // HEAD requests get the headers only, the content is never read
+            if head {
+                let (parts, _) = response.into_parts();
+                return Ok(warp::http::Response::from_parts(parts, Body::empty()));
+            }
+            state.record_access(&file_entry.hash);
+            return Ok(response);
+        }
+        _ => {
             error!("Could not find file with hash {}", hash);
             let response = warp::http::Response::builder()
                 .status(warp::http::StatusCode::NOT_FOUND)
-                .body(vec![])
+                .body(Body::empty())
                 .unwrap();
 
-            return Ok(warp::reply::with_status(
-                response,
-                warp::http::StatusCode::NOT_FOUND,
-            ));
+            return Ok(response);
         }
     }
 }
@@ -134,14 +435,27 @@ struct LookupResponse {
 }
 async fn lookup(hash: String, state: Arc<AppState>) -> Result<impl warp::Reply, warp::Rejection> {
     // Lookup in local filestore
-    if let Some(file_entry) = state.file_store.read().unwrap().get_file(&hash) {
-        let reply = warp::reply::json(&DownloadResponse {
-            hash,
-            content: file_entry.content().unwrap(),
-            content_type: String::from(&file_entry.content_type),
-            file_name: String::from(&file_entry.file_name),
-        });
-        return Ok(warp::reply::with_status(reply, warp::http::StatusCode::OK));
+    let file_opt = match state.file_store.read().unwrap().get_file(&hash) {
+        Ok(file_opt) => file_opt,
+        Err(_) => return Ok(file_store_unavailable()),
+    };
+    if let Some(file_entry) = file_opt {
+        let blob_store = state.file_store.read().unwrap().blob_store();
+        let key = file_entry.hash.clone();
+        let encoding = file_entry.encoding;
+        let content =
+            tokio::task::spawn_blocking(move || compression::read(blob_store, &key, encoding))
+                .await;
+        if let Ok(Ok(content)) = content {
+            state.record_access(&file_entry.hash);
+            let reply = warp::reply::json(&DownloadResponse {
+                hash,
+                content,
+                content_type: file_entry.content_type,
+                file_name: file_entry.file_name,
+            });
+            return Ok(warp::reply::with_status(reply, warp::http::StatusCode::OK));
+        }
     }
 
     // Forward lookup to monitor
@@ -161,57 +475,1079 @@ async fn lookup(hash: String, state: Arc<AppState>) -> Result<impl warp::Reply,
     ));
 }
 
+/* Stores the upload[data] field of a multipart form. The form is parsed while the body
+ * arrives, so the file goes to disk chunk by chunk and is never held in memory.
+ */
 async fn upload_multipart_fun(
-    mut data: warp::filters::multipart::FormData,
+    content_length: Option<u64>,
+    content_type: Option<String>,
+    accept: Option<String>,
+    body: impl futures::Stream<Item = Result<impl Buf, warp::Error>> + Send + 'static,
     state: Arc<AppState>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut query = String::from("status=error");
-
-    while let Some(Ok(mut part)) = data.next().await {
-        // Process uploaded data
-        if part.name() == "upload[data]" {
-            let mut buf = part.data().await.unwrap().unwrap();
-            let binary_vec = buf.to_bytes().to_vec();
-            let content_type = part
-                .content_type()
-                .or(Some("application/octet-stream"))
-                .unwrap();
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let json = accept.is_some_and(|accept| accept.contains("application/json"));
+    let max_upload_size = state.config_store.read().unwrap().max_upload_size();
 
-            // Check if hash already exists on other nodes
-            // let hash = state
-            //     .config_store
-            //     .write()
-            //     .unwrap()
-            //     .hash_content(binary_vec.as_slice());
-            // if let Some(_) = state.file_store.read().unwrap().get_file(&hash) {
-            //     error!("Uploaded file with hahs {} already exists!", &hash);
-            //     return Ok(warp::reply::with_status(
-            //         empty_reply(),
-            //         warp::http::StatusCode::CONFLICT,
-            //     ));
-            // }
-
-            // let monitor = state.config_store.read().unwrap().monitor();
-            // if let Ok(_) = lookup_hash_on_monitor(&hash, &monitor.addr).await {
-            //     error!("Uploaded file with hahs {} already exists!", &hash);
-            //     return Ok(warp::reply::with_status(
-            //         warp::reply(),
-            //         warp::http::StatusCode::CONFLICT,
-            //     ));
-            // }
-
-            let filename = part.filename().or(Some("unknown")).unwrap();
-            let hash = state.add_new_file(binary_vec.as_slice(), content_type, filename, true);
-            query = format!("status=success&hash={}", hash);
+    // Reserve space for the whole request before reading it, the file is never larger.
+    // Without a Content-Length the space gets reserved chunk by chunk while receiving.
+    // A request over the limit is refused before anything gets reserved.
+    let mut reserved = content_length.unwrap_or(0);
+    let uploaded = if reserved > max_upload_size {
+        Err(upload_too_large())
+    } else {
+        if !state.file_store.write().unwrap().reserve_space(reserved) {
+            error!("Not enough space left for upload of {} bytes", reserved);
+            return Ok(Box::new(insufficient_storage_reply()));
         }
+
+        let uploaded = match content_type.as_deref().map(multer::parse_boundary) {
+            Some(Ok(boundary)) => {
+                let body = body.map(|chunk| chunk.map(|mut buf| buf.to_bytes().to_vec()));
+                let constraints = multer::Constraints::new()
+                    .size_limit(multer::SizeLimit::new().per_field(max_upload_size));
+                let form = multer::Multipart::with_constraints(body, boundary, constraints);
+                receive_form(form, &state, &mut reserved).await
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "request is not a multipart form",
+            )),
+        };
+
+        // Stored files are counted as used space, the reservation is not needed anymore
+        state.file_store.write().unwrap().release_space(reserved);
+        uploaded
+    };
+
+    if json {
+        return Ok(Box::new(upload_reply(uploaded)));
     }
 
+    let query = match uploaded {
+        Ok(response) => format!("status=success&hash={}", response.hash),
+        Err(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+            return Ok(Box::new(insufficient_storage_reply()))
+        }
+        Err(_) => String::from("status=error"),
+    };
+
     let manager_addr = state.config_store.read().unwrap().manager();
     let addr = format!("{}?{}", manager_addr, query);
-    let uri = warp::http::Uri::from_str(&addr).unwrap();
+    let uri = match warp::http::Uri::from_str(&addr) {
+        Ok(uri) => uri,
+        Err(err) => {
+            error!("Invalid manager address {}: {}", manager_addr, err);
+            let reply = warp::reply::json(&JsonResponse {
+                status: String::from("error"),
+                message: format!("invalid manager address: {}", err),
+            });
+            return Ok(Box::new(warp::reply::with_status(
+                reply,
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )));
+        }
+    };
 
     info!("Sending reply");
-    return Ok(warp::redirect(uri));
+    return Ok(Box::new(warp::redirect(uri)));
+}
+
+// Receives the upload[data] field of a form, all other fields are skipped
+async fn receive_form(
+    mut form: multer::Multipart<'_>,
+    state: &Arc<AppState>,
+    reserved: &mut u64,
+) -> std::io::Result<UploadResponse> {
+    let form_error = |err: multer::Error| match err {
+        multer::Error::FieldSizeExceeded { .. } => upload_too_large(),
+        err => std::io::Error::new(std::io::ErrorKind::InvalidInput, err),
+    };
+
+    while let Some(field) = form.next_field().await.map_err(form_error)? {
+        if field.name() != Some("upload[data]") {
+            continue;
+        }
+
+        let content_type = field
+            .content_type()
+            .map(|mime| mime.to_string())
+            .unwrap_or_else(|| String::from("application/octet-stream"));
+        let filename = String::from(field.file_name().unwrap_or("unknown"));
+        let stream = field.map(|chunk| chunk.map_err(form_error));
+        return receive_upload(stream, &content_type, &filename, state, reserved).await;
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "form has no upload[data] field",
+    ))
+}
+
+// Stores the body of the request as a file, the file name is taken from the Content-Disposition header
+async fn upload_raw_fun(
+    content_length: Option<u64>,
+    content_type: Option<String>,
+    content_disposition: Option<String>,
+    body: impl futures::Stream<Item = Result<impl Buf, warp::Error>>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let content_type = content_type.unwrap_or_else(|| String::from("application/octet-stream"));
+    let filename = content_disposition
+        .as_deref()
+        .and_then(download::disposition_file_name)
+        .unwrap_or_else(|| String::from("unknown"));
+
+    let mut reserved = content_length.unwrap_or(0);
+    if reserved > state.config_store.read().unwrap().max_upload_size() {
+        return Ok(upload_reply(Err(upload_too_large())));
+    }
+    if !state.file_store.write().unwrap().reserve_space(reserved) {
+        error!("Not enough space left for upload of {} bytes", reserved);
+        return Ok(insufficient_storage_reply());
+    }
+
+    let body = body.map(|chunk| chunk.map(|mut buf| buf.to_bytes()).map_err(std::io::Error::other));
+    let uploaded = receive_upload(body, &content_type, &filename, &state, &mut reserved).await;
+    state.file_store.write().unwrap().release_space(reserved);
+
+    Ok(upload_reply(uploaded))
+}
+
+// Reply to PUT /files and to uploads with Accept: application/json
+#[derive(Serialize)]
+struct UploadResponse {
+    status: String,       // "success"
+    hash: String,         // SHA-256 hash of the file
+    legacy_hash: String,  // SHA-1 hash of the file
+    size: u64,            // Size of the file in bytes
+    file_name: String,    // Name given by the uploader
+    content_type: String, // Content type given by the uploader
+    replication: String,  // "queued", the file waits to be distributed to other nodes by the monitors
+}
+
+fn upload_reply(
+    uploaded: std::io::Result<UploadResponse>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    match uploaded {
+        Ok(response) => warp::reply::with_status(
+            warp::reply::json(&response),
+            warp::http::StatusCode::CREATED,
+        ),
+        Err(err) => {
+            let status = match err.kind() {
+                std::io::ErrorKind::StorageFull => warp::http::StatusCode::INSUFFICIENT_STORAGE,
+                std::io::ErrorKind::FileTooLarge => warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+                std::io::ErrorKind::InvalidInput => warp::http::StatusCode::BAD_REQUEST,
+                _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let reply = warp::reply::json(&JsonResponse {
+                status: String::from("error"),
+                message: err.to_string(),
+            });
+            warp::reply::with_status(reply, status)
+        }
+    }
+}
+
+fn upload_too_large() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::FileTooLarge,
+        "upload exceeds max_upload_size",
+    )
+}
+
+/* Receives an uploaded file into a temporary file and moves it into the BlobStore.
+ *
+ * stream: Content of the file, a multipart field or the body of the request
+ * reserved: Space reserved for the upload, grows if the file does not fit into it
+ */
+async fn receive_upload(
+    stream: impl futures::Stream<Item = std::io::Result<impl AsRef<[u8]>>>,
+    content_type: &str,
+    filename: &str,
+    state: &Arc<AppState>,
+    reserved: &mut u64,
+) -> std::io::Result<UploadResponse> {
+    let (hash, temp_path) = match receive_stream(stream, state, reserved).await {
+        Ok(received) => received,
+        Err(err) => {
+            error!("Failed to receive upload: {}", err);
+            return Err(err);
+        }
+    };
+    let size = tokio::fs::metadata(&temp_path).await?.len();
+
+    // Moving the file into the BlobStore may upload it, so it runs beside the runtime
+    let stored = {
+        let state = state.clone();
+        let hash = hash.clone();
+        let temp_path = temp_path.clone();
+        let content_type = String::from(content_type);
+        let filename = String::from(filename);
+        tokio::task::spawn_blocking(move || {
+            state.add_new_file_from_path(
+                &hash,
+                &temp_path,
+                &content_type,
+                &filename,
+                FileSource::Upload,
+                true,
+            )
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    };
+    if let Err(err) = stored {
+        error!("Could not store upload {}: {}", hash.hash, err);
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(err);
+    }
+
+    Ok(UploadResponse {
+        status: String::from("success"),
+        hash: hash.hash,
+        legacy_hash: hash.legacy_hash,
+        size,
+        file_name: String::from(filename),
+        content_type: String::from(content_type),
+        replication: String::from("queued"),
+    })
+}
+
+/* Streams an upload chunk by chunk into a temporary file inside the state dir, while its hash
+ * is computed on the fly. Returns the hash and the path of the temporary file.
+ * The temporary file is removed again if the upload fails or exceeds max_upload_size.
+ * While receiving, the written bytes are marked, as the free space on disk already shrank by them.
+ *
+ * reserved: Space reserved for the upload, grows if the file does not fit into it
+ */
+async fn receive_stream(
+    stream: impl futures::Stream<Item = std::io::Result<impl AsRef<[u8]>>>,
+    state: &Arc<AppState>,
+    reserved: &mut u64,
+) -> std::io::Result<(ContentHash, String)> {
+    let temp_path = state.file_store.read().unwrap().temp_file_path()?;
+    let mut hasher = state.config_store.read().unwrap().content_hasher();
+    let max_upload_size = state.config_store.read().unwrap().max_upload_size();
+
+    let mut written = 0;
+    let result = async {
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        let mut stream = Box::pin(stream);
+        let mut received = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let bytes = chunk.as_ref();
+
+            received += bytes.len() as u64;
+            if received > max_upload_size {
+                return Err(upload_too_large());
+            }
+            if received > *reserved {
+                let missing = received - *reserved;
+                if !state.file_store.write().unwrap().reserve_space(missing) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::StorageFull,
+                        "not enough space left for upload",
+                    ));
+                }
+                *reserved += missing;
+            }
+
+            hasher.input(bytes);
+            file.write_all(bytes).await?;
+            state.file_store.read().unwrap().mark_written(bytes.len() as u64);
+            written += bytes.len() as u64;
+        }
+
+        file.sync_all().await
+    }
+    .await;
+
+    // The file is stored or removed right away, until then it may count twice
+    state.file_store.read().unwrap().unmark_written(written);
+
+    match result {
+        Ok(_) => Ok((hasher.result(), temp_path)),
+        Err(err) => {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            Err(err)
+        }
+    }
+}
+
+/*
+ * Resumable uploads
+ * Implements the core of the tus protocol 1.0.0 with the creation, expiration and termination
+ * extensions, see https://tus.io/protocols/resumable-upload. The received bytes are kept in the
+ * UploadStore until the upload is complete and stored as new file. Partial uploads do not count
+ * in capacity_left, space is only reserved while a request writes to them, see UploadLock.
+ */
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+// Starts a reply of the tus protocol, every reply carries the version of the protocol
+fn tus_response(status: warp::http::StatusCode) -> warp::http::response::Builder {
+    warp::http::Response::builder()
+        .status(status)
+        .header("Tus-Resumable", TUS_VERSION)
+}
+
+fn tus_reply(status: warp::http::StatusCode) -> warp::http::Response<Body> {
+    tus_response(status).body(Body::empty()).unwrap()
+}
+
+// Returns the reply for requests of other versions of the protocol, if the version is not supported
+fn tus_version_mismatch(version: Option<String>) -> Option<warp::http::Response<Body>> {
+    match version.as_deref() {
+        Some(TUS_VERSION) => None,
+        _ => Some(
+            tus_response(warp::http::StatusCode::PRECONDITION_FAILED)
+                .header("Tus-Version", TUS_VERSION)
+                .body(Body::empty())
+                .unwrap(),
+        ),
+    }
+}
+
+// Returns the time when an upload expires, if it makes no progress from now on
+fn upload_expires_at(state: &Arc<AppState>) -> i64 {
+    let expiry = state.config_store.read().unwrap().upload_expiry();
+    chrono::Utc::now().timestamp() + expiry.min(i64::MAX as u64 / 2) as i64
+}
+
+/* Returns the value of a key of the Upload-Metadata header, like filename ZGF0YS5jc3Y=,...
+ * Values are base64 encoded, keys without value or with invalid values are ignored.
+ */
+fn upload_metadata(header: Option<&str>, keys: &[&str]) -> Option<String> {
+    header?
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?;
+            let value = base64::decode(parts.next()?.trim()).ok()?;
+            Some((key, String::from_utf8(value).ok()?))
+        })
+        .find(|(key, _)| keys.contains(key))
+        .map(|(_, value)| value)
+}
+
+async fn tus_options_fun(state: Arc<AppState>) -> Result<impl warp::Reply, warp::Rejection> {
+    let max_upload_size = state.config_store.read().unwrap().max_upload_size();
+    let response = tus_response(warp::http::StatusCode::NO_CONTENT)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", max_upload_size);
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+// Creates an upload, the file name and type are taken from the Upload-Metadata header
+async fn tus_create_fun(
+    version: Option<String>,
+    length: Option<u64>,
+    metadata: Option<String>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(reply) = tus_version_mismatch(version) {
+        return Ok(reply);
+    }
+    let length = match length {
+        Some(length) => length,
+        None => return Ok(tus_reply(warp::http::StatusCode::BAD_REQUEST)),
+    };
+    if length > state.config_store.read().unwrap().max_upload_size() {
+        return Ok(tus_reply(warp::http::StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    if length > state.file_store.read().unwrap().capacity_left() {
+        error!("Not enough space left for upload of {} bytes", length);
+        return Ok(tus_reply(warp::http::StatusCode::INSUFFICIENT_STORAGE));
+    }
+
+    let file_name = upload_metadata(metadata.as_deref(), &["filename", "name"])
+        .unwrap_or_else(|| String::from("unknown"));
+    let content_type = upload_metadata(metadata.as_deref(), &["filetype", "type"])
+        .unwrap_or_else(|| String::from("application/octet-stream"));
+    let created = state.uploads.write().unwrap().create(
+        length,
+        &file_name,
+        &content_type,
+        upload_expires_at(&state),
+    );
+    let upload = match created {
+        Ok(upload) => upload,
+        Err(err) => {
+            error!("Could not create upload: {}", err);
+            return Ok(tus_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+    info!("Created upload {} of {} bytes", upload.id, length);
+
+    // An empty file is complete right away
+    let mut response = tus_response(warp::http::StatusCode::CREATED)
+        .header("Location", format!("/uploads/{}", upload.id))
+        .header("Upload-Expires", download::http_date(upload.expires_at));
+    if length == 0 {
+        let _lock = state.lock_upload(&upload.id);
+        match complete_upload(&upload.id, &state).await {
+            Ok(hash) => response = response.header("Upload-Hash", hash.hash),
+            Err(_) => return Ok(tus_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR)),
+        }
+    }
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+// Returns the offset of an upload, so the client knows where to resume
+async fn tus_status_fun(
+    id: String,
+    version: Option<String>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(reply) = tus_version_mismatch(version) {
+        return Ok(reply);
+    }
+    let upload = match state.uploads.read().unwrap().get(&id) {
+        Some(upload) => upload,
+        None => return Ok(tus_reply(warp::http::StatusCode::NOT_FOUND)),
+    };
+
+    let mut response = tus_response(warp::http::StatusCode::OK)
+        .header("Upload-Offset", upload.offset)
+        .header("Upload-Length", upload.length)
+        .header("Upload-Expires", download::http_date(upload.expires_at))
+        .header("Cache-Control", "no-store");
+    if let Some(hash) = upload.hash {
+        response = response.header("Upload-Hash", hash);
+    }
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+/* Appends the body to an upload at the given offset. Bytes received before the connection
+ * dropped are kept, so the client can resume after them. The last request stores the file and
+ * returns its hash in the Upload-Hash header.
+ */
+async fn tus_append_fun(
+    id: String,
+    version: Option<String>,
+    offset: Option<u64>,
+    content_type: Option<String>,
+    body: impl futures::Stream<Item = Result<impl Buf, warp::Error>>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(reply) = tus_version_mismatch(version) {
+        return Ok(reply);
+    }
+    if content_type.as_deref() != Some("application/offset+octet-stream") {
+        return Ok(tus_reply(warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Ok(tus_reply(warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    let upload = match state.uploads.read().unwrap().get(&id) {
+        Some(upload) => upload,
+        None => return Ok(tus_reply(warp::http::StatusCode::NOT_FOUND)),
+    };
+    let mut lock = match state.lock_upload(&id) {
+        Some(lock) => lock,
+        None => return Ok(tus_reply(warp::http::StatusCode::LOCKED)),
+    };
+
+    // The upload may have been completed or resumed since it was read
+    let upload = state.uploads.read().unwrap().get(&id).unwrap_or(upload);
+    if offset != upload.offset {
+        return Ok(tus_reply(warp::http::StatusCode::CONFLICT));
+    }
+
+    let data_path = state.uploads.read().unwrap().data_path(&id);
+    let appended = match upload.is_complete() {
+        true => Ok(()),
+        false => append_upload(body, &data_path, &upload, &mut lock).await,
+    };
+    let new_offset = match upload.is_complete() {
+        true => upload.length,
+        false => tokio::fs::metadata(&data_path)
+            .await
+            .map(|metadata| metadata.len().min(upload.length))
+            .unwrap_or(upload.offset),
+    };
+
+    // Store the file before the upload is unlocked, so no other request sees it half done
+    let mut hash = upload.hash.clone();
+    let mut status = warp::http::StatusCode::NO_CONTENT;
+    if let Err(err) = appended {
+        error!("Failed to receive upload {}: {}", id, err);
+        status = match err.kind() {
+            std::io::ErrorKind::FileTooLarge => warp::http::StatusCode::BAD_REQUEST,
+            std::io::ErrorKind::StorageFull => warp::http::StatusCode::INSUFFICIENT_STORAGE,
+            _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    } else if hash.is_none() && new_offset == upload.length {
+        // The bytes of earlier requests were not reserved, the stored file needs all of it
+        let missing = upload.length.saturating_sub(lock.reserved());
+        if !lock.reserve(missing) {
+            error!("Not enough space left to store upload {}", id);
+            status = warp::http::StatusCode::INSUFFICIENT_STORAGE;
+        } else {
+            match complete_upload(&id, &state).await {
+                Ok(content_hash) => hash = Some(content_hash.hash),
+                Err(_) => status = warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    }
+
+    let expires_at = upload_expires_at(&state);
+    state
+        .uploads
+        .write()
+        .unwrap()
+        .record_progress(&id, new_offset, expires_at);
+    drop(lock);
+
+    let mut response = tus_response(status)
+        .header("Upload-Offset", new_offset)
+        .header("Upload-Expires", download::http_date(expires_at));
+    if let Some(hash) = hash {
+        response = response.header("Upload-Hash", hash);
+    }
+    Ok(response.body(Body::empty()).unwrap())
+}
+
+// Writes the body to the data file of an upload, behind the bytes received so far
+async fn append_upload(
+    body: impl futures::Stream<Item = Result<impl Buf, warp::Error>>,
+    data_path: &str,
+    upload: &Upload,
+    lock: &mut UploadLock<'_>,
+) -> std::io::Result<()> {
+    let (offset, length) = (upload.offset, upload.length);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(data_path)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    let mut body = Box::pin(body);
+    let mut received = offset;
+    let result = async {
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk.map_err(std::io::Error::other)?;
+            let bytes = chunk.to_bytes();
+
+            let fitting = bytes.len().min((length - received) as usize);
+            if !lock.reserve(fitting as u64) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "not enough space left for upload",
+                ));
+            }
+            file.write_all(&bytes[..fitting]).await?;
+            lock.written(fitting as u64);
+            received += fitting as u64;
+            if fitting < bytes.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::FileTooLarge,
+                    "upload exceeds Upload-Length",
+                ));
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // Keep everything received, even if the connection dropped
+    file.sync_all().await?;
+    result
+}
+
+// Stores a complete upload as new file beside the runtime
+async fn complete_upload(id: &str, state: &Arc<AppState>) -> std::io::Result<ContentHash> {
+    let app_state = state.clone();
+    let upload_id = String::from(id);
+    let completed = tokio::task::spawn_blocking(move || app_state.complete_upload(&upload_id))
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+
+    match &completed {
+        Ok(hash) => info!("Upload {} completed as {}", id, hash.hash),
+        Err(err) => error!("Could not store upload {}: {}", id, err),
+    }
+    completed
+}
+
+// Removes an upload and the bytes received so far
+async fn tus_terminate_fun(
+    id: String,
+    version: Option<String>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(reply) = tus_version_mismatch(version) {
+        return Ok(reply);
+    }
+    if state.uploads.read().unwrap().get(&id).is_none() {
+        return Ok(tus_reply(warp::http::StatusCode::NOT_FOUND));
+    }
+
+    match state.remove_upload(&id) {
+        Some(_) => Ok(tus_reply(warp::http::StatusCode::NO_CONTENT)),
+        None => Ok(tus_reply(warp::http::StatusCode::LOCKED)),
+    }
+}
+
+async fn list_files_fun(
+    query: FileQuery,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // The list shows and sorts by the latest accesses
+    state.flush_accesses();
+    match state.file_store.read().unwrap().list_files(&query) {
+        Ok(page) => Ok(warp::reply::with_status(
+            warp::reply::json(&page),
+            warp::http::StatusCode::OK,
+        )),
+        Err(_) => Ok(file_store_unavailable()),
+    }
+}
+
+async fn file_info_fun(
+    hash: String,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match state.file_store.read().unwrap().get_file(&hash) {
+        Ok(file_opt) => Ok(file_entry_reply(file_opt)),
+        Err(_) => Ok(file_store_unavailable()),
+    }
+}
+
+// Moves a file to the trash, like a deletion requested by the monitor. Held files are kept.
+async fn delete_file_fun(
+    hash: String,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let file_entry = match state.file_store.read().unwrap().get_file(&hash) {
+        Ok(Some(file_entry)) => file_entry,
+        Ok(None) => {
+            return Ok(warp::reply::with_status(
+                empty_reply(),
+                warp::http::StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(_) => return Ok(file_store_unavailable()),
+    };
+    if file_entry.is_held() {
+        let reply = warp::reply::json(&JsonResponse {
+            status: String::from("error"),
+            message: String::from("file is pinned or retained"),
+        });
+        return Ok(warp::reply::with_status(
+            reply,
+            warp::http::StatusCode::CONFLICT,
+        ));
+    }
+
+    // Moving the file to the trash may copy it inside the BlobStore, so it runs beside the runtime
+    let _ = tokio::task::spawn_blocking(move || state.remove_file_on_request(&hash)).await;
+    Ok(warp::reply::with_status(
+        empty_reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+async fn tags_fun(
+    hash: String,
+    tags: Vec<String>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match state.file_store.write().unwrap().set_tags(&hash, tags) {
+        Ok(file_opt) => Ok(file_entry_reply(file_opt)),
+        Err(_) => Ok(file_store_unavailable()),
+    }
+}
+
+// Reply if the file_store can not be read, so a file is not reported as missing by mistake
+fn file_store_unavailable() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        empty_reply(),
+        warp::http::StatusCode::SERVICE_UNAVAILABLE,
+    )
+}
+
+fn file_entry_reply(file_opt: Option<FileEntry>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match file_opt {
+        Some(file_entry) => warp::reply::with_status(
+            warp::reply::json(&file_entry),
+            warp::http::StatusCode::OK,
+        ),
+        None => warp::reply::with_status(empty_reply(), warp::http::StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+struct RetentionRequest {
+    retain_until: i64, // Unix timestamp until which the file is kept
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct HoldResponse {
+    pub hash: String,              // hash of the file
+    pub pinned: bool,              // file is pinned
+    pub retain_until: Option<i64>, // end of the retention hold as unix timestamp
+}
+
+async fn pin_fun(
+    pinned: bool,
+    hash: String,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let file_opt = match state.file_store.write().unwrap().set_pinned(&hash, pinned) {
+        Ok(file_opt) => file_opt,
+        Err(_) => return Ok(file_store_unavailable()),
+    };
+    if let Some(file_entry) = &file_opt {
+        info!("Set pin of {} to {}", file_entry.hash, pinned);
+    }
+    Ok(hold_reply(file_opt))
+}
+
+async fn retention_fun(
+    hash: String,
+    retain_until: Option<i64>,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let file_opt = match state
+        .file_store
+        .write()
+        .unwrap()
+        .set_retention(&hash, retain_until)
+    {
+        Ok(file_opt) => file_opt,
+        Err(_) => return Ok(file_store_unavailable()),
+    };
+    if let Some(file_entry) = &file_opt {
+        info!("Set retention of {} to {:?}", file_entry.hash, retain_until);
+    }
+    Ok(hold_reply(file_opt))
+}
+
+async fn trash_fun(
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let trashed_files = match state.file_store.read().unwrap().trashed_files() {
+        Ok(trashed_files) => trashed_files,
+        Err(_) => return Ok(file_store_unavailable()),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&trashed_files),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn storage_fun(
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match &state.data_dirs {
+        Some(data_dirs) => Ok(warp::reply::with_status(
+            warp::reply::json(&data_dirs.status()),
+            warp::http::StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            empty_reply(),
+            warp::http::StatusCode::CONFLICT,
+        )),
+    }
+}
+
+async fn restore_fun(
+    hash: String,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Moving the file back may copy it inside the BlobStore, so it runs beside the runtime
+    let restored = {
+        let state = state.clone();
+        let hash = hash.clone();
+        tokio::task::spawn_blocking(move || state.restore_file(&hash))
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    };
+
+    match restored {
+        Ok(file_opt) => Ok(hold_reply(file_opt)),
+        Err(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+            error!("{}", err);
+            Ok(insufficient_storage_reply())
+        }
+        Err(err) => {
+            error!("Could not restore {}: {}", hash, err);
+            Ok(warp::reply::with_status(
+                empty_reply(),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RotateKeyResponse {
+    pub key_id: u32, // id of the key which encrypts new files
+}
+
+async fn rotate_key_fun(
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let encryption = match &state.encryption {
+        Some(encryption) => encryption.clone(),
+        None => {
+            error!("Could not rotate key, encryption is disabled");
+            return Ok(warp::reply::with_status(
+                empty_reply(),
+                warp::http::StatusCode::CONFLICT,
+            ));
+        }
+    };
+
+    // Writing the keyring blocks, so it runs beside the runtime
+    let rotated = tokio::task::spawn_blocking(move || encryption.rotate_key())
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+
+    match rotated {
+        Ok(key_id) => {
+            info!("Rotated encryption key, new key {}", key_id);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&RotateKeyResponse { key_id }),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(err) => {
+            error!("Could not rotate key: {}", err);
+            Ok(warp::reply::with_status(
+                empty_reply(),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+fn hold_reply(file_opt: Option<FileEntry>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match file_opt {
+        Some(file_entry) => warp::reply::with_status(
+            warp::reply::json(&HoldResponse {
+                hash: file_entry.hash,
+                pinned: file_entry.pinned,
+                retain_until: file_entry.retain_until,
+            }),
+            warp::http::StatusCode::OK,
+        ),
+        None => warp::reply::with_status(empty_reply(), warp::http::StatusCode::NOT_FOUND),
+    }
+}
+
+// Body of POST /tokens
+#[derive(Deserialize)]
+struct TokenRequest {
+    name: String,       // Description of the token, like the client which uses it
+    scopes: Vec<Scope>, // Endpoints the token may use
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    id: String,            // id to revoke the token
+    name: String,          // description of the token
+    scopes: Vec<Scope>,    // endpoints the token may use
+    created_at: i64,       // creation as unix timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>, // the token itself, only returned once on creation
+}
+
+impl TokenResponse {
+    fn new(api_token: &ApiToken, token: Option<String>) -> TokenResponse {
+        TokenResponse {
+            id: api_token.id.clone(),
+            name: api_token.name.clone(),
+            scopes: api_token.scopes.clone(),
+            created_at: api_token.created_at,
+            token,
+        }
+    }
+}
+
+async fn list_tokens_fun(state: Arc<AppState>) -> Result<impl warp::Reply, warp::Rejection> {
+    let tokens: Vec<TokenResponse> = state
+        .tokens
+        .read()
+        .unwrap()
+        .tokens()
+        .iter()
+        .map(|api_token| TokenResponse::new(api_token, None))
+        .collect();
+    Ok(warp::reply::with_status(
+        warp::reply::json(&tokens),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn create_token_fun(
+    request: TokenRequest,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let created = state
+        .tokens
+        .write()
+        .unwrap()
+        .create(&request.name, &request.scopes);
+
+    match created {
+        Ok((api_token, token)) => Ok(warp::reply::with_status(
+            warp::reply::json(&TokenResponse::new(&api_token, Some(token))),
+            warp::http::StatusCode::CREATED,
+        )),
+        Err(err) => {
+            error!("Could not create API token: {}", err);
+            let status = match err.kind() {
+                std::io::ErrorKind::InvalidInput => warp::http::StatusCode::BAD_REQUEST,
+                _ => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let reply = warp::reply::json(&JsonResponse {
+                status: String::from("error"),
+                message: err.to_string(),
+            });
+            Ok(warp::reply::with_status(reply, status))
+        }
+    }
+}
+
+async fn revoke_token_fun(
+    id: String,
+    state: Arc<AppState>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let revoked = state.tokens.write().unwrap().revoke(&id);
+    match revoked {
+        Ok(Some(api_token)) => Ok(warp::reply::with_status(
+            warp::reply::json(&TokenResponse::new(&api_token, None)),
+            warp::http::StatusCode::OK,
+        )),
+        Ok(None) => Ok(warp::reply::with_status(
+            empty_reply(),
+            warp::http::StatusCode::NOT_FOUND,
+        )),
+        Err(err) => {
+            error!("Could not revoke API token {}: {}", id, err);
+            Ok(warp::reply::with_status(
+                empty_reply(),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+// Conditional headers of a request, see download::Preconditions
+fn preconditions() -> impl Filter<Extract = (Preconditions,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-match")
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("if-range"))
+        .map(
+            |if_match, if_none_match, if_modified_since, if_range| Preconditions {
+                if_match,
+                if_none_match,
+                if_modified_since,
+                if_range,
+            },
+        )
+}
+
+// Why the authorized filter rejected a request
+#[derive(Debug)]
+enum AuthError {
+    Unauthorized, // No token, or a token which is unknown or revoked
+    Forbidden,    // Token without the scope of the endpoint
+}
+
+impl warp::reject::Reject for AuthError {}
+
+/* Passes the requests with a token carrying the scope of the endpoints, requests from the
+ * node itself if trust_local_requests is set in config.json and uploads, as long as no token was
+ * created. Other requests are rejected with an AuthError, which auth_rejection turns into a reply.
+ *
+ * scope: Scope of the endpoints behind the filter
+ */
+fn authorized(
+    scope: Scope,
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::addr::remote())
+        .and_then(move |authorization: Option<String>, remote| {
+            // Pick up tokens created or revoked with the commands while the node is running
+            if app_state.tokens.read().unwrap().is_stale() {
+                if let Err(err) = app_state.tokens.write().unwrap().reload() {
+                    error!("Could not reload the API tokens: {}", err);
+                }
+            }
+
+            let trust_local = app_state.config_store.read().unwrap().trust_local_requests();
+            let tokens = app_state.tokens.read().unwrap();
+            let api_token = authorization
+                .as_deref()
+                .and_then(bearer_token)
+                .and_then(|token| tokens.find(token));
+            let result = match api_token {
+                _ if trust_local && is_local(remote) => Ok(()),
+                _ if scope == Scope::Upload && !tokens.is_enabled() => Ok(()),
+                Some(api_token) if api_token.allows(scope) => Ok(()),
+                Some(_) => Err(warp::reject::custom(AuthError::Forbidden)),
+                None => Err(warp::reject::custom(AuthError::Unauthorized)),
+            };
+            future::ready(result)
+        })
+        .untuple_one()
+}
+
+// Returns the token of an Authorization header with the Bearer scheme
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    Some(token.trim()).filter(|_| scheme.eq_ignore_ascii_case("bearer"))
+}
+
+// Replies to requests rejected by the authorized filter, other rejections are passed on
+async fn auth_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    let (status, challenge, message) = match rejection.find::<AuthError>() {
+        Some(AuthError::Unauthorized) => (
+            warp::http::StatusCode::UNAUTHORIZED,
+            "Bearer",
+            "missing or invalid API token",
+        ),
+        Some(AuthError::Forbidden) => (
+            warp::http::StatusCode::FORBIDDEN,
+            "Bearer error=\"insufficient_scope\"",
+            "API token lacks the scope of this endpoint",
+        ),
+        None => return Err(rejection),
+    };
+
+    let reply = warp::reply::json(&JsonResponse {
+        status: String::from("error"),
+        message: String::from(message),
+    });
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(reply, status),
+        "WWW-Authenticate",
+        challenge,
+    ))
+}
+
+// Returns true for requests from the same machine, which trust_local_requests exempts from tokens
+fn is_local(remote: Option<SocketAddr>) -> bool {
+    match remote.map(|addr| addr.ip()) {
+        Some(IpAddr::V4(ip)) => ip.is_loopback(),
+        Some(IpAddr::V6(ip)) => {
+            ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback())
+        }
+        None => false,
+    }
 }
 
 async fn ping_fun() -> Result<impl warp::Reply, warp::Rejection> {
@@ -221,7 +1557,401 @@ async fn ping_fun() -> Result<impl warp::Reply, warp::Rejection> {
     ))
 }
 
+fn insufficient_storage_reply() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(empty_reply(), warp::http::StatusCode::INSUFFICIENT_STORAGE)
+}
+
 fn empty_reply() -> warp::reply::Json {
     let empty_map: std::collections::HashMap<String, String> = std::collections::HashMap::new();
     warp::reply::json(&empty_map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::tests::open_app_state;
+
+    const LOCAL: &str = "127.0.0.1:50000";
+    const REMOTE: &str = "203.0.113.7:50000";
+
+    fn app_state(dir: &tempfile::TempDir, trust_local_requests: bool) -> Arc<AppState> {
+        let options = serde_json::json!({ "trust_local_requests": trust_local_requests });
+        Arc::new(open_app_state(dir, options).unwrap())
+    }
+
+    fn create_token(app_state: &AppState, scopes: &[Scope]) -> (ApiToken, String) {
+        app_state
+            .tokens
+            .write()
+            .unwrap()
+            .create("test", scopes)
+            .unwrap()
+    }
+
+    // Returns the status of a request to an endpoint behind the authorized filter
+    async fn status(
+        app_state: &Arc<AppState>,
+        scope: Scope,
+        authorization: Option<&str>,
+        remote: &str,
+    ) -> warp::http::StatusCode {
+        let filter = authorized(scope, app_state.clone())
+            .map(empty_reply)
+            .recover(auth_rejection);
+        let mut request = warp::test::request().remote_addr(remote.parse().unwrap());
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        request.reply(&filter).await.status()
+    }
+
+    #[tokio::test]
+    async fn admin_scope_includes_all_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let (_, admin) = create_token(&app_state, &[Scope::Admin]);
+        let (_, upload) = create_token(&app_state, &[Scope::Upload]);
+        let (_, delete) = create_token(&app_state, &[Scope::Delete]);
+        let (_, both) = create_token(&app_state, &[Scope::Upload, Scope::Delete]);
+
+        let cases = [
+            (&admin, Scope::Upload, warp::http::StatusCode::OK),
+            (&admin, Scope::Delete, warp::http::StatusCode::OK),
+            (&admin, Scope::Admin, warp::http::StatusCode::OK),
+            (&upload, Scope::Upload, warp::http::StatusCode::OK),
+            (&upload, Scope::Delete, warp::http::StatusCode::FORBIDDEN),
+            (&upload, Scope::Admin, warp::http::StatusCode::FORBIDDEN),
+            (&delete, Scope::Upload, warp::http::StatusCode::FORBIDDEN),
+            (&delete, Scope::Delete, warp::http::StatusCode::OK),
+            (&both, Scope::Delete, warp::http::StatusCode::OK),
+            (&both, Scope::Admin, warp::http::StatusCode::FORBIDDEN),
+        ];
+        for (token, scope, expected) in &cases {
+            let authorization = format!("Bearer {}", token);
+            let status = status(&app_state, *scope, Some(&authorization), REMOTE).await;
+            assert_eq!(status, *expected, "{:?}", scope);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_revoked_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let (api_token, token) = create_token(&app_state, &[Scope::Upload]);
+        create_token(&app_state, &[Scope::Admin]);
+        let authorization = format!("Bearer {}", token);
+
+        let status_before = status(&app_state, Scope::Upload, Some(&authorization), REMOTE).await;
+        assert_eq!(status_before, warp::http::StatusCode::OK);
+
+        let revoked = app_state
+            .tokens
+            .write()
+            .unwrap()
+            .revoke(&api_token.id)
+            .unwrap();
+        assert!(revoked.is_some());
+        let status_after = status(&app_state, Scope::Upload, Some(&authorization), REMOTE).await;
+        assert_eq!(status_after, warp::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_invalid_bearers() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let (_, token) = create_token(&app_state, &[Scope::Upload]);
+
+        let accepted = [format!("Bearer {}", token), format!("bearer  {} ", token)];
+        for authorization in &accepted {
+            let status = status(&app_state, Scope::Upload, Some(authorization), REMOTE).await;
+            assert_eq!(status, warp::http::StatusCode::OK, "{}", authorization);
+        }
+
+        let rejected = [
+            None,
+            Some(String::new()),
+            Some(String::from("Bearer")),
+            Some(format!("Basic {}", token)),
+            Some(format!("Bearer {}0", token)),
+            Some(token.clone()),
+        ];
+        for authorization in &rejected {
+            let status = status(&app_state, Scope::Upload, authorization.as_deref(), REMOTE).await;
+            assert_eq!(
+                status,
+                warp::http::StatusCode::UNAUTHORIZED,
+                "{:?}",
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejections_carry_a_challenge() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let (_, token) = create_token(&app_state, &[Scope::Upload]);
+        let filter = authorized(Scope::Admin, app_state.clone())
+            .map(empty_reply)
+            .recover(auth_rejection);
+
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), warp::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+
+        let response = warp::test::request()
+            .header("authorization", format!("Bearer {}", token))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()["www-authenticate"],
+            "Bearer error=\"insufficient_scope\""
+        );
+    }
+
+    #[tokio::test]
+    async fn trusts_local_requests_only_if_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        let trusting = app_state(&dir, true);
+        assert_eq!(
+            status(&trusting, Scope::Admin, None, LOCAL).await,
+            warp::http::StatusCode::OK
+        );
+        assert_eq!(
+            status(&trusting, Scope::Admin, None, "[::ffff:127.0.0.1]:50000").await,
+            warp::http::StatusCode::OK
+        );
+        assert_eq!(
+            status(&trusting, Scope::Admin, None, REMOTE).await,
+            warp::http::StatusCode::UNAUTHORIZED
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let strict = app_state(&dir, false);
+        assert_eq!(
+            status(&strict, Scope::Admin, None, LOCAL).await,
+            warp::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn uploads_are_open_until_the_first_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        assert_eq!(
+            status(&app_state, Scope::Upload, None, REMOTE).await,
+            warp::http::StatusCode::OK
+        );
+        assert_eq!(
+            status(&app_state, Scope::Delete, None, REMOTE).await,
+            warp::http::StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app_state, Scope::Admin, None, REMOTE).await,
+            warp::http::StatusCode::UNAUTHORIZED
+        );
+
+        create_token(&app_state, &[Scope::Admin]);
+        assert_eq!(
+            status(&app_state, Scope::Upload, None, REMOTE).await,
+            warp::http::StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_large_forms_before_reserving_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "max_upload_size": 100 });
+        let app_state = Arc::new(open_app_state(&dir, options).unwrap());
+        let capacity_left = app_state.file_store.read().unwrap().capacity_left();
+
+        let body = futures::stream::empty::<Result<bytes::Bytes, warp::Error>>();
+        let reply = upload_multipart_fun(
+            Some(1000),
+            Some(String::from("multipart/form-data; boundary=X")),
+            Some(String::from("application/json")),
+            body,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            warp::Reply::into_response(reply).status(),
+            warp::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            app_state.file_store.read().unwrap().capacity_left(),
+            capacity_left
+        );
+    }
+
+    // Creates an upload of the given length, returns its id
+    async fn tus_create(app_state: &Arc<AppState>, length: u64) -> String {
+        let metadata = format!("filename {}", base64::encode("data.txt"));
+        let reply = tus_create_fun(
+            Some(String::from(TUS_VERSION)),
+            Some(length),
+            Some(metadata),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        let response = warp::Reply::into_response(reply);
+        assert_eq!(response.status(), warp::http::StatusCode::CREATED);
+        let location = response.headers()["location"].to_str().unwrap();
+        String::from(location.strip_prefix("/uploads/").unwrap())
+    }
+
+    async fn tus_append(
+        app_state: &Arc<AppState>,
+        id: &str,
+        offset: u64,
+        content: &'static [u8],
+    ) -> warp::http::Response<Body> {
+        let body = futures::stream::iter(vec![Ok::<_, warp::Error>(bytes::Bytes::from_static(
+            content,
+        ))]);
+        let reply = tus_append_fun(
+            String::from(id),
+            Some(String::from(TUS_VERSION)),
+            Some(offset),
+            Some(String::from("application/offset+octet-stream")),
+            body,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        warp::Reply::into_response(reply)
+    }
+
+    async fn tus_status(app_state: &Arc<AppState>, id: &str) -> warp::http::Response<Body> {
+        let reply = tus_status_fun(
+            String::from(id),
+            Some(String::from(TUS_VERSION)),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        warp::Reply::into_response(reply)
+    }
+
+    fn header<'a>(response: &'a warp::http::Response<Body>, name: &str) -> &'a str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn tus_refuses_appends_at_the_wrong_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let id = tus_create(&app_state, 11).await;
+
+        let response = tus_append(&app_state, &id, 0, b"hello ").await;
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+        assert_eq!(header(&response, "upload-offset"), "6");
+
+        // A client which missed the last reply must ask for the offset first
+        let response = tus_append(&app_state, &id, 0, b"hello ").await;
+        assert_eq!(response.status(), warp::http::StatusCode::CONFLICT);
+        let response = tus_append(&app_state, &id, 11, b"world").await;
+        assert_eq!(response.status(), warp::http::StatusCode::CONFLICT);
+        let response = tus_status(&app_state, &id).await;
+        assert_eq!(header(&response, "upload-offset"), "6");
+
+        let response = tus_append(&app_state, &id, 6, b"world").await;
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+        let hash = header(&response, "upload-hash");
+        let file_store = app_state.file_store.read().unwrap();
+        let file_entry = file_store.get_file(hash).unwrap().unwrap();
+        assert_eq!(file_entry.file_name, "data.txt");
+        assert_eq!(file_store.blob_store().get(hash).unwrap(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn tus_uploads_resume_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = {
+            let app_state = app_state(&dir, false);
+            let id = tus_create(&app_state, 11).await;
+            tus_append(&app_state, &id, 0, b"hello ").await;
+            id
+        };
+
+        let app_state = app_state(&dir, false);
+        let response = tus_status(&app_state, &id).await;
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        assert_eq!(header(&response, "upload-offset"), "6");
+        assert_eq!(header(&response, "upload-length"), "11");
+
+        let response = tus_append(&app_state, &id, 6, b"world").await;
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+        let hash = String::from(header(&response, "upload-hash"));
+
+        // The hash is still known after the upload completed
+        let response = tus_status(&app_state, &id).await;
+        assert_eq!(header(&response, "upload-offset"), "11");
+        assert_eq!(header(&response, "upload-hash"), hash);
+    }
+
+    #[tokio::test]
+    async fn tus_uploads_expire_without_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "upload_expiry": 0 });
+        let app_state = Arc::new(open_app_state(&dir, options).unwrap());
+        let id = tus_create(&app_state, 11).await;
+
+        let expired = app_state.expire_uploads();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, id);
+        let response = tus_status(&app_state, &id).await;
+        assert_eq!(response.status(), warp::http::StatusCode::NOT_FOUND);
+        let response = tus_append(&app_state, &id, 0, b"hello ").await;
+        assert_eq!(response.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn lookups_resolve_legacy_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = app_state(&dir, false);
+        let hash = crate::app_state::tests::upload(&app_state, b"looked up");
+
+        let reply = lookup(hash.legacy_hash.clone(), app_state.clone())
+            .await
+            .unwrap();
+        let response = warp::Reply::into_response(reply);
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["hash"], hash.legacy_hash);
+        assert_eq!(body["content"], serde_json::json!(b"looked up".to_vec()));
+
+        // The access counts for the file, not for a separate entry of its legacy hash
+        app_state.flush_accesses();
+        let file_store = app_state.file_store.read().unwrap();
+        let file_entry = file_store.get_file(&hash.hash).unwrap().unwrap();
+        assert_eq!(file_entry.access_count, 1);
+    }
+
+    #[test]
+    fn refuses_malformed_token_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api_tokens.json");
+        std::fs::write(&path, "{\"tokens\": [").unwrap();
+
+        let err = open_app_state(&dir, serde_json::json!({})).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let message = err.to_string();
+        assert!(
+            message.starts_with("Could not read the API tokens: "),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("api_tokens.json is malformed"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,1407 @@
+use chrono::TimeZone;
+use log::debug;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row, ToSql, Transaction, NO_PARAMS};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::blob_store::BlobStoreFunc;
+use crate::compression::{Encoding, StoredContent};
+use crate::config_store::ContentHash;
+use crate::file_query::{ContentTypeFilter, FilePage, FileQuery, SortOrder};
+use crate::file_store::{
+    self, CorruptedReplica, FileAccess, FileEntry, FileSource, FileStore, FileStoreFunc,
+    RecoverEntry, TrashEntry,
+};
+
+/*
+ * SqliteFileStore
+ * FileStore which keeps the metadata of all files and its queues in a SQLite database
+ * ({path}/file_state.db) instead of file_state.json. Every change is written to the database
+ * immediately in its own transaction, so no state has to be rewritten as a whole.
+ * On first start the existing file_state.json is imported.
+ */
+
+const SCHEMA_VERSION: i64 = 1; // Version of the database schema, stored as user_version
+
+const SCHEMA: &str = "
+    CREATE TABLE files (
+        hash TEXT PRIMARY KEY,
+        legacy_hash TEXT UNIQUE,
+        file_name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        pinned INTEGER NOT NULL DEFAULT 0,
+        retain_until INTEGER,
+        created_at INTEGER NOT NULL DEFAULT 0,
+        source TEXT NOT NULL DEFAULT 'unknown',
+        source_node TEXT,
+        last_accessed INTEGER,
+        access_count INTEGER NOT NULL DEFAULT 0,
+        tags TEXT NOT NULL DEFAULT '[]',
+        encoding TEXT NOT NULL DEFAULT 'identity',
+        stored_size INTEGER
+    );
+    CREATE TABLE recover_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        hash TEXT NOT NULL,
+        last_checked INTEGER NOT NULL,
+        bad_sources TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE distribute_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        hash TEXT NOT NULL
+    );
+    CREATE TABLE rejected_hashes (hash TEXT NOT NULL);
+    CREATE TABLE uploaded_hashes (hash TEXT NOT NULL);
+    CREATE TABLE corrupted_hashes (hash TEXT NOT NULL);
+    CREATE TABLE corrupted_replicas (hash TEXT NOT NULL, node_addr TEXT NOT NULL);
+    CREATE TABLE lost_hashes (hash TEXT NOT NULL);
+    CREATE TABLE refused_deletions (hash TEXT NOT NULL);
+    CREATE TABLE evicted_hashes (hash TEXT NOT NULL);
+    CREATE TABLE trash (
+        hash TEXT PRIMARY KEY,
+        legacy_hash TEXT,
+        file_name TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        pinned INTEGER NOT NULL DEFAULT 0,
+        retain_until INTEGER,
+        created_at INTEGER NOT NULL DEFAULT 0,
+        source TEXT NOT NULL DEFAULT 'unknown',
+        source_node TEXT,
+        last_accessed INTEGER,
+        access_count INTEGER NOT NULL DEFAULT 0,
+        tags TEXT NOT NULL DEFAULT '[]',
+        encoding TEXT NOT NULL DEFAULT 'identity',
+        stored_size INTEGER,
+        deleted_at INTEGER NOT NULL
+    );
+";
+
+const FILE_COLUMNS: &str = "hash, legacy_hash, file_name, content_type, size, pinned, retain_until, created_at, source, source_node, last_accessed, access_count, tags, encoding, stored_size";
+const FILE_COLUMN_COUNT: usize = 15; // Number of columns in FILE_COLUMNS
+
+pub struct SqliteFileStore {
+    path: String,            // Path of the state dir
+    blob_store: Arc<dyn BlobStoreFunc>, // Stores the content of the files
+    capacity: u64,           // Total space on hdd
+    used: u64,               // Space used by all stored files, cached to avoid summing up all sizes
+    reserved: u64,           // Space reserved for running uploads and recoveries
+    written: AtomicU64,      // Part of the reserved space already written to disk
+    conn: Mutex<Connection>, // Connection to the database, a Connection can not be shared between threads
+}
+
+impl FileStoreFunc for SqliteFileStore {
+    fn new(capacity: u64, path: &str, blob_store: Arc<dyn BlobStoreFunc>) -> SqliteFileStore {
+        file_store::prepare_state_dir(path);
+
+        let db_path = format!("{}/file_state.db", path);
+        let conn = match Connection::open(&db_path) {
+            Ok(conn) => conn,
+            Err(err) => panic!("Could not open {}: {}", db_path, err),
+        };
+        if let Err(err) =
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+        {
+            warn!("Could not configure {}: {}", db_path, err);
+        }
+
+        SqliteFileStore::open(conn, capacity, path, blob_store)
+    }
+
+    fn blob_store(&self) -> Arc<dyn BlobStoreFunc> {
+        self.blob_store.clone()
+    }
+
+    fn get_file(&self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.get_file] {}", hash);
+        self.find_file(hash)
+    }
+
+    fn insert_file(
+        &mut self,
+        hash: &ContentHash,
+        content: StoredContent,
+        content_type: &str,
+        file_name: &str,
+        source: FileSource,
+    ) -> std::io::Result<()> {
+        debug!(
+            "[SqliteFileStore.insert_file] hash: {}, file_name: {}",
+            hash.hash, file_name
+        );
+
+        // A failed lookup aborts the insert, the pins and tags of a replaced file must be kept
+        let replaced = self.find_file(&hash.hash)?;
+        let replaced_size = replaced.as_ref().map_or(0, |fe| fe.disk_size());
+        let file_entry = FileEntry::new(hash, content, content_type, file_name, source, replaced);
+
+        self.transaction(|tx| insert_file_entry(tx, &file_entry))?;
+        self.used = self.used.saturating_sub(replaced_size) + file_entry.disk_size();
+        Ok(())
+    }
+
+    fn temp_file_path(&self) -> std::io::Result<String> {
+        file_store::new_temp_file_path(&self.path)
+    }
+
+    fn remove_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.remove_file] {}", hash);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM files WHERE hash = ?1",
+                params![file_entry.hash],
+            )
+        })?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        Ok(Some(file_entry))
+    }
+
+    fn quarantine_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.quarantine_file] {}", hash);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM files WHERE hash = ?1",
+                params![file_entry.hash],
+            )?;
+            tx.execute(
+                "INSERT INTO corrupted_hashes (hash) VALUES (?1)",
+                params![file_entry.hash],
+            )
+        })?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        Ok(Some(file_entry))
+    }
+
+    fn corrupted_hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM corrupted_hashes")
+    }
+
+    fn clear_corrupted_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("corrupted_hashes", reported)
+    }
+
+    fn remove_lost_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.remove_lost_file] {}", hash);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM files WHERE hash = ?1",
+                params![file_entry.hash],
+            )?;
+            tx.execute(
+                "INSERT INTO lost_hashes (hash) VALUES (?1)",
+                params![file_entry.hash],
+            )
+        })?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        Ok(Some(file_entry))
+    }
+
+    fn report_lost_hash(&mut self, hash: &str) -> std::io::Result<()> {
+        self.execute("INSERT INTO lost_hashes (hash) VALUES (?1)", params![hash])
+    }
+
+    fn lost_hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM lost_hashes")
+    }
+
+    fn clear_lost_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("lost_hashes", reported)
+    }
+
+    fn evict_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.evict_file] {}", hash);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM files WHERE hash = ?1",
+                params![file_entry.hash],
+            )?;
+            tx.execute(
+                "INSERT INTO evicted_hashes (hash) VALUES (?1)",
+                params![file_entry.hash],
+            )
+        })?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        Ok(Some(file_entry))
+    }
+
+    fn evicted_hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM evicted_hashes")
+    }
+
+    fn clear_evicted_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("evicted_hashes", reported)
+    }
+
+    fn report_corrupted_replica(&mut self, hash: &str, node_addr: &str) -> std::io::Result<()> {
+        self.execute(
+            "INSERT INTO corrupted_replicas (hash, node_addr) VALUES (?1, ?2)",
+            params![hash, node_addr],
+        )
+    }
+
+    fn corrupted_replicas(&self) -> std::io::Result<Vec<CorruptedReplica>> {
+        let conn = self.conn.lock().unwrap();
+        conn.prepare("SELECT hash, node_addr FROM corrupted_replicas")
+            .and_then(|mut stmt| {
+                stmt.query_map(NO_PARAMS, |row| {
+                    Ok(CorruptedReplica {
+                        hash: row.get(0)?,
+                        node_addr: row.get(1)?,
+                    })
+                })?
+                .collect()
+            })
+            .map_err(query_error)
+    }
+
+    fn clear_corrupted_replicas(&mut self, reported: &[CorruptedReplica]) -> std::io::Result<()> {
+        self.transaction(|tx| {
+            for replica in reported {
+                tx.execute(
+                    "DELETE FROM corrupted_replicas WHERE rowid = (SELECT rowid FROM corrupted_replicas WHERE hash = ?1 AND node_addr = ?2 LIMIT 1)",
+                    params![replica.hash, replica.node_addr],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_pinned(&mut self, hash: &str, pinned: bool) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.set_pinned] {}: {}", hash, pinned);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "UPDATE files SET pinned = ?2 WHERE hash = ?1",
+                params![file_entry.hash, pinned],
+            )
+        })?;
+        self.find_file(&file_entry.hash)
+    }
+
+    fn set_retention(
+        &mut self,
+        hash: &str,
+        retain_until: Option<i64>,
+    ) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.set_retention] {}: {:?}", hash, retain_until);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "UPDATE files SET retain_until = ?2 WHERE hash = ?1",
+                params![file_entry.hash, retain_until],
+            )
+        })?;
+        self.find_file(&file_entry.hash)
+    }
+
+    fn set_tags(&mut self, hash: &str, tags: Vec<String>) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.set_tags] {}: {:?}", hash, tags);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "UPDATE files SET tags = ?2 WHERE hash = ?1",
+                params![file_entry.hash, serde_json::to_string(&tags).unwrap()],
+            )
+        })?;
+        self.find_file(&file_entry.hash)
+    }
+
+    fn record_accesses(&mut self, accesses: &HashMap<String, FileAccess>) -> std::io::Result<()> {
+        self.transaction(|tx| {
+            for (hash, access) in accesses {
+                tx.execute(
+                    "UPDATE files SET last_accessed = ?2, access_count = access_count + ?3 WHERE hash = ?1 OR legacy_hash = ?1",
+                    params![hash, access.last_accessed, access.count as i64],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn refuse_deletion(&mut self, hash: &str) -> std::io::Result<()> {
+        self.execute(
+            "INSERT INTO refused_deletions (hash) VALUES (?1)",
+            params![hash],
+        )
+    }
+
+    fn refused_deletions(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM refused_deletions")
+    }
+
+    fn clear_refused_deletions(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("refused_deletions", reported)
+    }
+
+    fn trash_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.trash_file] {}", hash);
+        let file_entry = match self.find_file(hash)? {
+            Some(file_entry) => file_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO trash ({0}, deleted_at) SELECT {0}, ?2 FROM files WHERE hash = ?1",
+                    FILE_COLUMNS
+                ),
+                params![file_entry.hash, chrono::Utc::now().timestamp()],
+            )?;
+            tx.execute(
+                "DELETE FROM files WHERE hash = ?1",
+                params![file_entry.hash],
+            )
+        })?;
+        self.used = self.used.saturating_sub(file_entry.disk_size());
+        Ok(Some(file_entry))
+    }
+
+    fn restore_file(&mut self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        debug!("[SqliteFileStore.restore_file] {}", hash);
+        let file_entry = match self.trash_entry(hash)? {
+            Some(trash_entry) => trash_entry.file_entry,
+            None => return Ok(None),
+        };
+        let replaced = self.find_file(&file_entry.hash)?;
+        self.transaction(|tx| {
+            insert_file_entry(tx, &file_entry)?;
+            tx.execute("DELETE FROM trash WHERE hash = ?1", params![file_entry.hash])
+        })?;
+        let replaced_size = replaced.map_or(0, |fe| fe.disk_size());
+        self.used = self.used.saturating_sub(replaced_size) + file_entry.disk_size();
+        Ok(Some(file_entry))
+    }
+
+    fn purge_file(&mut self, hash: &str) -> std::io::Result<Option<TrashEntry>> {
+        debug!("[SqliteFileStore.purge_file] {}", hash);
+        let trash_entry = match self.trash_entry(hash)? {
+            Some(trash_entry) => trash_entry,
+            None => return Ok(None),
+        };
+        self.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM trash WHERE hash = ?1",
+                params![trash_entry.file_entry.hash],
+            )
+        })?;
+        Ok(Some(trash_entry))
+    }
+
+    fn trashed_files(&self) -> std::io::Result<Vec<TrashEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!(
+            "SELECT {}, deleted_at FROM trash ORDER BY deleted_at",
+            FILE_COLUMNS
+        );
+        conn.prepare(&sql)
+            .and_then(|mut stmt| stmt.query_map(NO_PARAMS, trash_entry_from_row)?.collect())
+            .map_err(query_error)
+    }
+
+    fn trash_used(&self) -> std::io::Result<u64> {
+        let used: i64 = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COALESCE(SUM(COALESCE(stored_size, size)), 0) FROM trash",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(query_error)?;
+        Ok(used as u64)
+    }
+
+    // The trash takes space on disk as well, so it is purged when the disk runs full
+    fn trash_excess(&self) -> std::io::Result<u64> {
+        let excess =
+            (self.used + self.reserved + self.trash_used()?).saturating_sub(self.capacity());
+        Ok(match self.blob_store.free_space() {
+            Some(free_space) => excess.max(self.unwritten().saturating_sub(free_space)),
+            None => excess,
+        })
+    }
+
+    fn insert_files_to_recover(&mut self, entries: Vec<RecoverEntry>) -> std::io::Result<()> {
+        self.transaction(|tx| {
+            for entry in &entries {
+                let stored: Option<String> = tx
+                    .query_row(
+                        "SELECT hash FROM files WHERE hash = ?1 OR legacy_hash = ?1",
+                        params![entry.hash],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                if stored.is_some() {
+                    tx.execute(
+                        "INSERT INTO rejected_hashes (hash) VALUES (?1)",
+                        params![entry.hash],
+                    )?;
+                    debug!(
+                        "[SqliteFileStore.insert_files_to_recover] Rejected {}",
+                        &entry.hash
+                    );
+                } else {
+                    debug!(
+                        "[SqliteFileStore.insert_files_to_recover] Sync {}",
+                        &entry.hash
+                    );
+                    insert_recover_entry(tx, entry)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn next_file_to_recover(&mut self) -> std::io::Result<Option<RecoverEntry>> {
+        // Same as RecoverEntry::waited_enough
+        let now = chrono::Utc::now().timestamp();
+        let sql = format!(
+            "SELECT id, hash, last_checked, bad_sources, attempts FROM recover_queue WHERE last_checked + ({} << MIN(attempts, {})) < ?1 ORDER BY id LIMIT 1",
+            file_store::RECOVER_DELAY,
+            file_store::MAX_RECOVER_ATTEMPTS
+        );
+
+        self.transaction(|tx| {
+            let row = tx
+                .query_row(&sql, params![now], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, u32>(4)?,
+                    ))
+                })
+                .optional()?;
+
+            let (id, hash, last_checked, bad_sources, attempts) = match row {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            tx.execute("DELETE FROM recover_queue WHERE id = ?1", params![id])?;
+
+            Ok(Some(RecoverEntry {
+                hash,
+                last_checked: chrono::Utc.timestamp_opt(last_checked, 0).unwrap(),
+                bad_sources: serde_json::from_str(&bad_sources).unwrap_or_default(),
+                attempts,
+            }))
+        })
+    }
+
+    fn insert_file_to_distribute(&mut self, hash: &str) -> std::io::Result<()> {
+        self.execute(
+            "INSERT INTO distribute_queue (hash) VALUES (?1)",
+            params![hash],
+        )
+    }
+
+    fn next_file_to_distribute(&mut self) -> std::io::Result<Option<String>> {
+        self.transaction(|tx| {
+            let row = tx
+                .query_row(
+                    "SELECT id, hash FROM distribute_queue ORDER BY id LIMIT 1",
+                    NO_PARAMS,
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                )
+                .optional()?;
+
+            let (id, hash) = match row {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            tx.execute("DELETE FROM distribute_queue WHERE id = ?1", params![id])?;
+
+            Ok(Some(hash))
+        })
+    }
+
+    fn hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM files")
+    }
+
+    fn files(&self) -> std::io::Result<Vec<FileEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!("SELECT {} FROM files", FILE_COLUMNS);
+        conn.prepare(&sql)
+            .and_then(|mut stmt| stmt.query_map(NO_PARAMS, file_entry_from_row)?.collect())
+            .map_err(query_error)
+    }
+
+    fn list_files(&self, query: &FileQuery) -> std::io::Result<FilePage> {
+        let (condition, values) = file_query_condition(query);
+        let order = match query.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let count_sql = format!("SELECT COUNT(*) FROM files WHERE {}", condition);
+        let page_sql = format!(
+            "SELECT {} FROM files WHERE {} ORDER BY {} {}, hash {} LIMIT {} OFFSET {}",
+            FILE_COLUMNS,
+            condition,
+            query.sort.column(),
+            order,
+            order,
+            query.limit(),
+            query.offset.min(i64::MAX as u64)
+        );
+
+        let conn = self.conn.lock().unwrap();
+        let total = conn
+            .query_row(&count_sql, &values, |row| row.get::<_, i64>(0))
+            .map_err(query_error)?;
+        let files = conn
+            .prepare(&page_sql)
+            .and_then(|mut stmt| stmt.query_map(&values, file_entry_from_row)?.collect())
+            .map_err(query_error)?;
+        Ok(FilePage {
+            total: total as u64,
+            offset: query.offset,
+            limit: query.limit(),
+            files,
+        })
+    }
+
+    fn legacy_hashes(&self) -> std::io::Result<HashMap<String, String>> {
+        let conn = self.conn.lock().unwrap();
+        conn.prepare("SELECT hash, legacy_hash FROM files WHERE legacy_hash IS NOT NULL")
+            .and_then(|mut stmt| {
+                stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .map_err(query_error)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.blob_store.capacity().unwrap_or(self.capacity)
+    }
+
+    // Limited by the capacity and by the space actually left on the disk
+    fn capacity_left(&self) -> u64 {
+        let capacity_left = self.capacity().saturating_sub(self.used + self.reserved);
+        match self.blob_store.free_space() {
+            Some(free_space) => capacity_left.min(free_space.saturating_sub(self.unwritten())),
+            None => capacity_left,
+        }
+    }
+
+    fn reserve_space(&mut self, size: u64) -> bool {
+        if size > self.capacity_left() {
+            debug!(
+                "[SqliteFileStore.reserve_space] Not enough space for {} bytes",
+                size
+            );
+            return false;
+        }
+
+        self.reserved += size;
+        true
+    }
+
+    fn mark_written(&self, size: u64) {
+        self.written.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn unmark_written(&self, size: u64) {
+        self.written.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn release_space(&mut self, size: u64) {
+        self.reserved = self.reserved.saturating_sub(size);
+    }
+
+    fn reject_hash(&mut self, hash: &str) -> std::io::Result<()> {
+        self.execute(
+            "INSERT INTO rejected_hashes (hash) VALUES (?1)",
+            params![hash],
+        )
+    }
+
+    fn rejected_hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM rejected_hashes")
+    }
+
+    fn clear_rejected_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("rejected_hashes", reported)
+    }
+
+    // Every change is written to the database immediately, nothing left to do
+    fn serialize_state(&self) {}
+
+    fn uploaded_hashes(&self) -> std::io::Result<Vec<String>> {
+        self.query_hashes("SELECT hash FROM uploaded_hashes")
+    }
+
+    fn add_hash_to_uploaded_hashes(&mut self, hash: &str) -> std::io::Result<()> {
+        self.execute(
+            "INSERT INTO uploaded_hashes (hash) VALUES (?1)",
+            params![hash],
+        )
+    }
+
+    fn clear_uploaded_hashes(&mut self, reported: &[String]) -> std::io::Result<()> {
+        self.remove_reported("uploaded_hashes", reported)
+    }
+}
+
+impl SqliteFileStore {
+    // Creates the FileStore on an open database, which is initialized first
+    fn open(
+        conn: Connection,
+        capacity: u64,
+        path: &str,
+        blob_store: Arc<dyn BlobStoreFunc>,
+    ) -> SqliteFileStore {
+        let db_path = format!("{}/file_state.db", path);
+        let mut file_store = SqliteFileStore {
+            path: String::from(path),
+            blob_store,
+            capacity,
+            used: 0,
+            reserved: 0,
+            written: AtomicU64::new(0),
+            conn: Mutex::new(conn),
+        };
+
+        if let Err(err) = file_store.initialize(capacity) {
+            panic!("Could not initialize {}: {}", db_path, err);
+        }
+
+        let (used, count) = match file_store.conn.lock().unwrap().query_row(
+            "SELECT COALESCE(SUM(COALESCE(stored_size, size)), 0), COUNT(*) FROM files",
+            NO_PARAMS,
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        ) {
+            Ok(row) => row,
+            Err(err) => panic!("Could not read {}: {}", db_path, err),
+        };
+        file_store.used = used as u64;
+        info!("FileStore initialized: {} files", count);
+        file_store
+    }
+
+    /* Creates the schema of a new database and imports the FileEntries of file_state.json.
+     * The JSON state is read with the JSON FileStore, so files with SHA-1 hashes are migrated
+     * and a missing or corrupted file_state.json is rebuilt from the BlobStore. Everything happens
+     * in one transaction, an interrupted import is started again on the next start.
+     */
+    fn initialize(&self, capacity: u64) -> std::io::Result<()> {
+        let version: i64 = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .map_err(query_error)?;
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        if version > SCHEMA_VERSION {
+            panic!(
+                "{}/file_state.db was written by a newer version of the node (version {})",
+                self.path, version
+            );
+        }
+
+        let json_path = format!("{}/file_state.json", self.path);
+        let json_store = FileStore::new(capacity, &self.path, self.blob_store.clone());
+        let files = json_store.files()?;
+
+        // Pending work is imported as well, so it is not lost by switching the store
+        let queues = [
+            ("distribute_queue", json_store.files_to_distribute().to_vec()),
+            ("rejected_hashes", json_store.rejected_hashes()?),
+            ("uploaded_hashes", json_store.uploaded_hashes()?),
+            ("corrupted_hashes", json_store.corrupted_hashes()?),
+            ("lost_hashes", json_store.lost_hashes()?),
+            ("refused_deletions", json_store.refused_deletions()?),
+            ("evicted_hashes", json_store.evicted_hashes()?),
+        ];
+        let trashed_files = json_store.trashed_files()?;
+        let corrupted_replicas = json_store.corrupted_replicas()?;
+
+        self.transaction(|tx| {
+            tx.execute_batch(SCHEMA)?;
+            for file_entry in &files {
+                insert_file_entry(tx, file_entry)?;
+            }
+            for entry in json_store.files_to_recover() {
+                insert_recover_entry(tx, entry)?;
+            }
+            for (table, queue) in &queues {
+                for hash in queue {
+                    tx.execute(
+                        &format!("INSERT INTO {} (hash) VALUES (?1)", table),
+                        params![hash],
+                    )?;
+                }
+            }
+            for trash_entry in &trashed_files {
+                let mut values = file_entry_values(&trash_entry.file_entry);
+                values.push(Box::new(trash_entry.deleted_at));
+                tx.execute(
+                    &format!(
+                        "INSERT INTO trash ({}, deleted_at) VALUES ({})",
+                        FILE_COLUMNS,
+                        placeholders(FILE_COLUMN_COUNT + 1)
+                    ),
+                    values,
+                )?;
+            }
+            for replica in &corrupted_replicas {
+                tx.execute(
+                    "INSERT INTO corrupted_replicas (hash, node_addr) VALUES (?1, ?2)",
+                    params![replica.hash, replica.node_addr],
+                )?;
+            }
+            tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+        })?;
+
+        // Keep the old state, but make sure it is not mistaken for the current one
+        if std::path::Path::new(&json_path).exists() {
+            let _ = std::fs::rename(&json_path, format!("{}.imported", json_path));
+        }
+        info!("Imported {} files from {}", files.len(), json_path);
+
+        Ok(())
+    }
+
+    // Runs f in a transaction, which is rolled back if f fails
+    fn transaction<T, F>(&self, f: F) -> std::io::Result<T>
+    where
+        F: FnOnce(&Transaction) -> rusqlite::Result<T>,
+    {
+        let mut conn = self.conn.lock().unwrap();
+        conn.transaction()
+            .and_then(|tx| {
+                let value = f(&tx)?;
+                tx.commit()?;
+                Ok(value)
+            })
+            .map_err(query_error)
+    }
+
+    // Returns the trashed file for given SHA-256 or SHA-1 hash
+    fn trash_entry(&self, hash: &str) -> std::io::Result<Option<TrashEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!(
+            "SELECT {}, deleted_at FROM trash WHERE hash = ?1 OR legacy_hash = ?1 ORDER BY hash = ?1 DESC LIMIT 1",
+            FILE_COLUMNS
+        );
+        conn.query_row(&sql, params![hash], trash_entry_from_row)
+            .optional()
+            .map_err(query_error)
+    }
+
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> std::io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(sql, params).map_err(query_error)?;
+        Ok(())
+    }
+
+    // Reserved space which is not written to disk yet, the written part is missing in free_space
+    fn unwritten(&self) -> u64 {
+        self.reserved.saturating_sub(self.written.load(Ordering::Relaxed))
+    }
+
+    // Removes one row for every reported hash, rows added after the ping was generated are kept
+    fn remove_reported(&self, table: &str, reported: &[String]) -> std::io::Result<()> {
+        let sql = format!(
+            "DELETE FROM {0} WHERE rowid = (SELECT rowid FROM {0} WHERE hash = ?1 LIMIT 1)",
+            table
+        );
+        self.transaction(|tx| {
+            for hash in reported {
+                tx.execute(&sql, params![hash])?;
+            }
+            Ok(())
+        })
+    }
+
+    // Returns the FileEntry for a SHA-256 or SHA-1 hash
+    fn find_file(&self, hash: &str) -> std::io::Result<Option<FileEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!(
+            "SELECT {} FROM files WHERE hash = ?1 OR legacy_hash = ?1 ORDER BY hash = ?1 DESC LIMIT 1",
+            FILE_COLUMNS
+        );
+        conn.query_row(&sql, params![hash], file_entry_from_row)
+            .optional()
+            .map_err(query_error)
+    }
+
+    // Runs a query which selects a single column of hashes
+    fn query_hashes(&self, sql: &str) -> std::io::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        conn.prepare(sql)
+            .and_then(|mut stmt| stmt.query_map(NO_PARAMS, |row| row.get(0))?.collect())
+            .map_err(query_error)
+    }
+}
+
+fn insert_file_entry(tx: &Transaction, file_entry: &FileEntry) -> rusqlite::Result<()> {
+    tx.execute(
+        &format!(
+            "INSERT OR REPLACE INTO files ({}) VALUES ({})",
+            FILE_COLUMNS,
+            placeholders(FILE_COLUMN_COUNT)
+        ),
+        file_entry_values(file_entry),
+    )?;
+    Ok(())
+}
+
+// Values of a FileEntry in the order of FILE_COLUMNS
+fn file_entry_values(file_entry: &FileEntry) -> Vec<Box<dyn ToSql>> {
+    let (source, source_node) = match &file_entry.source {
+        FileSource::Unknown => ("unknown", None),
+        FileSource::Upload => ("upload", None),
+        FileSource::Recovered { node_addr } => ("recovered", Some(node_addr.clone())),
+    };
+
+    vec![
+        Box::new(file_entry.hash.clone()),
+        Box::new(file_entry.legacy_hash.clone()),
+        Box::new(file_entry.file_name.clone()),
+        Box::new(file_entry.content_type.clone()),
+        Box::new(file_entry.size as i64),
+        Box::new(file_entry.pinned),
+        Box::new(file_entry.retain_until),
+        Box::new(file_entry.created_at),
+        Box::new(source),
+        Box::new(source_node),
+        Box::new(file_entry.last_accessed),
+        Box::new(file_entry.access_count as i64),
+        Box::new(serde_json::to_string(&file_entry.tags).unwrap()),
+        Box::new(file_entry.encoding.name()),
+        Box::new(file_entry.stored_size.map(|size| size as i64)),
+    ]
+}
+
+// Returns the placeholders ?1, ?2, ... for count values
+fn placeholders(count: usize) -> String {
+    (1..=count)
+        .map(|i| format!("?{}", i))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn insert_recover_entry(tx: &Transaction, entry: &RecoverEntry) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO recover_queue (hash, last_checked, bad_sources, attempts) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            entry.hash,
+            entry.last_checked.timestamp(),
+            serde_json::to_string(&entry.bad_sources).unwrap(),
+            entry.attempts
+        ],
+    )?;
+    Ok(())
+}
+
+fn file_entry_from_row(row: &Row) -> rusqlite::Result<FileEntry> {
+    Ok(FileEntry {
+        hash: row.get(0)?,
+        legacy_hash: row.get(1)?,
+        file_name: row.get(2)?,
+        content_type: row.get(3)?,
+        size: row.get::<_, i64>(4)? as u64,
+        pinned: row.get(5)?,
+        retain_until: row.get(6)?,
+        created_at: row.get(7)?,
+        source: match row.get::<_, String>(8)?.as_str() {
+            "upload" => FileSource::Upload,
+            "recovered" => FileSource::Recovered {
+                node_addr: row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+            },
+            _ => FileSource::Unknown,
+        },
+        last_accessed: row.get(10)?,
+        access_count: row.get::<_, i64>(11)? as u64,
+        tags: serde_json::from_str(&row.get::<_, String>(12)?).unwrap_or_default(),
+        encoding: Encoding::from_name(&row.get::<_, String>(13)?),
+        stored_size: row.get::<_, Option<i64>>(14)?.map(|size| size as u64),
+    })
+}
+
+// WHERE clause and its values for the filters of a file listing, see FileQuery::matches
+fn file_query_condition(query: &FileQuery) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut conditions = vec![String::from("1")];
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+    match query.content_type_filter() {
+        Some(ContentTypeFilter::Prefix(prefix)) => {
+            conditions.push(String::from("lower(content_type) LIKE ? ESCAPE '\\'"));
+            values.push(Box::new(format!("{}%", escape_like(&prefix))));
+        }
+        Some(ContentTypeFilter::Exact(media_type)) => {
+            conditions.push(String::from(
+                "(lower(content_type) = ? OR lower(content_type) LIKE ? ESCAPE '\\')",
+            ));
+            let pattern = format!("{};%", escape_like(&media_type));
+            values.push(Box::new(media_type));
+            values.push(Box::new(pattern));
+        }
+        None => {}
+    }
+    if let Some(min_size) = query.min_size {
+        conditions.push(String::from("size >= ?"));
+        values.push(Box::new(min_size.min(i64::MAX as u64) as i64));
+    }
+    if let Some(max_size) = query.max_size {
+        conditions.push(String::from("size <= ?"));
+        values.push(Box::new(max_size.min(i64::MAX as u64) as i64));
+    }
+    if let Some(source) = query.source {
+        conditions.push(String::from("source = ?"));
+        values.push(Box::new(source.name()));
+    }
+    if let Some(tag) = &query.tag {
+        // Tags are stored as a JSON array, one of its elements has to equal the tag
+        conditions.push(String::from(
+            "EXISTS (SELECT 1 FROM json_each(files.tags) WHERE json_each.value = ?)",
+        ));
+        values.push(Box::new(tag.clone()));
+    }
+
+    (conditions.join(" AND "), values)
+}
+
+// Escapes the wildcards of a LIKE pattern, with a backslash as escape character
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Logs a failed query and passes it on to the caller
+fn query_error(err: rusqlite::Error) -> std::io::Error {
+    error!("SQLite query failed: {}", err);
+    std::io::Error::other(err)
+}
+
+fn trash_entry_from_row(row: &Row) -> rusqlite::Result<TrashEntry> {
+    Ok(TrashEntry {
+        file_entry: file_entry_from_row(row)?,
+        deleted_at: row.get(FILE_COLUMN_COUNT)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryBlobStore;
+    use crate::config_store::ContentHasher;
+    use crate::file_query::{SortKey, SourceFilter};
+
+    fn sqlite_store(dir: &tempfile::TempDir) -> SqliteFileStore {
+        let conn = Connection::open_in_memory().unwrap();
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        SqliteFileStore::open(conn, 1 << 30, dir.path().to_str().unwrap(), blob_store)
+    }
+
+    fn content_hash(content: &[u8]) -> ContentHash {
+        let mut hasher = ContentHasher::new();
+        hasher.input(content);
+        hasher.result()
+    }
+
+    // Stores the FileEntry of a file with the given content, the content itself is not needed
+    fn insert(
+        file_store: &mut dyn FileStoreFunc,
+        content: &[u8],
+        content_type: &str,
+        source: FileSource,
+        tags: &[&str],
+    ) -> String {
+        let hash = content_hash(content);
+        let name = String::from_utf8_lossy(content).to_string();
+        let stored = StoredContent::identity(content.len() as u64);
+        file_store
+            .insert_file(&hash, stored, content_type, &name, source)
+            .unwrap();
+        let tags = tags.iter().map(|tag| String::from(*tag)).collect();
+        file_store.set_tags(&hash.hash, tags).unwrap();
+        hash.hash
+    }
+
+    fn page(file_store: &dyn FileStoreFunc, query: &FileQuery) -> (u64, Vec<String>) {
+        let page = file_store.list_files(query).unwrap();
+        let names = page.files.into_iter().map(|fe| fe.file_name).collect();
+        (page.total, names)
+    }
+
+    #[test]
+    fn imports_file_state_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        let mut json_store = FileStore::new(1 << 30, path, blob_store.clone());
+        let kept = insert(
+            &mut json_store,
+            b"kept",
+            "text/plain",
+            FileSource::Upload,
+            &["a"],
+        );
+        let trashed = insert(
+            &mut json_store,
+            b"trashed",
+            "text/plain",
+            FileSource::Unknown,
+            &[],
+        );
+        json_store.set_pinned(&kept, true).unwrap();
+        json_store.trash_file(&trashed).unwrap();
+        json_store.insert_file_to_distribute(&kept).unwrap();
+        json_store.add_hash_to_uploaded_hashes(&kept).unwrap();
+        json_store.reject_hash("rejected").unwrap();
+        json_store.serialize_state();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let mut file_store = SqliteFileStore::open(conn, 1 << 30, path, blob_store);
+
+        let file_entry = file_store.get_file(&kept).unwrap().unwrap();
+        assert!(file_entry.pinned);
+        assert_eq!(file_entry.tags, vec![String::from("a")]);
+        assert_eq!(file_store.files().unwrap().len(), 1);
+        assert_eq!(file_store.used, 4);
+        let trash = file_store.trashed_files().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].file_entry.hash, trashed);
+        assert_eq!(
+            file_store.next_file_to_distribute().unwrap(),
+            Some(kept.clone())
+        );
+        assert_eq!(file_store.uploaded_hashes().unwrap(), vec![kept]);
+        assert_eq!(
+            file_store.rejected_hashes().unwrap(),
+            vec![String::from("rejected")]
+        );
+
+        assert!(!dir.path().join("file_state.json").exists());
+        assert!(dir.path().join("file_state.json.imported").exists());
+    }
+
+    #[test]
+    fn lists_files_like_the_json_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_dir = tempfile::tempdir().unwrap();
+        let mut file_store = sqlite_store(&dir);
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        let mut json_store = FileStore::new(1 << 30, json_dir.path().to_str().unwrap(), blob_store);
+
+        for store in [&mut file_store as &mut dyn FileStoreFunc, &mut json_store] {
+            insert(store, b"a", "image/png", FileSource::Upload, &["a,b"]);
+            insert(store, b"bbb", "image/jpeg", FileSource::Upload, &["ab"]);
+            insert(
+                store,
+                b"cc",
+                "text/plain; charset=utf-8",
+                FileSource::Unknown,
+                &["a"],
+            );
+            let node_addr = String::from("127.0.0.1:8080");
+            insert(
+                store,
+                b"dddd",
+                "text/plain",
+                FileSource::Recovered { node_addr },
+                &[],
+            );
+        }
+
+        let queries = vec![
+            (
+                FileQuery {
+                    sort: SortKey::Size,
+                    order: SortOrder::Asc,
+                    ..Default::default()
+                },
+                (4, vec!["a", "cc", "bbb", "dddd"]),
+            ),
+            (
+                FileQuery {
+                    sort: SortKey::Size,
+                    limit: Some(2),
+                    offset: 1,
+                    ..Default::default()
+                },
+                (4, vec!["bbb", "cc"]),
+            ),
+            (
+                FileQuery {
+                    sort: SortKey::FileName,
+                    content_type: Some(String::from("image/*")),
+                    ..Default::default()
+                },
+                (2, vec!["bbb", "a"]),
+            ),
+            (
+                FileQuery {
+                    content_type: Some(String::from("text/plain")),
+                    sort: SortKey::Size,
+                    ..Default::default()
+                },
+                (2, vec!["dddd", "cc"]),
+            ),
+            (
+                FileQuery {
+                    source: Some(SourceFilter::Upload),
+                    min_size: Some(2),
+                    ..Default::default()
+                },
+                (1, vec!["bbb"]),
+            ),
+            (
+                FileQuery {
+                    tag: Some(String::from("a")),
+                    ..Default::default()
+                },
+                (1, vec!["cc"]),
+            ),
+        ];
+
+        for (query, (total, names)) in queries {
+            let expected = (total, names.into_iter().map(String::from).collect());
+            assert_eq!(page(&file_store, &query), expected, "{:?}", query);
+            assert_eq!(page(&json_store, &query), expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn queues_keep_their_order_until_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = sqlite_store(&dir);
+
+        file_store.insert_file_to_distribute("first").unwrap();
+        file_store.insert_file_to_distribute("second").unwrap();
+        assert_eq!(
+            file_store.next_file_to_distribute().unwrap(),
+            Some(String::from("first"))
+        );
+        assert_eq!(
+            file_store.next_file_to_distribute().unwrap(),
+            Some(String::from("second"))
+        );
+        assert_eq!(file_store.next_file_to_distribute().unwrap(), None);
+
+        file_store.add_hash_to_uploaded_hashes("first").unwrap();
+        file_store.add_hash_to_uploaded_hashes("second").unwrap();
+        assert_eq!(
+            file_store.uploaded_hashes().unwrap(),
+            vec![String::from("first"), String::from("second")]
+        );
+        file_store
+            .clear_uploaded_hashes(&[String::from("first")])
+            .unwrap();
+        assert_eq!(
+            file_store.uploaded_hashes().unwrap(),
+            vec![String::from("second")]
+        );
+    }
+
+    #[test]
+    fn recoveries_wait_longer_after_every_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_dir = tempfile::tempdir().unwrap();
+        let mut file_store = sqlite_store(&dir);
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        let mut json_store = FileStore::new(1 << 30, json_dir.path().to_str().unwrap(), blob_store);
+        let now = chrono::Utc::now().timestamp();
+        let entry = |hash: &str, waited: i64, attempts: u32| RecoverEntry {
+            hash: String::from(hash),
+            last_checked: chrono::Utc.timestamp_opt(now - waited, 0).unwrap(),
+            bad_sources: vec![String::from("http://127.0.0.1:8080")],
+            attempts,
+        };
+
+        for store in [&mut file_store as &mut dyn FileStoreFunc, &mut json_store] {
+            store
+                .insert_files_to_recover(vec![
+                    entry("waiting", 3 * file_store::RECOVER_DELAY, 2),
+                    entry("due", 5 * file_store::RECOVER_DELAY, 2),
+                ])
+                .unwrap();
+
+            let due = store.next_file_to_recover().unwrap().unwrap();
+            assert_eq!(due.hash, "due");
+            assert_eq!(due.attempts, 2);
+            assert_eq!(due.bad_sources, vec![String::from("http://127.0.0.1:8080")]);
+            assert!(store.next_file_to_recover().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn holds_survive_storing_the_file_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = sqlite_store(&dir);
+        let hash = insert(
+            &mut file_store,
+            b"held",
+            "text/plain",
+            FileSource::Upload,
+            &["a"],
+        );
+        let now = chrono::Utc::now().timestamp();
+
+        assert!(!file_store.get_file(&hash).unwrap().unwrap().is_held());
+        assert!(file_store
+            .set_retention(&hash, Some(now + 60))
+            .unwrap()
+            .unwrap()
+            .is_held());
+        assert!(!file_store
+            .set_retention(&hash, Some(now - 60))
+            .unwrap()
+            .unwrap()
+            .is_held());
+        assert!(file_store
+            .set_pinned(&hash, true)
+            .unwrap()
+            .unwrap()
+            .is_held());
+        assert!(file_store.set_pinned("unknown", true).unwrap().is_none());
+
+        insert(
+            &mut file_store,
+            b"held",
+            "text/plain",
+            FileSource::Unknown,
+            &["a"],
+        );
+        let file_entry = file_store.get_file(&hash).unwrap().unwrap();
+        assert!(file_entry.pinned);
+        assert_eq!(file_entry.retain_until, Some(now - 60));
+    }
+
+    #[test]
+    fn written_reservations_do_not_count_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let blob_store = Arc::new(MemoryBlobStore::new());
+        blob_store.set_free_space(Some(1000));
+        let conn = Connection::open_in_memory().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut sqlite_store = SqliteFileStore::open(conn, 1 << 30, path, blob_store.clone());
+        let json_dir = tempfile::tempdir().unwrap();
+        let json_path = json_dir.path().to_str().unwrap();
+        let mut json_store = FileStore::new(1 << 30, json_path, blob_store.clone());
+
+        for file_store in [&mut sqlite_store as &mut dyn FileStoreFunc, &mut json_store] {
+            blob_store.set_free_space(Some(1000));
+            assert!(file_store.reserve_space(600));
+            assert_eq!(file_store.capacity_left(), 400);
+            assert!(!file_store.reserve_space(500));
+
+            // The disk fills up while the reserved bytes are written
+            file_store.mark_written(500);
+            blob_store.set_free_space(Some(500));
+            assert_eq!(file_store.capacity_left(), 400);
+            assert_eq!(file_store.trash_excess().unwrap(), 0);
+
+            file_store.unmark_written(500);
+            file_store.release_space(600);
+            assert_eq!(file_store.capacity_left(), 500);
+        }
+    }
+
+    #[test]
+    fn files_are_found_by_their_legacy_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sqlite_store = sqlite_store(&dir);
+        let json_dir = tempfile::tempdir().unwrap();
+        let json_path = json_dir.path().to_str().unwrap();
+        let mut json_store = FileStore::new(1 << 30, json_path, Arc::new(MemoryBlobStore::new()));
+        let hash = content_hash(b"legacy");
+
+        for file_store in [&mut sqlite_store as &mut dyn FileStoreFunc, &mut json_store] {
+            insert(file_store, b"legacy", "text/plain", FileSource::Upload, &[]);
+            let legacy_hash = hash.legacy_hash.as_str();
+            let file_entry = file_store.get_file(legacy_hash).unwrap().unwrap();
+            assert_eq!(file_entry.hash, hash.hash);
+            assert!(
+                file_store
+                    .set_pinned(legacy_hash, true)
+                    .unwrap()
+                    .unwrap()
+                    .pinned
+            );
+
+            let mut accesses = HashMap::new();
+            let access = FileAccess {
+                last_accessed: 1,
+                count: 3,
+            };
+            accesses.insert(String::from(legacy_hash), access);
+            file_store.record_accesses(&accesses).unwrap();
+            let file_entry = file_store.get_file(&hash.hash).unwrap().unwrap();
+            assert_eq!(file_entry.access_count, 3);
+
+            // The trash resolves the legacy hash as well
+            assert!(file_store.trash_file(legacy_hash).unwrap().is_some());
+            assert!(file_store.get_file(legacy_hash).unwrap().is_none());
+            assert!(file_store.restore_file(legacy_hash).unwrap().is_some());
+            assert!(file_store.get_file(legacy_hash).unwrap().is_some());
+            assert!(file_store.trash_file(legacy_hash).unwrap().is_some());
+            assert!(file_store.purge_file(legacy_hash).unwrap().is_some());
+            assert!(file_store.trashed_files().unwrap().is_empty());
+
+            insert(file_store, b"legacy", "text/plain", FileSource::Upload, &[]);
+            let file_entry = file_store.remove_file(legacy_hash).unwrap().unwrap();
+            assert_eq!(file_entry.hash, hash.hash);
+            assert!(file_store.get_file(legacy_hash).unwrap().is_none());
+            assert!(file_store.files().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn failed_queries_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_store = sqlite_store(&dir);
+        let hash = content_hash(b"file");
+        file_store
+            .conn
+            .lock()
+            .unwrap()
+            .execute_batch("DROP TABLE files; DROP TABLE uploaded_hashes;")
+            .unwrap();
+
+        assert!(file_store.get_file(&hash.hash).is_err());
+        assert!(file_store.uploaded_hashes().is_err());
+        let stored = StoredContent::identity(4);
+        let inserted =
+            file_store.insert_file(&hash, stored, "text/plain", "file", FileSource::Upload);
+        assert!(inserted.is_err());
+        assert_eq!(file_store.used, 0);
+    }
+}
@@ -1,7 +1,9 @@
+use crate::state_file::{self, StateError};
 use chrono::{Datelike, Local, TimeZone};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+
+const STAT_STATE_VERSION: u64 = 2; // Version of the schema of stat_state.json
 
 #[derive(Deserialize, Serialize)]
 pub struct Stat<T> {
@@ -19,6 +21,14 @@ pub struct Stats {
     pub uptime_counter: Stat<u64>,
 }
 
+// Layout of stat_state.json
+#[derive(Deserialize, Serialize)]
+struct StatState<S> {
+    version: u64,
+    #[serde(flatten)]
+    stats: S,
+}
+
 #[derive(Deserialize)]
 pub struct StatStore {
     pub stats: Stats,
@@ -27,10 +37,12 @@ pub struct StatStore {
 
 pub trait StatStoreFunc {
     fn new(stats: Stats, path: String) -> StatStore;
-    fn total_rating(&self, capacity_left: u64) -> f32;      // Returns the total weight of the node
+    fn total_rating(&self, capacity_left: u64, capacity: u64) -> f32; // Returns the total weight of the node
     fn connection_rating(&self) -> f32;
-    fn capacity_rating(&self, capacity_left: u64) -> f32;
+    fn capacity_rating(&self, capacity_left: u64, capacity: u64) -> f32; // Share of the capacity which is still left
+    #[allow(dead_code)]
     fn uptime_rating(&self) -> f32;
+    #[allow(dead_code)]
     fn uptime_left_rating(&self) -> f32;
     fn uptime_count_rating(&self) -> f32;
     fn increase_uptime_counter(&mut self, inc: u64);
@@ -48,10 +60,10 @@ impl StatStoreFunc for StatStore {
         StatStore { stats, path }
     }
 
-    fn total_rating(&self, capacity_left: u64) -> f32 {
-        let ratings = vec![
+    fn total_rating(&self, capacity_left: u64, capacity: u64) -> f32 {
+        let ratings = [
             self.connection_rating(),
-            self.capacity_rating(capacity_left),
+            self.capacity_rating(capacity_left, capacity),
             // self.uptime_rating(),
             // self.uptime_left_rating(),
             self.uptime_count_rating(),
@@ -66,13 +78,13 @@ impl StatStoreFunc for StatStore {
 
         if speed < 6000 {
             speed_rating = 0.1;
-        } else if speed >= 6000 && speed < 16000 {
+        } else if (6000..16000).contains(&speed) {
             speed_rating = 0.3;
-        } else if speed >= 16000 && speed < 50000 {
+        } else if (16000..50000).contains(&speed) {
             speed_rating = 0.4;
-        } else if speed >= 50000 && speed < 200000 {
+        } else if (50000..200000).contains(&speed) {
             speed_rating = 0.6;
-        } else if speed >= 200000 && speed < 1000000 {
+        } else if (200000..1000000).contains(&speed) {
             speed_rating = 0.8;
         } else {
             speed_rating = 1.0;
@@ -93,17 +105,28 @@ impl StatStoreFunc for StatStore {
         let now = Local::now();
         let total_uptime_in_minutes =
             ((self.stats.uptime.value[1] - self.stats.uptime.value[0]) * 60) as f32;
-        let down =
-            Local
-                .ymd(now.year(), now.month(), now.day())
-                .and_hms(self.stats.uptime.value[1], 0, 0);
+        let down = Local
+            .with_ymd_and_hms(
+                now.year(),
+                now.month(),
+                now.day(),
+                self.stats.uptime.value[1],
+                0,
+                0,
+            )
+            .unwrap();
         let minutes_left = down.signed_duration_since(now).num_minutes() as f32;
 
         (minutes_left / total_uptime_in_minutes) * self.stats.uptime.weight
     }
 
-    fn capacity_rating(&self, capacity_left: u64) -> f32 {
-        (capacity_left as f32 / self.stats.capacity.value as f32) * self.stats.capacity.weight
+    // capacity_left is limited by the free space on disk, so a full disk rates like a full quota
+    fn capacity_rating(&self, capacity_left: u64, capacity: u64) -> f32 {
+        if capacity == 0 {
+            return 0.0;
+        }
+        let share = (capacity_left as f32 / capacity as f32).min(1.0);
+        share * self.stats.capacity.weight
     }
 
     fn uptime_count_rating(&self) -> f32 {
@@ -124,17 +147,56 @@ impl StatStoreFunc for StatStore {
 
     fn serialize_state(&self) {
         let path = format!("{}/stat_state.json", self.path);
-        let serialized = serde_json::to_string(&self.stats).unwrap();
-        let mut file = File::create(path).unwrap();
-        let _ = file.write_all(serialized.as_bytes());
+        let state = StatState {
+            version: STAT_STATE_VERSION,
+            stats: &self.stats,
+        };
+        let serialized = serde_json::to_string(&state).unwrap();
+        if let Err(err) = state_file::write_atomic(&path, serialized.as_bytes()) {
+            error!("Could not write {}: {}", path, err);
+        }
     }
 
     fn deserialize_state(path: &str) -> Stats {
         let complete_path = format!("{}/stat_state.json", path);
-        let data = std::fs::read_to_string(complete_path).expect("Unable to read file");
-        let config: Stats = serde_json::from_str(&data).expect("JSON was not well-formatted");
-        return config;
+        let backup_path = format!("{}.bak", complete_path);
+
+        // Keep a copy of the last readable state, in case the file gets corrupted later on
+        match StatStore::read_stats(&complete_path) {
+            Ok(stats) => {
+                let _ = std::fs::copy(&complete_path, &backup_path);
+                return stats;
+            }
+            Err(err) => {
+                error!("Could not read {}: {}", complete_path, err);
+                match StatStore::read_stats(&backup_path) {
+                    Ok(stats) => {
+                        warn!("Using last readable state from {}", backup_path);
+                        return stats;
+                    }
+                    Err(_) => panic!("Unable to read {}: {}", complete_path, err),
+                }
+            }
+        }
     }
 }
 
-impl StatStore {}
+impl StatStore {
+    fn read_stats(path: &str) -> Result<Stats, StateError> {
+        let migrations: [state_file::Migration; 1] = [StatStore::migrate_state_v1];
+        let value = state_file::read_state(path, &migrations)?.ok_or_else(|| {
+            StateError::Io(std::io::Error::from(std::io::ErrorKind::NotFound))
+        })?;
+        let state: StatState<Stats> =
+            serde_json::from_value(value).map_err(|err| StateError::Malformed(err.to_string()))?;
+        Ok(state.stats)
+    }
+
+    // Version 1 contained the stats without a version field
+    fn migrate_state_v1(mut stats: serde_json::Value) -> serde_json::Value {
+        if let Some(stats) = stats.as_object_mut() {
+            stats.insert(String::from("version"), serde_json::json!(2));
+        }
+        stats
+    }
+}
@@ -0,0 +1,210 @@
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/*
+ * StateFile
+ * Reading and writing of the JSON files in the state directory.
+ * Files are written to a temporary file first, synced to disk and then renamed, so a crash
+ * never leaves a half written state behind. Every file carries a schema version, older files
+ * are upgraded step by step with the migrations of the store they belong to.
+ */
+
+// Upgrades the JSON value of a state file by one version
+pub type Migration = fn(Value) -> Value;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),        // File could not be read
+    Malformed(String),         // File is not valid JSON or does not match the schema
+    UnsupportedVersion(u64),   // File was written by a newer version of the node
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateError::Io(err) => write!(f, "{}", err),
+            StateError::Malformed(err) => write!(f, "malformed state: {}", err),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported state version {}", version)
+            }
+        }
+    }
+}
+
+/* Write content to path without ever leaving a partially written file
+ *
+ * path: Location of the state file
+ * content: New content of the file
+ */
+pub fn write_atomic(path: &str, content: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, content, false)
+}
+
+// Same as write_atomic, for secrets: the file is only readable by the owner from the start
+pub fn write_atomic_private(path: &str, content: &[u8]) -> std::io::Result<()> {
+    write_atomic_with(path, content, true)
+}
+
+fn write_atomic_with(path: &str, content: &[u8], private: bool) -> std::io::Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        // The mode only applies to new files, a temporary file left by a crash is replaced
+        let _ = std::fs::remove_file(&temp_path);
+        options.create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+    }
+
+    let mut file = options.open(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    // Sync the directory, so the rename itself survives a crash
+    if let Some(dir) = Path::new(path).parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+/* Read a state file and upgrade it to the latest version
+ * Returns None if the file does not exist. Files without a version field are version 1.
+ *
+ * path: Location of the state file
+ * migrations: migrations[i] upgrades a file from version i + 1 to i + 2
+ */
+pub fn read_state(path: &str, migrations: &[Migration]) -> Result<Option<Value>, StateError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(StateError::Io(err)),
+    };
+
+    let mut value: Value =
+        serde_json::from_str(&content).map_err(|err| StateError::Malformed(err.to_string()))?;
+
+    let latest = migrations.len() as u64 + 1;
+    let mut version = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+    if version == 0 || version > latest {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    while version < latest {
+        value = migrations[version as usize - 1](value);
+        version += 1;
+    }
+
+    Ok(Some(value))
+}
+
+// Move a state file which can not be read out of the way, to keep it for inspection
+pub fn set_aside(path: &str) {
+    let corrupt_path = format!("{}.corrupt-{}", path, chrono::Utc::now().timestamp());
+    let _ = std::fs::rename(path, corrupt_path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryBlobStore;
+    use crate::file_store::{FileStore, FileStoreFunc};
+    use std::sync::Arc;
+
+    // Records the version it upgraded from, so the order of the migrations is visible
+    fn step(mut value: Value) -> Value {
+        let version = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+        value["version"] = serde_json::json!(version + 1);
+        if let Some(steps) = value["steps"].as_array_mut() {
+            steps.push(serde_json::json!(version));
+        }
+        value
+    }
+
+    fn read(dir: &tempfile::TempDir, content: &str) -> Result<Option<Value>, StateError> {
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, content).unwrap();
+        read_state(path.to_str().unwrap(), &[step, step])
+    }
+
+    #[test]
+    fn old_states_are_migrated_step_by_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        assert!(read_state(missing.to_str().unwrap(), &[step])
+            .unwrap()
+            .is_none());
+
+        // Files without a version are version 1
+        let value = read(&dir, r#"{ "steps": [] }"#).unwrap().unwrap();
+        assert_eq!(value, serde_json::json!({ "version": 3, "steps": [1, 2] }));
+        let value = read(&dir, r#"{ "version": 2, "steps": [] }"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, serde_json::json!({ "version": 3, "steps": [2] }));
+        let value = read(&dir, r#"{ "version": 3, "steps": [] }"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, serde_json::json!({ "version": 3, "steps": [] }));
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            read(&dir, r#"{ "version": 4 }"#),
+            Err(StateError::UnsupportedVersion(4))
+        ));
+        assert!(matches!(
+            read(&dir, r#"{ "version": 0 }"#),
+            Err(StateError::UnsupportedVersion(0))
+        ));
+        assert!(matches!(read(&dir, "{"), Err(StateError::Malformed(_))));
+    }
+
+    #[test]
+    fn writes_replace_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+        write_atomic(path, b"first version, which is longer").unwrap();
+        write_atomic(path, b"second").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"second");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn file_state_of_version_1_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = serde_json::json!({
+            "a": { "hash": "a", "file_name": "a.txt", "content_type": "text/plain", "size": 1 },
+        });
+        std::fs::write(dir.path().join("file_state.json"), state.to_string()).unwrap();
+
+        let path = dir.path().to_str().unwrap();
+        let file_store = FileStore::new(1 << 30, path, Arc::new(MemoryBlobStore::new()));
+        let file_entry = file_store.get_file("a").unwrap().unwrap();
+        assert_eq!(file_entry.file_name, "a.txt");
+        assert!(file_store.files_to_recover().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "newer version")]
+    fn file_state_of_a_newer_node_is_not_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = serde_json::json!({ "version": 99, "files": {} });
+        std::fs::write(dir.path().join("file_state.json"), state.to_string()).unwrap();
+
+        let path = dir.path().to_str().unwrap();
+        FileStore::new(1 << 30, path, Arc::new(MemoryBlobStore::new()));
+    }
+}
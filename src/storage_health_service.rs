@@ -0,0 +1,72 @@
+use log::{error, info};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::app_state::AppState;
+use crate::fsck;
+
+/*
+ * StorageHealthService
+ * Checks all data dirs regularly, see MultiDirBlobStore. When a data dir was taken out, the
+ * files stored in it are reported lost to the monitor and recovered from other nodes. When it
+ * is taken back in, its files are adopted again. Does nothing if no data dirs are configured.
+ *
+ * timeout: The amount of time in seconds between two health checks
+ */
+
+pub struct StorageHealthService {
+    pub app_state: Arc<AppState>,
+    pub timeout: u64,
+}
+
+impl StorageHealthService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Storage health service started");
+            let stop_services = self.app_state.stop_services.clone();
+
+            loop {
+                self.check().await;
+
+                // If flag is set, exit thread
+                if stop_services.load(Ordering::Relaxed) {
+                    info!("Shutting down storage health service");
+                    break;
+                }
+
+                tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        info!("Storage health service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, timeout: u64) -> StorageHealthService {
+        StorageHealthService { app_state, timeout }
+    }
+
+    // Probe all data dirs and reconcile the stored files if a dir was taken out or back in
+    async fn check(&self) {
+        let data_dirs = match &self.app_state.data_dirs {
+            Some(data_dirs) => data_dirs.clone(),
+            None => return,
+        };
+
+        let app_state = self.app_state.clone();
+        let checked = tokio::task::spawn_blocking(move || {
+            data_dirs.check_health();
+            if data_dirs.take_changed() {
+                fsck::reconcile(&app_state);
+                app_state.force_ping.swap(true, Ordering::Relaxed);
+            }
+        })
+        .await;
+
+        if let Err(err) = checked {
+            error!("Could not check data dirs: {}", err);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::time::SystemTime;
+
+use crate::encryption::random_bytes;
+use crate::state_file;
+
+/*
+ * TokenStore
+ * API tokens which authorize requests to the upload, delete and admin endpoints. Clients send
+ * them in the header Authorization: Bearer <token>. The tokens are kept in
+ * {path}/api_tokens.json, which only holds their SHA-256 hashes, so a token is shown only once
+ * when it is created. Every token carries scopes, the admin scope includes all others.
+ * The first token is created offline with node-app create-token, which writes the file directly.
+ * A running node picks up such changes the next time a token is checked.
+ */
+
+const TOKEN_LEN: usize = 32; // Random bytes of a token, sent hex encoded
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Upload, // Upload files, with a form, PUT /files or the tus protocol
+    Delete, // Delete files
+    Admin,  // Holds, tags, the trash, the storage, the encryption key and the tokens
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scope> {
+        match name {
+            "upload" => Some(Scope::Upload),
+            "delete" => Some(Scope::Delete),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApiToken {
+    pub id: String,         // Random id, which names the token when it is listed or revoked
+    pub name: String,       // Given at creation, like the client which uses the token
+    pub scopes: Vec<Scope>, // Endpoints the token may use
+    pub created_at: i64,    // Unix timestamp
+    hash: String,           // Hex encoded SHA-256 hash of the token
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+// Content of api_tokens.json
+#[derive(Deserialize, Serialize)]
+struct TokenFile {
+    tokens: Vec<ApiToken>,
+}
+
+pub struct TokenStore {
+    path: String,                 // Path of api_tokens.json
+    tokens: Vec<ApiToken>,        // All tokens, oldest first
+    modified: Option<SystemTime>, // Modification time of api_tokens.json when it was last read
+}
+
+impl TokenStore {
+    // Reads the tokens from the state dir, fails if the file exists but can not be read
+    pub fn open(path: &str) -> std::io::Result<TokenStore> {
+        let path = format!("{}/api_tokens.json", path);
+        let modified = modified(&path);
+        let tokens = read_tokens(&path)?;
+
+        info!("TokenStore initialized: {} tokens", tokens.len());
+        Ok(TokenStore {
+            path,
+            tokens,
+            modified,
+        })
+    }
+
+    // Returns true if api_tokens.json was changed by someone else, like the create-token command
+    pub fn is_stale(&self) -> bool {
+        modified(&self.path) != self.modified
+    }
+
+    /* Reads the tokens again if api_tokens.json was changed. If the file can not be read, the
+     * tokens are kept and the file is not read again until it changes once more.
+     */
+    pub fn reload(&mut self) -> std::io::Result<()> {
+        if !self.is_stale() {
+            return Ok(());
+        }
+
+        self.modified = modified(&self.path);
+        self.tokens = read_tokens(&self.path)?;
+        info!("API tokens reloaded: {} tokens", self.tokens.len());
+        Ok(())
+    }
+
+    // Returns true once a token exists, before that uploads are open to everyone
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    // Returns the token sent by a client, None if it is unknown or revoked
+    pub fn find(&self, token: &str) -> Option<&ApiToken> {
+        let hash = hash_token(token);
+        self.tokens.iter().find(|api_token| api_token.hash == hash)
+    }
+
+    pub fn tokens(&self) -> &[ApiToken] {
+        &self.tokens
+    }
+
+    /* Adds a new token. Returns it together with the token itself, which is not stored.
+     *
+     * name: Description of the token, like the client which uses it
+     * scopes: Endpoints the token may use, at least one
+     */
+    pub fn create(&mut self, name: &str, scopes: &[Scope]) -> std::io::Result<(ApiToken, String)> {
+        if scopes.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a token needs at least one scope",
+            ));
+        }
+
+        self.reload()?;
+
+        let mut secret = [0; TOKEN_LEN];
+        random_bytes(&mut secret);
+        let token = to_hex(&secret);
+
+        let mut id = String::new();
+        while id.is_empty() || self.tokens.iter().any(|api_token| api_token.id == id) {
+            let mut bytes = [0; 4];
+            random_bytes(&mut bytes);
+            id = to_hex(&bytes);
+        }
+
+        let mut unique_scopes = Vec::new();
+        for scope in scopes {
+            if !unique_scopes.contains(scope) {
+                unique_scopes.push(*scope);
+            }
+        }
+
+        let api_token = ApiToken {
+            id,
+            name: String::from(name),
+            scopes: unique_scopes,
+            created_at: chrono::Utc::now().timestamp(),
+            hash: hash_token(&token),
+        };
+
+        let mut tokens = self.tokens.clone();
+        tokens.push(api_token.clone());
+        self.save(&tokens)?;
+        self.tokens = tokens;
+
+        info!("Created API token {} ({})", api_token.id, api_token.name);
+        Ok((api_token, token))
+    }
+
+    // Removes the token with the given id, returns None if there is none
+    pub fn revoke(&mut self, id: &str) -> std::io::Result<Option<ApiToken>> {
+        self.reload()?;
+
+        let position = match self.tokens.iter().position(|api_token| api_token.id == id) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        let mut tokens = self.tokens.clone();
+        let api_token = tokens.remove(position);
+        self.save(&tokens)?;
+        self.tokens = tokens;
+
+        info!("Revoked API token {} ({})", api_token.id, api_token.name);
+        Ok(Some(api_token))
+    }
+
+    // Writes the tokens to disk, only readable by the owner
+    fn save(&mut self, tokens: &[ApiToken]) -> std::io::Result<()> {
+        let file = TokenFile {
+            tokens: tokens.to_vec(),
+        };
+        let serialized = serde_json::to_string_pretty(&file).unwrap();
+        state_file::write_atomic_private(&self.path, serialized.as_bytes())?;
+        self.modified = modified(&self.path);
+        Ok(())
+    }
+}
+
+// Reads the tokens from api_tokens.json, no file means no tokens
+fn read_tokens(path: &str) -> std::io::Result<Vec<ApiToken>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(Error::new(
+                err.kind(),
+                format!("could not read {}: {}", path, err),
+            ))
+        }
+    };
+
+    match serde_json::from_str::<TokenFile>(&content) {
+        Ok(file) => Ok(file.tokens),
+        Err(err) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is malformed: {}", path, err),
+        )),
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_kept_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        // A temporary file left by a crash must not pass on its permissions
+        std::fs::write(dir.path().join("api_tokens.json.tmp"), "").unwrap();
+
+        let mut tokens = TokenStore::open(path).unwrap();
+        let (api_token, token) = tokens.create("test", &[Scope::Upload]).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let tokens_path = dir.path().join("api_tokens.json");
+            let mode = std::fs::metadata(&tokens_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!dir.path().join("api_tokens.json.tmp").exists());
+
+        let tokens = TokenStore::open(path).unwrap();
+        assert_eq!(tokens.find(&token).unwrap().id, api_token.id);
+    }
+}
@@ -0,0 +1,177 @@
+use chrono::Utc;
+use log::{error, info};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::app_state::AppState;
+use crate::config_store::ConfigStoreFunc;
+
+/*
+ * TrashService
+ * Purges files which the monitor asked to delete and which were moved to the trash. A file is
+ * purged when its retention expired, or earlier if the trash takes space needed by new files.
+ * In that case the oldest deletions are purged first. Files in the trash are not counted in
+ * capacity_left, so the monitor does not have to wait for them to go away.
+ *
+ * timeout: The amount of time in seconds between two checks of the trash
+ */
+
+pub struct TrashService {
+    pub app_state: Arc<AppState>,
+    pub timeout: u64,
+}
+
+impl TrashService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Trash service started");
+            let stop_services = self.app_state.stop_services.clone();
+
+            loop {
+                self.purge().await;
+
+                // If flag is set, exit thread
+                if stop_services.load(Ordering::Relaxed) {
+                    info!("Shutting down trash service");
+                    break;
+                }
+
+                tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        info!("Trash service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, timeout: u64) -> TrashService {
+        TrashService { app_state, timeout }
+    }
+
+    // Purge all expired files, then the oldest ones as long as the trash takes too much space
+    async fn purge(&self) {
+        let retention = self
+            .app_state
+            .config_store
+            .read()
+            .unwrap()
+            .trash_retention() as i64;
+        let expired_before = Utc::now().timestamp() - retention;
+
+        let trash = {
+            let file_store = self.app_state.file_store.read().unwrap();
+            file_store
+                .trashed_files()
+                .and_then(|trashed_files| Ok((trashed_files, file_store.trash_excess()?)))
+        };
+        let (trashed_files, mut excess) = match trash {
+            Ok(trash) => trash,
+            Err(err) => {
+                error!("Could not read the trash: {}", err);
+                return;
+            }
+        };
+
+        for trash_entry in trashed_files {
+            if trash_entry.deleted_at > expired_before && excess == 0 {
+                // Entries are sorted by their deletion, all remaining ones are younger
+                break;
+            }
+
+            let app_state = self.app_state.clone();
+            let hash = trash_entry.file_entry.hash.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || app_state.purge_file(&hash)).await
+            {
+                error!("Could not purge {}: {}", trash_entry.file_entry.hash, err);
+                continue;
+            }
+            excess = excess.saturating_sub(trash_entry.file_entry.disk_size());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::tests::{open_app_state, upload};
+
+    // Moves the file to the trash like a deletion requested by the monitor
+    fn trash(app_state: &AppState, hash: &str) {
+        app_state.remove_file_on_request(hash);
+        let file_store = app_state.file_store.read().unwrap();
+        assert!(file_store.get_file(hash).unwrap().is_none());
+    }
+
+    fn trashed_hashes(app_state: &AppState) -> Vec<String> {
+        let file_store = app_state.file_store.read().unwrap();
+        let trashed_files = file_store.trashed_files().unwrap();
+        trashed_files
+            .into_iter()
+            .map(|entry| entry.file_entry.hash)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn expired_files_are_purged() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = serde_json::json!({ "trash_retention": 1 });
+        let app_state = Arc::new(open_app_state(&dir, options).unwrap());
+        let hash = upload(&app_state, b"deleted").hash;
+        trash(&app_state, &hash);
+        let service = TrashService::new(app_state.clone(), 0);
+
+        service.purge().await;
+        assert_eq!(trashed_hashes(&app_state), vec![hash.clone()]);
+
+        tokio::time::delay_for(Duration::from_millis(1100)).await;
+        service.purge().await;
+        assert!(trashed_hashes(&app_state).is_empty());
+        let blob_store = app_state.file_store.read().unwrap().blob_store();
+        assert!(blob_store.restore(&hash).is_err());
+    }
+
+    #[tokio::test]
+    async fn oldest_files_are_purged_when_space_runs_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_state = Arc::new(open_app_state(&dir, serde_json::json!({})).unwrap());
+        let older = upload(&app_state, b"deleted first").hash;
+        let newer = upload(&app_state, b"deleted second").hash;
+        trash(&app_state, &older);
+        trash(&app_state, &newer);
+        let service = TrashService::new(app_state.clone(), 0);
+
+        // Files in the trash fit besides the stored ones, nothing is purged before it expires
+        service.purge().await;
+        assert_eq!(trashed_hashes(&app_state).len(), 2);
+
+        // Reservations leave room for one of the files only
+        let trashed_files = app_state
+            .file_store
+            .read()
+            .unwrap()
+            .trashed_files()
+            .unwrap();
+        let (first, second) = (&trashed_files[0], &trashed_files[1]);
+        {
+            let mut file_store = app_state.file_store.write().unwrap();
+            let capacity_left = file_store.capacity_left();
+            assert!(file_store.reserve_space(capacity_left - second.file_entry.disk_size()));
+            assert_eq!(
+                file_store.trash_excess().unwrap(),
+                first.file_entry.disk_size()
+            );
+        }
+
+        service.purge().await;
+        assert_eq!(
+            trashed_hashes(&app_state),
+            vec![second.file_entry.hash.clone()]
+        );
+        assert_eq!(
+            app_state.file_store.read().unwrap().trash_excess().unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use log::info;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+
+use crate::app_state::AppState;
+
+/*
+ * UploadExpiryService
+ * Removes resumable uploads which made no progress for upload_expiry seconds, together with the
+ * bytes received so far. Completed uploads are removed the same way, they only remember the
+ * hash of their file.
+ *
+ * timeout: The amount of time in seconds between two checks of the uploads
+ */
+
+pub struct UploadExpiryService {
+    pub app_state: Arc<AppState>,
+    pub timeout: u64,
+}
+
+impl UploadExpiryService {
+    pub async fn start(self) -> std::io::Result<()> {
+        tokio::spawn(async move {
+            info!("Upload expiry service started");
+            let stop_services = self.app_state.stop_services.clone();
+
+            loop {
+                for upload in self.app_state.expire_uploads() {
+                    info!(
+                        "Upload {} expired after {} of {} bytes",
+                        upload.id, upload.offset, upload.length
+                    );
+                }
+
+                // If flag is set, exit thread
+                if stop_services.load(Ordering::Relaxed) {
+                    info!("Shutting down upload expiry service");
+                    break;
+                }
+
+                tokio::time::delay_for(Duration::from_secs(self.timeout)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        info!("Upload expiry service terminated");
+        Ok(())
+    }
+
+    pub fn new(app_state: Arc<AppState>, timeout: u64) -> UploadExpiryService {
+        UploadExpiryService { app_state, timeout }
+    }
+}
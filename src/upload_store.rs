@@ -0,0 +1,320 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::state_file;
+
+/*
+ * UploadStore
+ * Keeps the resumable uploads of the tus protocol in {path}/uploads. Every upload has a data file
+ * {id}, which holds the bytes received so far, and {id}.json with its length and metadata, so an
+ * upload can be resumed after a restart of the node. The offset of an upload is the size of its
+ * data file. Completed uploads are kept until they expire, so a client which lost the reply to
+ * its last request can still learn the hash of its file.
+ */
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Upload {
+    pub id: String,
+    pub length: u64,          // Size of the complete file
+    #[serde(skip)]
+    pub offset: u64,          // Bytes received so far, the size of the data file
+    pub file_name: String,
+    pub content_type: String,
+    pub expires_at: i64,      // Unix timestamp when the upload is removed, if it makes no progress
+    pub hash: Option<String>, // SHA-256 hash of the file, once the upload is completed and stored
+    #[serde(skip)]
+    pub busy: bool,           // A request is appending to the upload
+}
+
+impl Upload {
+    pub fn is_complete(&self) -> bool {
+        self.hash.is_some()
+    }
+}
+
+pub struct UploadStore {
+    path: String,                     // Path of the uploads dir
+    uploads: HashMap<String, Upload>, // All uploads by their id
+}
+
+impl UploadStore {
+    // Loads all uploads of the state dir, uploads with missing or broken files are removed
+    pub fn new(path: &str) -> UploadStore {
+        let path = Path::new(path)
+            .join("uploads")
+            .to_string_lossy()
+            .into_owned();
+        if let Err(err) = std::fs::create_dir_all(&path) {
+            panic!("Could not create {}: {}", path, err);
+        }
+
+        let mut store = UploadStore {
+            path,
+            uploads: HashMap::new(),
+        };
+        for upload in store.read_uploads() {
+            store.uploads.insert(upload.id.clone(), upload);
+        }
+
+        info!("UploadStore initialized: {} uploads", store.uploads.len());
+        store
+    }
+
+    fn read_uploads(&self) -> Vec<Upload> {
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Could not read {}: {}", self.path, err);
+                return Vec::new();
+            }
+        };
+
+        let mut uploads = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let id = match name.strip_suffix(".json") {
+                Some(id) => String::from(id),
+                None => continue,
+            };
+
+            let upload = std::fs::read_to_string(entry.path())
+                .map_err(|err| err.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<Upload>(&json).map_err(|err| err.to_string())
+                });
+            match upload {
+                Ok(mut upload) if upload.id == id => {
+                    upload.offset = match upload.is_complete() {
+                        true => upload.length,
+                        false => std::fs::metadata(self.data_path(&id))
+                            .map(|metadata| metadata.len().min(upload.length))
+                            .unwrap_or(0),
+                    };
+                    uploads.push(upload);
+                }
+                Ok(_) => warn!("Upload {} does not match its file name, removed", id),
+                Err(err) => warn!("Upload {} is malformed, removed: {}", id, err),
+            }
+        }
+
+        // Remove data files without metadata and metadata which could not be read
+        let ids: Vec<&str> = uploads.iter().map(|upload| upload.id.as_str()).collect();
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let id = name.strip_suffix(".json").unwrap_or(&name);
+                if !ids.contains(&id) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+
+        uploads
+    }
+
+    /* Creates a new upload with an empty data file
+     *
+     * length: Size of the complete file
+     * expires_at: Unix timestamp when the upload is removed, if it makes no progress
+     */
+    pub fn create(
+        &mut self,
+        length: u64,
+        file_name: &str,
+        content_type: &str,
+        expires_at: i64,
+    ) -> std::io::Result<Upload> {
+        let id = (0..16)
+            .map(|_| format!("{:02x}", rand::random::<u8>()))
+            .collect::<String>();
+        let upload = Upload {
+            id: id.clone(),
+            length,
+            offset: 0,
+            file_name: String::from(file_name),
+            content_type: String::from(content_type),
+            expires_at,
+            hash: None,
+            busy: false,
+        };
+
+        std::fs::File::create(self.data_path(&id))?;
+        self.write_upload(&upload)?;
+        self.uploads.insert(id, upload.clone());
+        Ok(upload)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Upload> {
+        self.uploads.get(id).cloned()
+    }
+
+    // Returns the path of the data file of an upload
+    pub fn data_path(&self, id: &str) -> String {
+        Path::new(&self.path)
+            .join(id)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Marks the upload as busy, returns false if another request appends to it already
+    pub fn lock(&mut self, id: &str) -> bool {
+        match self.uploads.get_mut(id) {
+            Some(upload) if !upload.busy => {
+                upload.busy = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Releases the upload for the next request, see UploadLock
+    pub fn unlock(&mut self, id: &str) {
+        if let Some(upload) = self.uploads.get_mut(id) {
+            upload.busy = false;
+        }
+    }
+
+    // Records the new offset after a request appended to the upload
+    pub fn record_progress(&mut self, id: &str, offset: u64, expires_at: i64) {
+        let upload = match self.uploads.get_mut(id) {
+            Some(upload) => upload,
+            None => return,
+        };
+        upload.offset = offset;
+        upload.expires_at = expires_at;
+
+        let upload = upload.clone();
+        if let Err(err) = self.write_upload(&upload) {
+            error!("Could not save upload {}: {}", id, err);
+        }
+    }
+
+    // Records that the upload was stored as file with the given hash, its data file is gone
+    pub fn complete(&mut self, id: &str, hash: &str) {
+        let upload = match self.uploads.get_mut(id) {
+            Some(upload) => upload,
+            None => return,
+        };
+        upload.hash = Some(String::from(hash));
+
+        let upload = upload.clone();
+        if let Err(err) = self.write_upload(&upload) {
+            error!("Could not save upload {}: {}", id, err);
+        }
+    }
+
+    // Removes the upload and its files, unless a request appends to it
+    pub fn remove(&mut self, id: &str) -> Option<Upload> {
+        if self.uploads.get(id)?.busy {
+            return None;
+        }
+
+        let upload = self.uploads.remove(id)?;
+        let _ = std::fs::remove_file(self.data_path(id));
+        let _ = std::fs::remove_file(self.json_path(id));
+        Some(upload)
+    }
+
+    // Removes all uploads which expired before the given unix timestamp
+    pub fn remove_expired(&mut self, now: i64) -> Vec<Upload> {
+        let expired: Vec<String> = self
+            .uploads
+            .values()
+            .filter(|upload| upload.expires_at <= now)
+            .map(|upload| upload.id.clone())
+            .collect();
+        expired.iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    fn json_path(&self, id: &str) -> String {
+        format!("{}.json", self.data_path(id))
+    }
+
+    fn write_upload(&self, upload: &Upload) -> std::io::Result<()> {
+        let serialized = serde_json::to_string(upload).map_err(std::io::Error::other)?;
+        state_file::write_atomic(&self.json_path(&upload.id), serialized.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_dir() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = String::from(dir.path().to_str().unwrap());
+        (dir, path)
+    }
+
+    #[test]
+    fn uploads_resume_after_restart() {
+        let (_dir, path) = state_dir();
+        let mut store = UploadStore::new(&path);
+        let partial = store.create(10, "data.csv", "text/csv", 100).unwrap();
+        let completed = store.create(3, "done.txt", "text/plain", 100).unwrap();
+        std::fs::write(store.data_path(&partial.id), b"0123").unwrap();
+        store.record_progress(&partial.id, 4, 200);
+        store.complete(&completed.id, "hash");
+        std::fs::remove_file(store.data_path(&completed.id)).unwrap();
+
+        // The offset is taken from the data file, it may have grown after the last record
+        std::fs::write(store.data_path(&partial.id), b"012345").unwrap();
+        let store = UploadStore::new(&path);
+        let partial = store.get(&partial.id).unwrap();
+        assert_eq!(partial.offset, 6);
+        assert_eq!(partial.expires_at, 200);
+        assert_eq!(partial.file_name, "data.csv");
+        let completed = store.get(&completed.id).unwrap();
+        assert_eq!(completed.offset, 3);
+        assert_eq!(completed.hash.as_deref(), Some("hash"));
+    }
+
+    #[test]
+    fn broken_uploads_are_removed_on_start() {
+        let (dir, path) = state_dir();
+        let mut store = UploadStore::new(&path);
+        let upload = store.create(10, "data.csv", "text/csv", 100).unwrap();
+        let uploads_dir = dir.path().join("uploads");
+        std::fs::write(uploads_dir.join("orphan"), b"data").unwrap();
+        std::fs::write(uploads_dir.join("broken.json"), b"{").unwrap();
+        std::fs::write(uploads_dir.join("broken"), b"data").unwrap();
+
+        let store = UploadStore::new(&path);
+        assert!(store.get(&upload.id).is_some());
+        assert!(store.get("broken").is_none());
+        let mut names = std::fs::read_dir(&uploads_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![upload.id.clone(), format!("{}.json", upload.id)]
+        );
+    }
+
+    #[test]
+    fn expired_uploads_are_removed_unless_busy() {
+        let (_dir, path) = state_dir();
+        let mut store = UploadStore::new(&path);
+        let expired = store.create(10, "a", "text/plain", 100).unwrap();
+        let busy = store.create(10, "b", "text/plain", 100).unwrap();
+        let active = store.create(10, "c", "text/plain", 300).unwrap();
+        assert!(store.lock(&busy.id));
+        assert!(!store.lock(&busy.id));
+
+        let removed = store.remove_expired(200);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id, expired.id);
+        assert!(!Path::new(&store.data_path(&expired.id)).exists());
+        assert!(store.get(&busy.id).is_some());
+        assert!(store.get(&active.id).is_some());
+
+        // Once the request is done, the upload expires as well
+        store.unlock(&busy.id);
+        assert_eq!(store.remove_expired(200).len(), 1);
+        assert!(UploadStore::new(&path).get(&busy.id).is_none());
+    }
+}